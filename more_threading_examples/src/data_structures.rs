@@ -1,5 +1,9 @@
+use dashmap::mapref::multiple::RefMulti;
 use dashmap::DashMap;
-use std::hash::Hash;
+use rayon::iter::plumbing::UnindexedConsumer;
+use rayon::prelude::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 
 pub struct Record<K, V>
@@ -76,7 +80,7 @@ where
 
     pub fn modify_a_map_with_key<F>(&mut self, key: &K, operation: F) -> Option<&DashMap<K, V>>
     where
-        F: FnOnce(&mut DashMap<K, V>) -> ()
+        F: FnOnce(&mut DashMap<K, V>)
     {
         // Find the index of the map containing the key
         let map_index = self.data.iter().position(|map| map.contains_key(key))?;
@@ -87,4 +91,127 @@ where
         Some(&self.data[map_index])
     }
 
-}
\ No newline at end of file
+}
+
+impl <K, V> Record<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone
+{
+    // Map a key is routed to; DefaultHasher::new() has fixed keys, so the
+    // same key always picks the same map
+    fn index_for_key(key: &K, size: usize) -> usize {
+
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) % size
+    }
+}
+
+/// Parallel iterator over every entry of every map in a Record.
+/// Work is split across the maps and within each one.
+pub struct ParIter<'a, K, V> {
+    data: &'a [DashMap<K, V>],
+}
+
+impl <'a, K, V> ParallelIterator for ParIter<'a, K, V>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync
+{
+    type Item = RefMulti<'a, K, V>;
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>
+    {
+        self.data.par_iter().flat_map(|map| map.par_iter()).drive_unindexed(consumer)
+    }
+}
+
+/// Parallel iterator that consumes a Record and yields owned entries
+pub struct IntoParIter<K, V> {
+    data: Vec<DashMap<K, V>>,
+}
+
+impl <K, V> ParallelIterator for IntoParIter<K, V>
+where
+    K: Hash + Eq + Send,
+    V: Send
+{
+    type Item = (K, V);
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>
+    {
+        self.data.into_par_iter().flat_map(|map| map.into_par_iter()).drive_unindexed(consumer)
+    }
+}
+
+impl <'a, K, V> IntoParallelIterator for &'a Record<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    V: Clone + Send + Sync
+{
+    type Iter = ParIter<'a, K, V>;
+    type Item = RefMulti<'a, K, V>;
+
+    fn into_par_iter(self) -> Self::Iter {
+
+        ParIter { data: &self.data }
+    }
+}
+
+impl <K, V> IntoParallelIterator for Record<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    V: Clone + Send + Sync
+{
+    type Iter = IntoParIter<K, V>;
+    type Item = (K, V);
+
+    fn into_par_iter(self) -> Self::Iter {
+
+        IntoParIter { data: self.data }
+    }
+}
+
+impl <K, V> ParallelExtend<(K, V)> for Record<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    V: Clone + Send + Sync
+{
+    /// Entries go to the map their key hashes to, so a key extended in twice
+    /// stays in one map. A key put in another map with `insert_data` is not
+    /// looked for there and ends up in both.
+    fn par_extend<I>(&mut self, par_iter: I)
+    where
+        I: IntoParallelIterator<Item = (K, V)>
+    {
+        if self.data.is_empty() {
+            self.data.push(DashMap::new());
+        }
+
+        let data = &self.data;
+        par_iter.into_par_iter().for_each(|(key, value)| {
+            data[Self::index_for_key(&key, data.len())].insert(key, value);
+        });
+    }
+}
+
+/// Collects into a Record with id 0 and one map per rayon thread, at least four
+impl <K, V> FromParallelIterator<(K, V)> for Record<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync,
+    V: Clone + Send + Sync
+{
+    fn from_par_iter<I>(par_iter: I) -> Self
+    where
+        I: IntoParallelIterator<Item = (K, V)>
+    {
+        let mut record = Record::new(0, rayon::current_num_threads().max(4));
+        record.par_extend(par_iter);
+        record
+    }
+}
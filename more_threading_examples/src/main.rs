@@ -14,7 +14,7 @@ fn main() {
     for i in 0..10 {
         for j in 0..5 {
             let key = format!("key-{}-{}", i, j);
-            let value = i as i32 * 100 + j as i32;
+            let value = i as i32 * 100 + j;
             record.insert_data(i, key, value);
         }
     }
@@ -151,5 +151,22 @@ fn main() {
         println!("  {}", key);
     }
 
+    // Test rayon adaptors directly on a Record
+    println!("\nTesting rayon adaptors on Record...");
+    let mut collected: Record<String, i32> = (0..1000).into_par_iter().map(|i| (format!("par-key-{}", i), i)).collect();
+    println!("Collected Record with {} DashMaps, empty: {}", collected.get_data_size(), collected.is_data_empty());
+
+    let even_sum: i32 = collected.par_iter().filter(|entry| entry.value() % 2 == 0).map(|entry| *entry.value()).sum();
+    println!("Sum of even values: {}", even_sum);
+
+    // Existing keys are updated in place, new ones spread over the maps
+    collected.par_extend((990..1010).into_par_iter().map(|i| (format!("par-key-{}", i), -i)));
+    let per_map: Vec<usize> = collected.get_data().iter().map(|map| map.len()).collect();
+    println!("After par_extend: {} keys, per map {:?}, first map holds {}", collected.get_all_keys().len(), per_map, collected.get_a_map_in_vector(0).len());
+
+    let mut negatives: Vec<(String, i32)> = collected.into_par_iter().filter(|(_, v)| *v < 0).collect();
+    negatives.par_sort_unstable();
+    println!("{} negative values, first {:?}", negatives.len(), negatives.first());
+
     println!("\nTests completed!");
 }
\ No newline at end of file
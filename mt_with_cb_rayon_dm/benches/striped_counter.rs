@@ -0,0 +1,65 @@
+//! Parallel increments on one shared `AtomicUsize` versus a `StripedCounter`.
+//! Run with `cargo bench --bench striped_counter`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// The crate is a binary, so pull the counter in directly
+#[allow(dead_code)]
+#[path = "../src/counter.rs"]
+mod counter;
+
+use counter::StripedCounter;
+
+const INCREMENTS_PER_THREAD: usize = 100_000;
+
+/// Run `increment` `INCREMENTS_PER_THREAD` times on each of `threads` threads
+fn hammer(threads: usize, increment: impl Fn() + Sync) {
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for _ in 0..INCREMENTS_PER_THREAD {
+                    increment();
+                }
+            });
+        }
+    });
+}
+
+fn parallel_increments(c: &mut Criterion) {
+    let cpus = thread::available_parallelism().map_or(4, |n| n.get());
+    let mut group = c.benchmark_group("parallel_increments");
+    group.sample_size(20);
+
+    let mut thread_counts = vec![1, 2, 4, 8, cpus];
+    thread_counts.retain(|&t| t <= cpus);
+    thread_counts.sort_unstable();
+    thread_counts.dedup();
+
+    for threads in thread_counts {
+        group.throughput(Throughput::Elements((threads * INCREMENTS_PER_THREAD) as u64));
+
+        group.bench_with_input(BenchmarkId::new("atomic", threads), &threads, |b, &threads| {
+            b.iter(|| {
+                let counter = AtomicUsize::new(0);
+                hammer(threads, || {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                });
+                assert_eq!(counter.load(Ordering::Relaxed), threads * INCREMENTS_PER_THREAD);
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("striped", threads), &threads, |b, &threads| {
+            b.iter(|| {
+                let counter = StripedCounter::new();
+                hammer(threads, || counter.increment());
+                assert_eq!(counter.sum(), threads * INCREMENTS_PER_THREAD);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parallel_increments);
+criterion_main!(benches);
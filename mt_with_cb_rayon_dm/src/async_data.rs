@@ -0,0 +1,483 @@
+use dashmap::mapref::entry::Entry;
+use dashmap::try_result::TryResult;
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use tokio::sync::{broadcast, oneshot, watch};
+
+use crate::counter::StripedCounter;
+use crate::data_structures::MyData;
+
+// Changes buffered per `changes()` subscriber before it starts missing some
+const CHANGE_FEED_CAPACITY: usize = 1024;
+
+/// Where scan-style operations run, away from the async runtime's threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanPool {
+    /// tokio's blocking thread pool
+    Blocking,
+    /// rayon's global pool, for scans that parallelise internally
+    Rayon,
+}
+
+/// One write made through an `AsyncData`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change<K, V> {
+    pub key: K,
+    /// The new value, or `None` for a removal
+    pub value: Option<V>,
+}
+
+/// Counters describing how often async callers had to leave the runtime
+#[derive(Debug, Clone, Copy)]
+pub struct AsyncStats {
+    /// Point operations that ran inline without waiting on a lock
+    pub inline: usize,
+    /// Point operations moved to a blocking thread because a shard was busy
+    pub offloaded: usize,
+    /// Scans run on the scan pool
+    pub scans: usize,
+}
+
+enum Write<V> {
+    Insert(V),
+    Remove,
+}
+
+struct Inner<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    data: Arc<MyData<K, V>>,
+    // Per-key watch channels. Every write through the facade holds the key's
+    // entry here while it writes and notifies, so a watcher registering at
+    // the same time sees either the old value and then the change, or the new one.
+    watchers: DashMap<K, watch::Sender<Option<V>>>,
+    changes: broadcast::Sender<Change<K, V>>,
+    pool: ScanPool,
+    inline: StripedCounter,
+    offloaded: StripedCounter,
+    scans: StripedCounter,
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Apply `write` without waiting on any lock, or hand it back
+    fn try_apply(&self, key: &K, write: Write<V>) -> Result<Option<V>, Write<V>> {
+        let Some(watch) = self.watchers.try_entry(key.clone()) else {
+            return Err(write);
+        };
+        let (previous, current) = match write {
+            Write::Insert(value) => {
+                let current = self.wants_changes(&watch).then(|| value.clone());
+                match self.data.insert_nowait(key.clone(), value) {
+                    Ok(previous) => (previous, current),
+                    Err((_, value)) => return Err(Write::Insert(value)),
+                }
+            }
+            Write::Remove => match self.data.remove_nowait(key) {
+                TryResult::Present((_, previous)) => (Some(previous), None),
+                // Nothing was removed, so there is nothing to announce
+                TryResult::Absent => return Ok(None),
+                TryResult::Locked => return Err(Write::Remove),
+            },
+        };
+        self.notify(watch, key, current);
+        Ok(previous)
+    }
+
+    /// Apply `write`, waiting for locks; only call this off the runtime
+    fn apply_blocking(&self, key: &K, write: Write<V>) -> Option<V> {
+        let watch = self.watchers.entry(key.clone());
+        let (previous, current) = match write {
+            Write::Insert(value) => {
+                let current = self.wants_changes(&watch).then(|| value.clone());
+                (self.data.insert(key.clone(), value), current)
+            }
+            Write::Remove => match self.data.remove(key) {
+                Some((_, previous)) => (Some(previous), None),
+                None => return None,
+            },
+        };
+        self.notify(watch, key, current);
+        previous
+    }
+
+    fn wants_changes(&self, watch: &Entry<'_, K, watch::Sender<Option<V>>>) -> bool {
+        matches!(watch, Entry::Occupied(_)) || self.changes.receiver_count() > 0
+    }
+
+    fn notify(&self, watch: Entry<'_, K, watch::Sender<Option<V>>>, key: &K, current: Option<V>) {
+        if let Entry::Occupied(sender) = watch {
+            if sender.get().receiver_count() == 0 {
+                // Every watcher of this key has gone away
+                sender.remove();
+            } else {
+                sender.get().send_replace(current.clone());
+            }
+        }
+        // Errors only mean nobody is subscribed
+        let _ = self.changes.send(Change {
+            key: key.clone(),
+            value: current,
+        });
+    }
+}
+
+/// Async facade over a `MyData` for tokio services. Point operations run
+/// inline when their shard is free and move to a blocking thread when it is
+/// busy; scans always run on the chosen `ScanPool`, so a long scan never
+/// stalls the runtime. Cloning gives another handle to the same store.
+///
+/// Watches and the change feed only see writes made through the facade.
+pub struct AsyncData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    inner: Arc<Inner<K, V>>,
+}
+
+impl<K, V> Clone for AsyncData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn clone(&self) -> Self {
+        AsyncData {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<K, V> AsyncData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new(data: Arc<MyData<K, V>>, pool: ScanPool) -> Self {
+        AsyncData {
+            inner: Arc::new(Inner {
+                data,
+                watchers: DashMap::new(),
+                changes: broadcast::channel(CHANGE_FEED_CAPACITY).0,
+                pool,
+                inline: StripedCounter::new(),
+                offloaded: StripedCounter::new(),
+                scans: StripedCounter::new(),
+            }),
+        }
+    }
+
+    /// The wrapped store, for synchronous callers
+    pub fn data(&self) -> &Arc<MyData<K, V>> {
+        &self.inner.data
+    }
+
+    pub async fn get(&self, key: &K) -> Option<V> {
+        match self.inner.data.get_nowait(key) {
+            TryResult::Present(entry) => {
+                self.inner.inline.increment();
+                return Some(entry.value().clone());
+            }
+            TryResult::Absent => {
+                self.inner.inline.increment();
+                return None;
+            }
+            TryResult::Locked => {}
+        }
+        let key = key.clone();
+        self.offload(move |inner| inner.data.get(&key).map(|v| v.value().clone())).await
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Option<V> {
+        self.write(key, Write::Insert(value)).await
+    }
+
+    pub async fn remove(&self, key: &K) -> Option<V> {
+        self.write(key.clone(), Write::Remove).await
+    }
+
+    async fn write(&self, key: K, write: Write<V>) -> Option<V> {
+        match self.inner.try_apply(&key, write) {
+            Ok(previous) => {
+                self.inner.inline.increment();
+                previous
+            }
+            Err(write) => self.offload(move |inner| inner.apply_blocking(&key, write)).await,
+        }
+    }
+
+    /// `MyData::find`, run on the scan pool
+    pub async fn find<F>(&self, predicate: F) -> Vec<(K, V)>
+    where
+        F: Fn(&K, &V) -> bool + Send + Sync + Clone + 'static,
+    {
+        self.scan(move |data| data.find(predicate)).await
+    }
+
+    /// Run any whole-store operation on the scan pool and await its result
+    pub async fn scan<R, F>(&self, operation: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&MyData<K, V>) -> R + Send + 'static,
+    {
+        self.inner.scans.increment();
+        let data = Arc::clone(&self.inner.data);
+        match self.inner.pool {
+            ScanPool::Blocking => join_blocking(tokio::task::spawn_blocking(move || operation(&data))).await,
+            ScanPool::Rayon => {
+                let (done, result) = oneshot::channel();
+                rayon::spawn(move || {
+                    // A panic must reach the awaiting task, not abort rayon
+                    let _ = done.send(panic::catch_unwind(AssertUnwindSafe(|| operation(&data))));
+                });
+                match result.await.expect("rayon dropped a scan without running it") {
+                    Ok(value) => value,
+                    Err(payload) => panic::resume_unwind(payload),
+                }
+            }
+        }
+    }
+
+    /// Watch one key. The watch starts at the key's current value and sees
+    /// every later write through this facade, though a slow watcher may
+    /// skip straight to the latest value.
+    pub async fn watch(&self, key: K) -> KeyWatch<V> {
+        // Registering waits on the key's shard, so do it off the runtime
+        self.offload(move |inner| {
+            let receiver = match inner.watchers.entry(key.clone()) {
+                Entry::Occupied(sender) => sender.get().subscribe(),
+                Entry::Vacant(slot) => {
+                    let current = inner.data.get(&key).map(|v| v.value().clone());
+                    slot.insert(watch::channel(current).0).subscribe()
+                }
+            };
+            KeyWatch { receiver }
+        })
+        .await
+    }
+
+    /// Subscribe to every write made through this facade from now on
+    pub fn changes(&self) -> ChangeFeed<K, V> {
+        ChangeFeed {
+            receiver: self.inner.changes.subscribe(),
+            missed: 0,
+        }
+    }
+
+    pub fn stats(&self) -> AsyncStats {
+        AsyncStats {
+            inline: self.inner.inline.sum(),
+            offloaded: self.inner.offloaded.sum(),
+            scans: self.inner.scans.sum(),
+        }
+    }
+
+    async fn offload<R, F>(&self, operation: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&Inner<K, V>) -> R + Send + 'static,
+    {
+        self.inner.offloaded.increment();
+        let inner = Arc::clone(&self.inner);
+        join_blocking(tokio::task::spawn_blocking(move || operation(&inner))).await
+    }
+}
+
+/// Await a blocking task, passing its panic on to the caller
+async fn join_blocking<R>(task: tokio::task::JoinHandle<R>) -> R {
+    match task.await {
+        Ok(value) => value,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => panic!("blocking task did not finish: {}", e),
+    }
+}
+
+/// Changes to one key, from `AsyncData::watch`
+pub struct KeyWatch<V> {
+    receiver: watch::Receiver<Option<V>>,
+}
+
+impl<V: Clone> KeyWatch<V> {
+    /// The latest value seen, without waiting
+    pub fn current(&self) -> Option<V> {
+        self.receiver.borrow().clone()
+    }
+
+    /// Wait for the next change and return the key's new value (`Some(None)`
+    /// after a removal), or `None` once the store has been dropped
+    pub async fn changed(&mut self) -> Option<Option<V>> {
+        self.receiver.changed().await.ok()?;
+        Some(self.receiver.borrow_and_update().clone())
+    }
+}
+
+/// Every write made through an `AsyncData`, from `AsyncData::changes`
+pub struct ChangeFeed<K, V> {
+    receiver: broadcast::Receiver<Change<K, V>>,
+    missed: u64,
+}
+
+impl<K: Clone, V: Clone> ChangeFeed<K, V> {
+    /// Wait for the next change, or `None` once the store has been dropped.
+    /// A subscriber that falls too far behind skips ahead; see `missed`.
+    pub async fn next(&mut self) -> Option<Change<K, V>> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(change) => return Some(change),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => self.missed += skipped,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Changes skipped because this subscriber fell behind
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn store(pool: ScanPool) -> AsyncData<u64, u64> {
+        AsyncData::new(Arc::new(MyData::new(0, 4)), pool)
+    }
+
+    /// Hold the write lock of `key`'s shard on another thread for a while,
+    /// returning once it is held
+    async fn lock_shard(data: &AsyncData<u64, u64>, key: u64) -> std::thread::JoinHandle<()> {
+        data.data().insert(key, 0);
+        let (held, ready) = oneshot::channel();
+        let store = Arc::clone(data.data());
+        let holder = std::thread::spawn(move || {
+            let segment = store.get_segment(store.get_segment_index(&key)).unwrap();
+            let _guard = segment.get_mut(&key).unwrap();
+            let _ = held.send(());
+            std::thread::sleep(Duration::from_millis(100));
+        });
+        ready.await.unwrap();
+        holder
+    }
+
+    #[tokio::test]
+    async fn point_operations_run_inline_on_free_shards() {
+        let data = store(ScanPool::Blocking);
+        assert_eq!(data.insert(1, 10).await, None);
+        assert_eq!(data.get(&1).await, Some(10));
+        assert_eq!(data.insert(1, 11).await, Some(10));
+        assert_eq!(data.remove(&1).await, Some(11));
+        assert_eq!(data.remove(&1).await, None);
+        assert_eq!(data.get(&1).await, None);
+
+        let stats = data.stats();
+        assert_eq!((stats.inline, stats.offloaded), (6, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn point_operations_offload_on_busy_shards() {
+        let data = store(ScanPool::Blocking);
+
+        let holder = lock_shard(&data, 1).await;
+        assert_eq!(data.get(&1).await, Some(0));
+        holder.join().unwrap();
+
+        let holder = lock_shard(&data, 1).await;
+        assert_eq!(data.insert(1, 10).await, Some(0));
+        holder.join().unwrap();
+
+        let holder = lock_shard(&data, 1).await;
+        assert_eq!(data.remove(&1).await, Some(0));
+        holder.join().unwrap();
+
+        assert_eq!(data.stats().offloaded, 3);
+        assert_eq!(data.get(&1).await, None);
+    }
+
+    #[tokio::test]
+    async fn removing_an_absent_key_notifies_nobody() {
+        let data = store(ScanPool::Blocking);
+        let mut feed = data.changes();
+        let watch = data.watch(1).await;
+
+        assert_eq!(data.remove(&1).await, None);
+        data.insert(2, 20).await;
+
+        assert_eq!(feed.next().await, Some(Change { key: 2, value: Some(20) }));
+        assert!(!watch.receiver.has_changed().unwrap());
+        assert_eq!(watch.current(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn watches_see_concurrent_writes_in_order() {
+        let data = store(ScanPool::Blocking);
+        let writer = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                for value in 0..2_000 {
+                    data.insert(1, value).await;
+                    if value % 100 == 0 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            })
+        };
+
+        let mut watches = Vec::new();
+        for _ in 0..4 {
+            watches.push(data.watch(1).await);
+            tokio::task::yield_now().await;
+        }
+        writer.await.unwrap();
+
+        for mut watch in watches {
+            let mut last = *watch.receiver.borrow_and_update();
+            while watch.receiver.has_changed().unwrap() {
+                let next = watch.changed().await.unwrap();
+                assert!(next > last, "{:?} after {:?}", next, last);
+                last = next;
+            }
+            assert_eq!(last, Some(1_999));
+        }
+    }
+
+    #[tokio::test]
+    async fn change_feed_counts_skipped_changes() {
+        let data = store(ScanPool::Blocking);
+        let mut feed = data.changes();
+        let extra = 10;
+        for key in 0..(CHANGE_FEED_CAPACITY + extra) as u64 {
+            data.insert(key, key).await;
+        }
+
+        let first = feed.next().await.unwrap();
+        assert_eq!(first.key, extra as u64);
+        assert_eq!(feed.missed(), extra as u64);
+
+        drop(data);
+        let mut received = 1;
+        while feed.next().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, CHANGE_FEED_CAPACITY);
+        assert_eq!(feed.missed(), extra as u64);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn scan_panics_reach_the_caller() {
+        for pool in [ScanPool::Blocking, ScanPool::Rayon] {
+            let data = store(pool);
+            let task = tokio::spawn(async move { data.scan(|_| -> usize { panic!("scan failed") }).await });
+            let payload = task.await.unwrap_err().into_panic();
+            assert_eq!(payload.downcast_ref::<&str>(), Some(&"scan failed"), "{:?}", pool);
+        }
+    }
+}
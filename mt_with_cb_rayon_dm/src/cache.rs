@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::codec::Codec;
+use crate::counter::StripedCounter;
+use crate::data_structures::MyData;
+
+/// The slower store a cache sits in front of
+pub trait BackingStore<K, V>: Send + Sync + 'static {
+    /// Load the value for `key`, if the store has one
+    fn load(&self, key: &K) -> io::Result<Option<V>>;
+
+    /// Create or overwrite the value for `key`
+    fn store(&self, key: &K, value: &V) -> io::Result<()>;
+
+    /// Delete `key`; deleting a missing key is not an error
+    fn delete(&self, key: &K) -> io::Result<()>;
+
+    /// Make previous writes durable
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A backing store that keeps one file per key in a directory.
+/// Meant for tests and examples rather than production use.
+pub struct FileBackingStore<K, V> {
+    directory: PathBuf,
+    latency: Duration,
+    loads: AtomicUsize,
+    stores: AtomicUsize,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Codec, V: Codec> FileBackingStore<K, V> {
+    pub fn new(directory: impl Into<PathBuf>) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(FileBackingStore {
+            directory,
+            latency: Duration::ZERO,
+            loads: AtomicUsize::new(0),
+            stores: AtomicUsize::new(0),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Sleep this long on every access to simulate a slow store
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Number of `load` calls served so far
+    pub fn load_count(&self) -> usize {
+        self.loads.load(Ordering::Relaxed)
+    }
+
+    /// Number of `store` and `delete` calls served so far
+    pub fn store_count(&self) -> usize {
+        self.stores.load(Ordering::Relaxed)
+    }
+
+    fn path_for(&self, key: &K) -> PathBuf {
+        let mut bytes = Vec::new();
+        key.encode(&mut bytes);
+        let name: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        self.directory.join(format!("{}.val", name))
+    }
+
+    fn simulate_latency(&self) {
+        if !self.latency.is_zero() {
+            thread::sleep(self.latency);
+        }
+    }
+}
+
+impl<K, V> BackingStore<K, V> for FileBackingStore<K, V>
+where
+    K: Codec + 'static,
+    V: Codec + 'static,
+{
+    fn load(&self, key: &K) -> io::Result<Option<V>> {
+        self.simulate_latency();
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        match fs::read(self.path_for(key)) {
+            Ok(bytes) => V::decode(&mut bytes.as_slice()).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn store(&self, key: &K, value: &V) -> io::Result<()> {
+        self.simulate_latency();
+        self.stores.fetch_add(1, Ordering::Relaxed);
+        let mut bytes = Vec::new();
+        value.encode(&mut bytes);
+        fs::write(self.path_for(key), bytes)
+    }
+
+    fn delete(&self, key: &K) -> io::Result<()> {
+        self.simulate_latency();
+        self.stores.fetch_add(1, Ordering::Relaxed);
+        match fs::remove_file(self.path_for(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How writes reach the backing store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Every write goes to the backing store before the cache is updated
+    WriteThrough,
+    /// Writes are queued and flushed in the background every `flush_interval`,
+    /// or inline once `max_pending` writes are queued
+    WriteBehind { flush_interval: Duration, max_pending: usize },
+}
+
+/// Outcome of a backing store load, shared with every caller waiting on it
+type LoadOutcome<V> = Result<Option<V>, (io::ErrorKind, String)>;
+
+/// A backing store load that other callers missing on the same key wait for
+struct Flight<V> {
+    outcome: Mutex<Option<LoadOutcome<V>>>,
+    done: Condvar,
+    // Set when the key is written while the load is running, so the
+    // possibly outdated result is not cached
+    stale: AtomicBool,
+}
+
+impl<V> Flight<V> {
+    /// Hand the outcome to every waiter
+    fn publish(&self, outcome: LoadOutcome<V>) {
+        *self.outcome.lock().unwrap_or_else(PoisonError::into_inner) = Some(outcome);
+        self.done.notify_all();
+    }
+}
+
+/// Held by the leader of a flight while it loads. If the load unwinds, the
+/// flight is removed and its waiters get an error instead of blocking forever.
+struct FlightGuard<'a, K: Hash + Eq, V> {
+    flights: &'a Mutex<HashMap<K, Arc<Flight<V>>>>,
+    key: &'a K,
+    flight: &'a Flight<V>,
+}
+
+impl<K: Hash + Eq, V> Drop for FlightGuard<'_, K, V> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.flights.lock().unwrap_or_else(PoisonError::into_inner).remove(self.key);
+            self.flight.publish(Err((io::ErrorKind::Other, "backing store load panicked".to_string())));
+        }
+    }
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    hits: StripedCounter,
+    misses: StripedCounter,
+    loads: StripedCounter,
+    coalesced: StripedCounter,
+    written: StripedCounter,
+    flush_errors: StripedCounter,
+}
+
+/// A point-in-time snapshot of cache statistics
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub cached: usize,
+    pub hits: usize,
+    pub misses: usize,
+    /// Misses that went to the backing store
+    pub loads: usize,
+    /// Misses that waited for another caller's load instead
+    pub coalesced: usize,
+    /// Writes applied to the backing store
+    pub written: usize,
+    /// Write-behind writes not yet flushed
+    pub pending: usize,
+    pub flush_errors: usize,
+}
+
+/// A `MyData` used as a read-through cache in front of a `BackingStore`
+pub struct CachedData<K, V, B>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    B: BackingStore<K, V>,
+{
+    data: MyData<K, V>,
+    backing: B,
+    mode: WriteMode,
+    flights: Mutex<HashMap<K, Arc<Flight<V>>>>,
+    pending: Mutex<WriteQueue<K, V>>,
+    // Held for a whole flush, so batches reach the backing store in order
+    flush_lock: Mutex<()>,
+    counters: CacheCounters,
+}
+
+/// Write-behind writes that the backing store does not have yet; `None` is a delete
+struct WriteQueue<K, V> {
+    queued: HashMap<K, Option<V>>,
+    // Taken by the running flush but not stored yet. Loads still see these,
+    // since the backing store may hold an older value until they land.
+    flushing: HashMap<K, Option<V>>,
+}
+
+impl<K: Hash + Eq, V> WriteQueue<K, V> {
+    fn get(&self, key: &K) -> Option<&Option<V>> {
+        self.queued.get(key).or_else(|| self.flushing.get(key))
+    }
+
+    fn len(&self) -> usize {
+        self.queued.len() + self.flushing.len()
+    }
+}
+
+impl<K, V, B> CachedData<K, V, B>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    B: BackingStore<K, V>,
+{
+    /// Create the cache. In write-behind mode this also starts the flush
+    /// thread, which stops on its own once the cache is dropped.
+    pub fn new(id: usize, num_segments: usize, backing: B, mode: WriteMode) -> Arc<Self> {
+        let cache = Arc::new(CachedData {
+            data: MyData::new(id, num_segments),
+            backing,
+            mode,
+            flights: Mutex::new(HashMap::new()),
+            pending: Mutex::new(WriteQueue {
+                queued: HashMap::new(),
+                flushing: HashMap::new(),
+            }),
+            flush_lock: Mutex::new(()),
+            counters: CacheCounters::default(),
+        });
+
+        if let WriteMode::WriteBehind { flush_interval, .. } = mode {
+            let weak = Arc::downgrade(&cache);
+            thread::spawn(move || flush_loop(weak, flush_interval));
+        }
+        cache
+    }
+
+    /// The backing store this cache writes to
+    pub fn backing(&self) -> &B {
+        &self.backing
+    }
+
+    /// Get a value, loading it from the backing store on a miss.
+    /// Concurrent misses on the same key share a single load.
+    pub fn get(&self, key: &K) -> io::Result<Option<V>> {
+        if let Some(value) = self.data.get(key) {
+            self.counters.hits.increment();
+            return Ok(Some(value.clone()));
+        }
+        self.counters.misses.increment();
+
+        let (flight, leader) = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(key) {
+                Some(flight) => (Arc::clone(flight), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        outcome: Mutex::new(None),
+                        done: Condvar::new(),
+                        stale: AtomicBool::new(false),
+                    });
+                    flights.insert(key.clone(), Arc::clone(&flight));
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leader {
+            self.counters.coalesced.increment();
+            let mut outcome = flight.outcome.lock().unwrap();
+            while outcome.is_none() {
+                outcome = flight.done.wait(outcome).unwrap();
+            }
+            return match outcome.as_ref().unwrap() {
+                Ok(value) => Ok(value.clone()),
+                Err((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+            };
+        }
+
+        let guard = FlightGuard {
+            flights: &self.flights,
+            key,
+            flight: &flight,
+        };
+        let result = self.load(key);
+        drop(guard);
+
+        {
+            let mut flights = self.flights.lock().unwrap();
+            flights.remove(key);
+            if let Ok(Some(value)) = &result {
+                if !flight.stale.load(Ordering::Acquire) {
+                    let idx = self.data.get_segment_index(key);
+                    if let Some(segment) = self.data.get_segment(idx) {
+                        // Never overwrite a value written while we were loading
+                        segment.entry(key.clone()).or_insert_with(|| value.clone());
+                    }
+                }
+            }
+        }
+
+        flight.publish(match &result {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => Err((e.kind(), e.to_string())),
+        });
+        result
+    }
+
+    /// Load from queued writes first, then from the backing store
+    fn load(&self, key: &K) -> io::Result<Option<V>> {
+        if let Some(queued) = self.pending.lock().unwrap().get(key) {
+            return Ok(queued.clone());
+        }
+        self.counters.loads.increment();
+        self.backing.load(key)
+    }
+
+    /// Mark any load running for `key` as outdated
+    fn invalidate_flight(&self, key: &K) {
+        if let Some(flight) = self.flights.lock().unwrap().get(key) {
+            flight.stale.store(true, Ordering::Release);
+        }
+    }
+
+    /// Insert a value into the cache and the backing store
+    pub fn insert(&self, key: K, value: V) -> io::Result<()> {
+        self.invalidate_flight(&key);
+        match self.mode {
+            WriteMode::WriteThrough => {
+                self.backing.store(&key, &value)?;
+                self.counters.written.increment();
+                self.data.insert(key, value);
+            }
+            WriteMode::WriteBehind { max_pending, .. } => {
+                let queued = {
+                    let mut pending = self.pending.lock().unwrap();
+                    pending.queued.insert(key.clone(), Some(value.clone()));
+                    self.data.insert(key, value);
+                    pending.len()
+                };
+                if queued >= max_pending {
+                    self.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a value from the cache and the backing store.
+    /// Returns the cached value, if the key was cached.
+    pub fn remove(&self, key: &K) -> io::Result<Option<V>> {
+        self.invalidate_flight(key);
+        match self.mode {
+            WriteMode::WriteThrough => {
+                self.backing.delete(key)?;
+                self.counters.written.increment();
+            }
+            WriteMode::WriteBehind { .. } => {
+                self.pending.lock().unwrap().queued.insert(key.clone(), None);
+            }
+        }
+        Ok(self.remove_cached(key))
+    }
+
+    /// Drop a removed key from the cache. A load that started before the
+    /// removal may still be running with the old value, so it is marked
+    /// stale again under the same lock it caches its result with.
+    fn remove_cached(&self, key: &K) -> Option<V> {
+        let flights = self.flights.lock().unwrap();
+        if let Some(flight) = flights.get(key) {
+            flight.stale.store(true, Ordering::Release);
+        }
+        self.data.remove(key).map(|(_, v)| v)
+    }
+
+    /// Drop a key from the cache only; the next `get` loads it again
+    pub fn evict(&self, key: &K) {
+        self.data.remove(key);
+    }
+
+    /// Write all queued writes to the backing store and flush it.
+    /// Writes that fail stay queued for the next flush. Concurrent flushes
+    /// run one after another, so a key is never stored out of order.
+    pub fn flush(&self) -> io::Result<()> {
+        let _flushing = self.flush_lock.lock().unwrap();
+        let batch: Vec<(K, Option<V>)> = {
+            let mut pending = self.pending.lock().unwrap();
+            let queued = std::mem::take(&mut pending.queued);
+            pending.flushing = queued;
+            pending.flushing.iter().map(|(k, w)| (k.clone(), w.clone())).collect()
+        };
+
+        let mut first_error = None;
+        let mut failed = Vec::new();
+        for (key, write) in batch {
+            let result = match &write {
+                Some(value) => self.backing.store(&key, value),
+                None => self.backing.delete(&key),
+            };
+            match result {
+                Ok(()) => {
+                    self.counters.written.increment();
+                    // The backing store has it now
+                    self.pending.lock().unwrap().flushing.remove(&key);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                    failed.push((key, write));
+                }
+            }
+        }
+
+        if !failed.is_empty() {
+            let mut pending = self.pending.lock().unwrap();
+            for (key, write) in failed {
+                pending.flushing.remove(&key);
+                // A newer write queued meanwhile supersedes the failed one
+                pending.queued.entry(key).or_insert(write);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => self.backing.flush(),
+        }
+    }
+
+    /// Take a snapshot of the cache statistics
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            cached: self.data.len(),
+            hits: self.counters.hits.sum(),
+            misses: self.counters.misses.sum(),
+            loads: self.counters.loads.sum(),
+            coalesced: self.counters.coalesced.sum(),
+            written: self.counters.written.sum(),
+            pending: self.pending.lock().unwrap().len(),
+            flush_errors: self.counters.flush_errors.sum(),
+        }
+    }
+}
+
+/// Background loop that flushes write-behind writes until the cache goes away
+fn flush_loop<K, V, B>(cache: Weak<CachedData<K, V, B>>, interval: Duration)
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    B: BackingStore<K, V>,
+{
+    loop {
+        thread::sleep(interval);
+        let Some(cache) = cache.upgrade() else {
+            return;
+        };
+        if cache.flush().is_err() {
+            cache.counters.flush_errors.increment();
+        }
+    }
+}
+
+impl<K, V, B> Drop for CachedData<K, V, B>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    B: BackingStore<K, V>,
+{
+    fn drop(&mut self) {
+        // Best effort: queued writes would otherwise be lost
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// In-memory backing store with a fixed delay on every call
+    struct SlowStore {
+        values: Mutex<HashMap<u64, u64>>,
+        latency: Duration,
+    }
+
+    impl SlowStore {
+        fn new(latency: Duration) -> Self {
+            SlowStore {
+                values: Mutex::new(HashMap::new()),
+                latency,
+            }
+        }
+    }
+
+    impl BackingStore<u64, u64> for SlowStore {
+        fn load(&self, key: &u64) -> io::Result<Option<u64>> {
+            let value = self.values.lock().unwrap().get(key).copied();
+            thread::sleep(self.latency);
+            Ok(value)
+        }
+
+        fn store(&self, key: &u64, value: &u64) -> io::Result<()> {
+            thread::sleep(self.latency);
+            self.values.lock().unwrap().insert(*key, *value);
+            Ok(())
+        }
+
+        fn delete(&self, key: &u64) -> io::Result<()> {
+            thread::sleep(self.latency);
+            self.values.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn write_behind() -> WriteMode {
+        WriteMode::WriteBehind {
+            flush_interval: Duration::from_secs(3600),
+            max_pending: usize::MAX,
+        }
+    }
+
+    #[test]
+    fn concurrent_flushes_store_the_latest_value() {
+        let cache = CachedData::new(0, 4, SlowStore::new(Duration::from_millis(1)), write_behind());
+        thread::scope(|s| {
+            for t in 0..4 {
+                let cache = &cache;
+                s.spawn(move || {
+                    for round in 0..25 {
+                        if t == 0 {
+                            cache.insert(1, round).unwrap();
+                        }
+                        cache.flush().unwrap();
+                    }
+                });
+            }
+        });
+        cache.flush().unwrap();
+        assert_eq!(cache.backing().values.lock().unwrap().get(&1), Some(&24));
+    }
+
+    #[test]
+    fn loads_see_writes_that_are_being_flushed() {
+        let cache = CachedData::new(0, 4, SlowStore::new(Duration::from_millis(100)), write_behind());
+        cache.backing().values.lock().unwrap().insert(1, 1);
+        cache.insert(1, 2).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| cache.flush().unwrap());
+            thread::sleep(Duration::from_millis(20));
+            cache.evict(&1);
+            assert_eq!(cache.get(&1).unwrap(), Some(2));
+        });
+    }
+
+    #[test]
+    fn write_through_remove_beats_a_running_load() {
+        let cache = CachedData::new(0, 4, SlowStore::new(Duration::from_millis(50)), WriteMode::WriteThrough);
+        cache.backing().values.lock().unwrap().insert(1, 1);
+        thread::scope(|s| {
+            s.spawn(|| cache.get(&1).unwrap());
+            thread::sleep(Duration::from_millis(10));
+            cache.remove(&1).unwrap();
+        });
+        assert_eq!(cache.stats().cached, 0);
+        assert_eq!(cache.get(&1).unwrap(), None);
+    }
+
+    /// Backing store whose first load panics
+    struct PanickyStore {
+        panicked: AtomicBool,
+    }
+
+    impl BackingStore<u64, u64> for PanickyStore {
+        fn load(&self, _key: &u64) -> io::Result<Option<u64>> {
+            thread::sleep(Duration::from_millis(50));
+            if !self.panicked.swap(true, Ordering::SeqCst) {
+                panic!("loader failed");
+            }
+            Ok(Some(7))
+        }
+
+        fn store(&self, _key: &u64, _value: &u64) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn delete(&self, _key: &u64) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_panicking_load_releases_its_followers() {
+        let store = PanickyStore {
+            panicked: AtomicBool::new(false),
+        };
+        let cache = CachedData::new(0, 4, store, WriteMode::WriteThrough);
+        thread::scope(|s| {
+            let leader = s.spawn(|| cache.get(&1));
+            thread::sleep(Duration::from_millis(10));
+            let follower = s.spawn(|| cache.get(&1));
+            assert!(leader.join().is_err());
+            let error = follower.join().unwrap().unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::Other);
+        });
+        assert_eq!(cache.stats().coalesced, 1);
+        // The dead flight is gone, so the next miss loads again
+        assert_eq!(cache.get(&1).unwrap(), Some(7));
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Why an operation did not run to completion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpError {
+    /// The caller cancelled the operation
+    Cancelled,
+    /// The operation's deadline passed
+    TimedOut,
+}
+
+impl fmt::Display for OpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpError::Cancelled => f.write_str("operation was cancelled"),
+            OpError::TimedOut => f.write_str("operation deadline expired"),
+        }
+    }
+}
+
+impl std::error::Error for OpError {}
+
+/// Result type for operations that can be cancelled or time out
+pub type OpResult<T> = Result<T, OpError>;
+
+/// A cheaply clonable flag the caller can flip to abandon an operation
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every operation holding this token to stop
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+/// Optional deadline and cancellation token attached to an operation
+#[derive(Debug, Clone, Default)]
+pub struct OpContext {
+    deadline: Option<Instant>,
+    tokens: Vec<CancellationToken>,
+}
+
+impl OpContext {
+    /// A context that never cancels or expires
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up once `deadline` has passed
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Give up once `timeout` has elapsed from now
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Give up once `token` is cancelled, in addition to any token added before
+    pub fn with_token(mut self, token: CancellationToken) -> Self {
+        self.tokens.push(token);
+        self
+    }
+
+    /// The context of work nested inside both `self` and `outer`:
+    /// the earlier deadline applies and a token of either side cancels it
+    pub fn within(&self, outer: &OpContext) -> OpContext {
+        let deadline = match (self.deadline, outer.deadline) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        OpContext {
+            deadline,
+            tokens: self.tokens.iter().chain(&outer.tokens).cloned().collect(),
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Check whether the operation should still run.
+    /// Cancellation wins over an expired deadline.
+    pub fn check(&self) -> OpResult<()> {
+        if self.tokens.iter().any(CancellationToken::is_cancelled) {
+            return Err(OpError::Cancelled);
+        }
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            return Err(OpError::TimedOut);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_contexts_combine() {
+        let soon = Instant::now() + Duration::from_secs(1);
+        let later = soon + Duration::from_secs(60);
+        let inner = OpContext::new().with_deadline(later);
+        let outer = OpContext::new().with_deadline(soon);
+        assert_eq!(inner.within(&outer).deadline(), Some(soon));
+        assert_eq!(outer.within(&inner).deadline(), Some(soon));
+        assert_eq!(OpContext::new().within(&inner).deadline(), Some(later));
+
+        let token = CancellationToken::new();
+        let combined = OpContext::new().with_token(CancellationToken::new()).within(&OpContext::new().with_token(token.clone()));
+        assert_eq!(combined.check(), Ok(()));
+        token.cancel();
+        assert_eq!(combined.check(), Err(OpError::Cancelled));
+    }
+}
@@ -0,0 +1,178 @@
+use std::io;
+
+/// Compact binary encoding for keys and values written to disk
+pub trait Codec: Sized {
+    /// Append the encoded form of `self` to `out`
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decode a value from the front of `input`, advancing it past the bytes read
+    fn decode(input: &mut &[u8]) -> io::Result<Self>;
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Write an unsigned integer using 7 bits per byte
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Read an integer written by `write_varint`
+pub fn read_varint(input: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input
+            .split_first()
+            .ok_or_else(|| invalid_data("truncated varint"))?;
+        *input = rest;
+        if shift >= 64 {
+            return Err(invalid_data("varint too long"));
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Take exactly `len` bytes from the front of `input`
+pub fn read_bytes<'a>(input: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(invalid_data("truncated record"));
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+    Ok(bytes)
+}
+
+/// 64-bit FNV-1a, for hashes that are written to disk or compared across
+/// processes. `DefaultHasher` may change between Rust releases; this does
+/// not, and integers are fed to it little-endian on every platform.
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl std::hash::Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        // Same bytes on 32- and 64-bit targets
+        self.write_u64(i as u64);
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> io::Result<Self> {
+        let len = read_varint(input)? as usize;
+        let bytes = read_bytes(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("string is not valid UTF-8"))
+    }
+}
+
+macro_rules! unsigned_codec {
+    ($($t:ty),*) => {
+        $(impl Codec for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                write_varint(out, *self as u64);
+            }
+
+            fn decode(input: &mut &[u8]) -> io::Result<Self> {
+                <$t>::try_from(read_varint(input)?).map_err(|_| invalid_data("integer out of range"))
+            }
+        })*
+    };
+}
+
+unsigned_codec!(u8, u16, u32, u64, usize);
+
+macro_rules! signed_codec {
+    ($($t:ty),*) => {
+        $(impl Codec for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                // Zigzag so small negative numbers stay small
+                let v = *self as i64;
+                write_varint(out, ((v << 1) ^ (v >> 63)) as u64);
+            }
+
+            fn decode(input: &mut &[u8]) -> io::Result<Self> {
+                let raw = read_varint(input)?;
+                let v = ((raw >> 1) as i64) ^ -((raw & 1) as i64);
+                <$t>::try_from(v).map_err(|_| invalid_data("integer out of range"))
+            }
+        })*
+    };
+}
+
+signed_codec!(i8, i16, i32, i64, isize);
+
+impl Codec for f64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> io::Result<Self> {
+        let bytes = read_bytes(input, 8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().expect("read exactly 8 bytes")))
+    }
+}
+
+impl<T: Codec> Codec for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        for item in self {
+            item.encode(out);
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> io::Result<Self> {
+        let len = read_varint(input)? as usize;
+        // Cap the pre-allocation so a corrupt length cannot exhaust memory
+        let mut items = Vec::with_capacity(len.min(1024));
+        for _ in 0..len {
+            items.push(T::decode(input)?);
+        }
+        Ok(items)
+    }
+}
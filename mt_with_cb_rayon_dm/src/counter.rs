@@ -0,0 +1,83 @@
+use crossbeam::utils::CachePadded;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+
+// Hands out stripe slots to threads round-robin, the first time each counts
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+// Cell count of `StripedCounter::new`, worked out once: asking for the CPU
+// count is a syscall, plus a cgroup read on Linux
+static DEFAULT_STRIPES: OnceLock<usize> = OnceLock::new();
+
+thread_local! {
+    static STRIPE: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+fn thread_stripe() -> usize {
+    STRIPE.with(|stripe| match stripe.get() {
+        Some(idx) => idx,
+        None => {
+            let idx = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed);
+            stripe.set(Some(idx));
+            idx
+        }
+    })
+}
+
+/// A counter spread over cache-line padded cells, in the style of Java's
+/// `LongAdder`. Each thread adds to its own cell, so parallel increments do
+/// not fight over one cache line; reads add up every cell.
+///
+/// The price is memory: a cell takes a whole padded cache line (128 bytes on
+/// x86_64), so the default of two cells per CPU is 4 KiB on a 16-CPU machine
+/// where a plain atomic is 8 bytes. Use `with_stripes` for counters that are
+/// numerous or rarely contended.
+pub struct StripedCounter {
+    cells: Box<[CachePadded<AtomicUsize>]>,
+    // cells.len() - 1; the length is a power of two
+    mask: usize,
+}
+
+impl StripedCounter {
+    /// A counter with two cells per available CPU, rounded up to a power of two
+    pub fn new() -> Self {
+        let stripes = DEFAULT_STRIPES.get_or_init(|| thread::available_parallelism().map_or(4, |n| n.get()) * 2);
+        Self::with_stripes(*stripes)
+    }
+
+    /// A counter with at least `stripes` cells, rounded up to a power of two
+    pub fn with_stripes(stripes: usize) -> Self {
+        let len = stripes.max(1).next_power_of_two();
+        StripedCounter {
+            cells: (0..len).map(|_| CachePadded::new(AtomicUsize::new(0))).collect(),
+            mask: len - 1,
+        }
+    }
+
+    pub fn add(&self, n: usize) {
+        self.cells[thread_stripe() & self.mask].fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// Total of every cell. Exact once concurrent `add`s have finished;
+    /// while they run it includes any subset of them.
+    pub fn sum(&self) -> usize {
+        self.cells.iter().map(|cell| cell.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Number of cells the count is spread over
+    pub fn stripes(&self) -> usize {
+        self.cells.len()
+    }
+}
+
+impl Default for StripedCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// Identifies the replica that made an update. Each replica of a store must
+/// use its own id, or concurrent updates can be lost in a merge.
+pub type ReplicaId = usize;
+
+/// A state that can absorb another replica's state. `merge` must be
+/// commutative, associative and idempotent, so replicas converge no matter
+/// how often or in what order they exchange state.
+pub trait Mergeable {
+    fn merge(&mut self, other: &Self);
+}
+
+/// Grow-only counter: one running total per replica, merged by maximum
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GCounter {
+    counts: HashMap<ReplicaId, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(&mut self, replica: ReplicaId, by: u64) {
+        *self.counts.entry(replica).or_insert(0) += by;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl Mergeable for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (&replica, &count) in &other.counts {
+            let mine = self.counts.entry(replica).or_insert(0);
+            *mine = (*mine).max(count);
+        }
+    }
+}
+
+/// Counter that can go both ways, kept as two grow-only counters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PNCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PNCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(&mut self, replica: ReplicaId, by: u64) {
+        self.increments.increment(replica, by);
+    }
+
+    pub fn decrement(&mut self, replica: ReplicaId, by: u64) {
+        self.decrements.increment(replica, by);
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Mergeable for PNCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+}
+
+/// Last-writer-wins register. The write with the highest timestamp wins,
+/// with the replica id breaking ties; a replica must not reuse a timestamp.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LwwRegister<T> {
+    value: T,
+    timestamp: u64,
+    replica: ReplicaId,
+}
+
+impl<T: Clone> LwwRegister<T> {
+    pub fn new(value: T, timestamp: u64, replica: ReplicaId) -> Self {
+        LwwRegister {
+            value,
+            timestamp,
+            replica,
+        }
+    }
+
+    /// Write `value` unless a later write is already held
+    pub fn set(&mut self, value: T, timestamp: u64, replica: ReplicaId) {
+        if (timestamp, replica) > (self.timestamp, self.replica) {
+            *self = LwwRegister::new(value, timestamp, replica);
+        }
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+}
+
+impl<T: Clone> Mergeable for LwwRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        self.set(other.value.clone(), other.timestamp, other.replica);
+    }
+}
+
+// (replica, sequence number) that makes one add of an element unique
+type Tag = (ReplicaId, u64);
+
+/// Observed-remove set: a remove only cancels the adds it has seen, so an
+/// add concurrent with a remove survives the merge.
+///
+/// Removes leave no tombstones. The clock records every add a replica has
+/// seen, so a tag that one side lacks but has seen must have been removed
+/// there. That relies on states being merged whole, never in part.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrSet<T>
+where
+    T: Hash + Eq,
+{
+    // Live tags of every element that has at least one
+    adds: HashMap<T, HashSet<Tag>>,
+    // Highest sequence number seen from each replica; every earlier add of
+    // that replica has been seen too
+    clock: HashMap<ReplicaId, u64>,
+}
+
+impl<T> Default for OrSet<T>
+where
+    T: Hash + Eq,
+{
+    fn default() -> Self {
+        OrSet {
+            adds: HashMap::new(),
+            clock: HashMap::new(),
+        }
+    }
+}
+
+impl<T> OrSet<T>
+where
+    T: Hash + Eq + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, replica: ReplicaId, element: T) {
+        let seq = self.clock.entry(replica).or_insert(0);
+        *seq += 1;
+        self.adds.entry(element).or_default().insert((replica, *seq));
+    }
+
+    /// Remove `element` as seen by this replica; returns whether it was present
+    pub fn remove(&mut self, element: &T) -> bool {
+        self.adds.remove(element).is_some()
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.adds.contains_key(element)
+    }
+
+    pub fn len(&self) -> usize {
+        self.adds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.adds.keys()
+    }
+}
+
+impl<T> Mergeable for OrSet<T>
+where
+    T: Hash + Eq + Clone,
+{
+    fn merge(&mut self, other: &Self) {
+        let seen = |clock: &HashMap<ReplicaId, u64>, &(replica, seq): &Tag| {
+            clock.get(&replica).is_some_and(|&latest| seq <= latest)
+        };
+        // Drop tags the other side has seen but no longer holds: it removed them
+        self.adds.retain(|element, tags| {
+            let theirs = other.adds.get(element);
+            tags.retain(|tag| theirs.is_some_and(|t| t.contains(tag)) || !seen(&other.clock, tag));
+            !tags.is_empty()
+        });
+        // Take the tags this side has not seen; ones it has seen and lacks it removed
+        for (element, tags) in &other.adds {
+            let fresh: Vec<Tag> = tags.iter().filter(|tag| !seen(&self.clock, tag)).copied().collect();
+            if !fresh.is_empty() {
+                self.adds.entry(element.clone()).or_default().extend(fresh);
+            }
+        }
+        for (&replica, &seq) in &other.clock {
+            let mine = self.clock.entry(replica).or_insert(0);
+            *mine = (*mine).max(seq);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRIALS: usize = 300;
+    const REPLICAS: usize = 3;
+
+    /// Deterministic xorshift, so a failing trial can be replayed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+    }
+
+    fn merged<T: Mergeable + Clone>(x: &T, y: &T) -> T {
+        let mut out = x.clone();
+        out.merge(y);
+        out
+    }
+
+    fn assert_merge_laws<T>(states: &[T], trial: usize)
+    where
+        T: Mergeable + Clone + PartialEq + std::fmt::Debug,
+    {
+        let [a, b, c] = states else {
+            panic!("expected three replicas");
+        };
+        assert_eq!(merged(a, b), merged(b, a), "commutativity, trial {}", trial);
+        assert_eq!(
+            merged(&merged(a, b), c),
+            merged(a, &merged(b, c)),
+            "associativity, trial {}",
+            trial
+        );
+        assert_eq!(merged(a, a), *a, "idempotence, trial {}", trial);
+    }
+
+    /// Run `TRIALS` random histories on three replicas, built by `replica`,
+    /// and check the merge laws on each
+    fn check_random_histories<T>(mut replica: impl FnMut(&mut Rng, ReplicaId, &[T]) -> T)
+    where
+        T: Mergeable + Clone + PartialEq + std::fmt::Debug,
+    {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for trial in 0..TRIALS {
+            let mut states = Vec::with_capacity(REPLICAS);
+            for id in 0..REPLICAS {
+                let state = replica(&mut rng, id, &states);
+                states.push(state);
+            }
+            assert_merge_laws(&states, trial);
+        }
+    }
+
+    #[test]
+    fn gcounter_obeys_the_merge_laws() {
+        check_random_histories(|rng, _, _: &[GCounter]| {
+            let mut counter = GCounter::new();
+            for _ in 0..rng.below(12) {
+                let (by, replica) = (rng.below(5), rng.below(REPLICAS as u64) as usize);
+                counter.increment(replica, by);
+            }
+            counter
+        });
+    }
+
+    #[test]
+    fn pncounter_obeys_the_merge_laws() {
+        check_random_histories(|rng, id, _: &[PNCounter]| {
+            let mut counter = PNCounter::new();
+            for _ in 0..rng.below(12) {
+                counter.increment(id, rng.below(5));
+                let other = rng.below(REPLICAS as u64) as usize;
+                counter.decrement(other, rng.below(4));
+            }
+            counter
+        });
+    }
+
+    #[test]
+    fn lww_register_obeys_the_merge_laws() {
+        check_random_histories(|rng, id, _: &[LwwRegister<u64>]| {
+            let mut register = LwwRegister::new(0, 0, id);
+            for _ in 0..rng.below(12) {
+                let (value, timestamp) = (rng.below(100), rng.below(20));
+                register.set(value, timestamp, id);
+            }
+            register
+        });
+    }
+
+    #[test]
+    fn orset_obeys_the_merge_laws() {
+        check_random_histories(|rng, id, earlier: &[OrSet<u64>]| {
+            let mut set = OrSet::new();
+            for _ in 0..rng.below(12) {
+                set.add(id, rng.below(6));
+                if rng.below(3) == 0 {
+                    set.remove(&rng.below(6));
+                }
+            }
+            // Sometimes sync with the previous replica first
+            if let Some(prev) = earlier.last() {
+                if rng.below(2) == 0 {
+                    set.merge(prev);
+                    set.remove(&rng.below(6));
+                    set.add(id, rng.below(6));
+                }
+            }
+            set
+        });
+    }
+
+    #[test]
+    fn orset_remove_wins_over_the_adds_it_saw_only() {
+        let mut a = OrSet::new();
+        a.add(0, "book");
+        let mut b = a.clone();
+        a.remove(&"book");
+        b.add(1, "book");
+        b.add(1, "pen");
+
+        // b's second add of "book" was concurrent with a's remove
+        let mut ab = merged(&a, &b);
+        assert!(ab.contains(&"book") && ab.contains(&"pen"));
+
+        // Once every replica has seen it, a remove stays removed
+        ab.remove(&"book");
+        assert_eq!(merged(&ab, &b), merged(&b, &ab));
+        assert!(!merged(&ab, &b).contains(&"book"));
+    }
+}
@@ -1,7 +1,123 @@
+use dashmap::mapref::multiple::{RefMulti, RefMutMulti};
+use dashmap::try_result::TryResult;
 use dashmap::DashMap;
-use std::hash::Hash;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use rayon::iter::plumbing::{Consumer, Folder, UnindexedConsumer};
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::{RwLock, RwLockReadGuard};
+use std::time::Duration;
+
+use crate::cancellation::{OpContext, OpResult};
+use crate::codec::StableHasher;
+use crate::counter::StripedCounter;
+use crate::crdt::Mergeable;
+use crate::group::GroupBy;
+use crate::lease::{LeaseError, LeaseTable, LeaseToken};
+use crate::verify::{ChecksumCheck, Fault, VerifyReport};
+
+// How many entries a cancellable scan visits between deadline checks
+pub(crate) const CANCEL_CHECK_INTERVAL: usize = 64;
+
+/// Same-thread access to a shard that a closure passed to this store is
+/// currently holding. Going ahead would deadlock on the shard's lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReentrantAccess {
+    pub store_id: usize,
+    /// The (segment, shard within it) being accessed, or `None` for a
+    /// whole-store operation
+    pub shard: Option<(usize, usize)>,
+}
+
+impl fmt::Display for ReentrantAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.shard {
+            Some((segment, shard)) => write!(
+                f,
+                "reentrant access to shard {} of segment {} of MyData {} from a closure that holds it",
+                shard, segment, self.store_id
+            ),
+            None => write!(
+                f,
+                "reentrant whole-store access to MyData {} from a closure holding one of its shards",
+                self.store_id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReentrantAccess {}
+
+thread_local! {
+    // (store address, (segment, shard)) of every shard a closure on this thread is running under
+    static HELD_SHARDS: RefCell<Vec<(usize, (usize, usize))>> = const { RefCell::new(Vec::new()) };
+}
+
+thread_local! {
+    // Address of every store whose `swap_lock` a scan on this thread already holds
+    static SCANNING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Keeps whole-store replacement out while a scan runs. Only the outermost
+/// scan on a thread takes `swap_lock`: a nested read would wait behind a
+/// writer queued in between and deadlock.
+struct ScanGuard<'a> {
+    lock: Option<RwLockReadGuard<'a, ()>>,
+    store: usize,
+}
+
+impl Drop for ScanGuard<'_> {
+    fn drop(&mut self) {
+        if self.lock.is_some() {
+            SCANNING.with_borrow_mut(|scanning| {
+                if let Some(pos) = scanning.iter().rposition(|&s| s == self.store) {
+                    scanning.remove(pos);
+                }
+            });
+        }
+    }
+}
+
+/// Write-lock every shard of every segment, always in the same order.
+/// Point operations hold one shard at a time, so this cannot deadlock with
+/// them; it only waits for each shard's current holder to finish.
+macro_rules! lock_all_shards {
+    ($store:expr) => {
+        $store
+            .segments
+            .iter()
+            .flat_map(|segment| segment.shards().iter().map(|shard| shard.write()))
+            .collect::<Vec<_>>()
+    };
+}
+
+/// Swap the shard tables of `$staged` (laid out like the store) into the
+/// write-locked live ones, leaving the old contents behind in `$staged`
+macro_rules! install_staged {
+    ($guards:expr, $staged:expr) => {
+        let replacements = $staged.iter_mut().flat_map(|segment| segment.shards_mut().iter_mut());
+        for (live, replacement) in $guards.iter_mut().zip(replacements) {
+            std::mem::swap(&mut **live, replacement.get_mut());
+        }
+    };
+}
+
+/// Marks a shard as held by the current thread until dropped
+struct HeldShard {
+    store: usize,
+    shard: (usize, usize),
+}
+
+impl Drop for HeldShard {
+    fn drop(&mut self) {
+        HELD_SHARDS.with_borrow_mut(|held| {
+            if let Some(pos) = held.iter().rposition(|&h| h == (self.store, self.shard)) {
+                held.remove(pos);
+            }
+        });
+    }
+}
 
 /// A thread-safe data structure that uses sharding to reduce contention
 /// across multiple hashmap segments
@@ -12,10 +128,16 @@ where
 {
     id: usize,
     segments: Vec<DashMap<K, V>>,
-    // Keep track of operations to help with monitoring
-    op_counter: Arc<AtomicUsize>,
+    // Keep track of operations to help with monitoring. Striped, so it costs
+    // 2 cache-line padded cells per CPU (4 KiB on 16 CPUs) for every store.
+    op_counter: StripedCounter,
     // Store a consistent hasher for deterministic segment assignment
     hasher: std::collections::hash_map::RandomState,
+    // Held for reading by whole-store scans and for writing while new contents
+    // are swapped in, so a scan never sees half of each
+    swap_lock: RwLock<()>,
+    // Leases on keys, with their fencing tokens
+    leases: LeaseTable<K>,
 }
 
 impl<K, V> MyData<K, V>
@@ -33,11 +155,48 @@ where
         MyData {
             id,
             segments,
-            op_counter: Arc::new(AtomicUsize::new(0)),
+            op_counter: StripedCounter::new(),
             hasher: std::collections::hash_map::RandomState::new(),
+            swap_lock: RwLock::new(()),
+            leases: LeaseTable::new(),
         }
     }
 
+    /// Create an empty data structure with the same segment count and hasher
+    /// as `template`, so every key lands in the same segment index in both
+    pub fn new_with_layout<V2>(id: usize, template: &MyData<K, V2>) -> Self
+    where
+        V2: Clone + Send + Sync + 'static,
+    {
+        let mut segments = Vec::with_capacity(template.segments.len());
+        for _ in 0..template.segments.len() {
+            segments.push(DashMap::new());
+        }
+
+        MyData {
+            id,
+            segments,
+            op_counter: StripedCounter::new(),
+            hasher: template.hasher.clone(),
+            swap_lock: RwLock::new(()),
+            leases: LeaseTable::new(),
+        }
+    }
+
+    /// Check whether `other` assigns every key to the same segment index as `self`
+    pub fn shares_layout_with<V2>(&self, other: &MyData<K, V2>) -> bool
+    where
+        V2: Clone + Send + Sync + 'static,
+    {
+        // RandomState cannot be compared directly, but two instances with different
+        // seeds disagree on these probes with overwhelming probability
+        self.segments.len() == other.segments.len()
+            && [0u64, 0x9e37_79b9_7f4a_7c15].iter().all(|probe| {
+                std::hash::BuildHasher::hash_one(&self.hasher, probe)
+                    == std::hash::BuildHasher::hash_one(&other.hasher, probe)
+            })
+    }
+
     /// Get the ID of this data structure
     pub fn id(&self) -> usize {
         self.id
@@ -48,18 +207,70 @@ where
         self.segments.len()
     }
 
-    /// Get the total number of entries across all segments
+    /// Get the total number of entries across all segments.
+    /// Not synchronized with `replace_all` or `swap_with`, so it may count
+    /// part of the old and part of the new contents while one runs.
     pub fn len(&self) -> usize {
+        self.assert_not_reentrant(None);
         self.segments.iter().map(|segment| segment.len()).sum()
     }
 
     /// Check if all segments are empty
     pub fn is_empty(&self) -> bool {
+        self.assert_not_reentrant(None);
         self.segments.iter().all(|segment| segment.is_empty())
     }
 
+    /// Hold off whole-store replacement for the rest of a scan
+    fn scan(&self) -> ScanGuard<'_> {
+        let store = self as *const Self as usize;
+        if SCANNING.with_borrow(|scanning| scanning.contains(&store)) {
+            return ScanGuard { lock: None, store };
+        }
+        let lock = self.swap_lock.read().unwrap();
+        SCANNING.with_borrow_mut(|scanning| scanning.push(store));
+        ScanGuard { lock: Some(lock), store }
+    }
+
+    /// The segment a key belongs to and the DashMap shard within it
+    fn shard_of(&self, key: &K) -> (usize, usize) {
+        let segment_idx = self.get_segment_index(key);
+        (segment_idx, self.segments[segment_idx].determine_map(key))
+    }
+
+    /// Record that a closure on this thread runs while `shard` is locked
+    fn hold(&self, shard: (usize, usize)) -> HeldShard {
+        let store = self as *const Self as usize;
+        HELD_SHARDS.with_borrow_mut(|held| held.push((store, shard)));
+        HeldShard { store, shard }
+    }
+
+    /// Fail if a closure on this thread holds `shard` of this store, or for
+    /// `None`, any shard of it
+    fn check_reentry(&self, shard: Option<(usize, usize)>) -> Result<(), ReentrantAccess> {
+        let store = self as *const Self as usize;
+        let reentrant = HELD_SHARDS.with_borrow(|held| {
+            held.iter()
+                .any(|&(s, held_shard)| s == store && shard.is_none_or(|target| target == held_shard))
+        });
+        if reentrant {
+            Err(ReentrantAccess { store_id: self.id, shard })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Panic instead of deadlocking when `key`'s shard (or for `None`, any
+    /// shard) is held by a closure on this thread. The `try_*` methods make the
+    /// same check and return the error instead.
+    fn assert_not_reentrant(&self, key: Option<&K>) {
+        if let Err(e) = self.check_reentry(key.map(|key| self.shard_of(key))) {
+            panic!("{}; this would deadlock (use try_get/try_insert/try_remove to handle it)", e);
+        }
+    }
+
     /// Determine which segment a key belongs to
-    fn get_segment_index(&self, key: &K) -> usize {
+    pub fn get_segment_index(&self, key: &K) -> usize {
         // Use the consistent hasher stored in the struct
         let hash = std::hash::BuildHasher::hash_one(&self.hasher, key);
         (hash as usize) % self.segments.len()
@@ -67,47 +278,171 @@ where
 
     /// Insert a key-value pair
     pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.assert_not_reentrant(Some(&key));
         let segment_idx = self.get_segment_index(&key);
         let result = self.segments[segment_idx].insert(key, value);
-        self.op_counter.fetch_add(1, Ordering::Relaxed);
+        self.op_counter.increment();
         result
     }
 
-    /// Get a value by key
-    pub fn get(&self, key: &K) -> Option<dashmap::mapref::one::Ref<K, V>> {
-        let segment_idx = self.get_segment_index(key);
-        self.segments[segment_idx].get(key)
+    /// Get a value by key. The returned ref read-locks the key's shard:
+    /// `replace_all` and `swap_with` wait for it, so a scan started while
+    /// holding it can wait behind them forever.
+    pub fn get(&self, key: &K) -> Option<dashmap::mapref::one::Ref<'_, K, V>> {
+        self.assert_not_reentrant(Some(key));
+        self.segments[self.get_segment_index(key)].get(key)
     }
 
     /// Remove a key-value pair
     pub fn remove(&self, key: &K) -> Option<(K, V)> {
-        let segment_idx = self.get_segment_index(key);
-        let result = self.segments[segment_idx].remove(key);
+        self.assert_not_reentrant(Some(key));
+        let result = self.segments[self.get_segment_index(key)].remove(key);
         if result.is_some() {
-            self.op_counter.fetch_add(1, Ordering::Relaxed);
+            self.op_counter.increment();
         }
         result
     }
 
+    /// Like `insert`, but safe to call from inside a closure running on this store
+    pub fn try_insert(&self, key: K, value: V) -> Result<Option<V>, ReentrantAccess> {
+        let shard = self.shard_of(&key);
+        self.check_reentry(Some(shard))?;
+        let result = self.segments[shard.0].insert(key, value);
+        self.op_counter.increment();
+        Ok(result)
+    }
+
+    /// Like `get`, but safe to call from inside a closure running on this store
+    pub fn try_get(&self, key: &K) -> Result<Option<dashmap::mapref::one::Ref<'_, K, V>>, ReentrantAccess> {
+        let shard = self.shard_of(key);
+        self.check_reentry(Some(shard))?;
+        Ok(self.segments[shard.0].get(key))
+    }
+
+    /// Like `remove`, but safe to call from inside a closure running on this store
+    pub fn try_remove(&self, key: &K) -> Result<Option<(K, V)>, ReentrantAccess> {
+        let shard = self.shard_of(key);
+        self.check_reentry(Some(shard))?;
+        let result = self.segments[shard.0].remove(key);
+        if result.is_some() {
+            self.op_counter.increment();
+        }
+        Ok(result)
+    }
+
+    /// Claim `key` for `ttl`, or `None` while someone else holds a valid
+    /// lease on it. A lapsed lease can be claimed by anyone.
+    pub fn lease(&self, key: K, ttl: Duration) -> Option<LeaseToken<K>> {
+        self.leases.acquire(key, ttl)
+    }
+
+    /// Extend a lease still held by `token` to `ttl` from now
+    pub fn renew(&self, token: &LeaseToken<K>, ttl: Duration) -> Result<LeaseToken<K>, LeaseError> {
+        self.leases.renew(token, ttl)
+    }
+
+    /// Give up a lease early so the key can be claimed straight away
+    pub fn release(&self, token: &LeaseToken<K>) -> Result<(), LeaseError> {
+        self.leases.release(token)
+    }
+
+    /// Number of keys under a lease that has not lapsed
+    pub fn active_leases(&self) -> usize {
+        self.leases.active()
+    }
+
+    /// Insert at the token's key only while `token` is its valid lease
+    pub fn insert_leased(&self, token: &LeaseToken<K>, value: V) -> Result<Option<V>, LeaseError> {
+        self.leases.while_held(token, || self.insert(token.key().clone(), value))
+    }
+
+    /// Remove the token's key only while `token` is its valid lease
+    pub fn remove_leased(&self, token: &LeaseToken<K>) -> Result<Option<V>, LeaseError> {
+        self.leases.while_held(token, || self.remove(token.key()).map(|(_, v)| v))
+    }
+
+    /// Like `get`, but reports `TryResult::Locked` instead of waiting for a busy shard
+    pub fn get_nowait(&self, key: &K) -> TryResult<dashmap::mapref::one::Ref<'_, K, V>> {
+        self.segments[self.get_segment_index(key)].try_get(key)
+    }
+
+    /// Like `insert`, but hands the pair back instead of waiting for a busy shard
+    pub fn insert_nowait(&self, key: K, value: V) -> Result<Option<V>, (K, V)> {
+        let segment = &self.segments[self.get_segment_index(&key)];
+        match segment.try_entry(key.clone()) {
+            Some(entry) => {
+                let previous = match entry {
+                    dashmap::Entry::Occupied(mut occupied) => Some(occupied.insert(value)),
+                    dashmap::Entry::Vacant(vacant) => {
+                        vacant.insert(value);
+                        None
+                    }
+                };
+                self.op_counter.increment();
+                Ok(previous)
+            }
+            None => Err((key, value)),
+        }
+    }
+
+    /// Like `remove`, but reports `TryResult::Locked` instead of waiting for a busy shard
+    pub fn remove_nowait(&self, key: &K) -> TryResult<(K, V)> {
+        let segment = &self.segments[self.get_segment_index(key)];
+        match segment.try_entry(key.clone()) {
+            Some(dashmap::Entry::Occupied(occupied)) => {
+                self.op_counter.increment();
+                TryResult::Present(occupied.remove_entry())
+            }
+            Some(dashmap::Entry::Vacant(_)) => TryResult::Absent,
+            None => TryResult::Locked,
+        }
+    }
+
     /// Process each key-value pair with the given function
     pub fn for_each<F>(&self, mut f: F)
     where
         F: FnMut(&K, &mut V) + Send + Clone + 'static,
     {
-        for segment in &self.segments {
-            for mut entry in segment.iter_mut() {
-                // Clone the key to avoid borrowing issues
-                let key = entry.key().clone();
-                // Now we can mutably borrow the value
-                f(&key, entry.value_mut());
+        self.assert_not_reentrant(None);
+        let _scan = self.scan();
+        for (idx, segment) in self.segments.iter().enumerate() {
+            // Walk the shards directly so we know which one the closure runs under
+            for (shard_idx, shard) in segment.shards().iter().enumerate() {
+                let _held = self.hold((idx, shard_idx));
+                for (key, value) in shard.write().iter_mut() {
+                    f(key, value);
+                }
+            }
+            self.op_counter.increment();
+        }
+    }
+
+    /// Like `for_each`, but stops between entries once `ctx` is cancelled or expired
+    pub fn for_each_cancellable<F>(&self, mut f: F, ctx: &OpContext) -> OpResult<()>
+    where
+        F: FnMut(&K, &mut V),
+    {
+        self.assert_not_reentrant(None);
+        let _scan = self.scan();
+        for (idx, segment) in self.segments.iter().enumerate() {
+            ctx.check()?;
+            for (shard_idx, shard) in segment.shards().iter().enumerate() {
+                let _held = self.hold((idx, shard_idx));
+                for (visited, (key, value)) in shard.write().iter_mut().enumerate() {
+                    if visited % CANCEL_CHECK_INTERVAL == 0 {
+                        ctx.check()?;
+                    }
+                    f(key, value);
+                }
             }
-            self.op_counter.fetch_add(1, Ordering::Relaxed);
+            self.op_counter.increment();
         }
+        Ok(())
     }
 
     /// Get the operation counter
     pub fn op_count(&self) -> usize {
-        self.op_counter.load(Ordering::Relaxed)
+        self.op_counter.sum()
     }
 
     /// Execute a transaction that may involve multiple operations
@@ -116,32 +451,55 @@ where
     where
         F: FnOnce(&K, &mut V) -> R,
     {
-        let segment_idx = self.get_segment_index(key);
-        let segment = &self.segments[segment_idx];
+        self.assert_not_reentrant(Some(key));
+        let shard = self.shard_of(key);
+        let segment = &self.segments[shard.0];
 
         // Try to get a mutable reference to the entry
         if let Some(mut entry) = segment.get_mut(key) {
+            let _held = self.hold(shard);
             // Clone the key to avoid borrowing issues
             let key_clone = entry.key().clone();
             // Now we can mutably borrow the value
             let result = transaction(&key_clone, entry.value_mut());
-            self.op_counter.fetch_add(1, Ordering::Relaxed);
+            self.op_counter.increment();
             Some(result)
         } else {
             None
         }
     }
 
+    /// Like `transaction`, but first inserts `default()` if the key is missing,
+    /// all under the segment lock
+    pub fn upsert<D, F, R>(&self, key: K, default: D, update: F) -> R
+    where
+        D: FnOnce() -> V,
+        F: FnOnce(&K, &mut V) -> R,
+    {
+        self.assert_not_reentrant(Some(&key));
+        let shard = self.shard_of(&key);
+        let mut entry = self.segments[shard.0].entry(key).or_insert_with(default);
+        let _held = self.hold(shard);
+        let key_clone = entry.key().clone();
+        let result = update(&key_clone, entry.value_mut());
+        self.op_counter.increment();
+        result
+    }
+
     /// Clear all segments
     pub fn clear(&self) {
+        self.assert_not_reentrant(None);
+        let _scan = self.scan();
         for segment in &self.segments {
             segment.clear();
         }
-        self.op_counter.fetch_add(1, Ordering::Relaxed);
+        self.op_counter.increment();
     }
 
-    /// Get all keys across all segments
+    /// Get all keys across all segments. Like `len`, this does not wait for
+    /// whole-store writes; use `find` for a view of one dataset.
     pub fn keys(&self) -> Vec<K> {
+        self.assert_not_reentrant(None);
         let mut keys = Vec::new();
         for segment in &self.segments {
             for entry in segment.iter() {
@@ -153,6 +511,8 @@ where
 
     /// Create a clone of this data structure (clones all entries)
     pub fn clone_data(&self) -> Self {
+        self.assert_not_reentrant(None);
+        let _scan = self.scan();
         let mut segments = Vec::with_capacity(self.segments.len());
         for segment in &self.segments {
             let new_segment = DashMap::new();
@@ -162,16 +522,213 @@ where
             segments.push(new_segment);
         }
 
+        let op_counter = StripedCounter::new();
+        op_counter.add(self.op_count());
+
         MyData {
             id: self.id,
             segments,
-            op_counter: Arc::new(AtomicUsize::new(self.op_count())),
+            op_counter,
             hasher: self.hasher.clone(),
+            swap_lock: RwLock::new(()),
+            leases: LeaseTable::new(),
         }
     }
 
+    /// Start a group-by over the entries, grouped by `key_fn`.
+    /// Finish it with `aggregate`, `count` or `fold`.
+    pub fn group_by<G, F>(&self, key_fn: F) -> GroupBy<'_, K, V, F>
+    where
+        G: Hash + Eq + Send,
+        F: Fn(&K, &V) -> G + Send + Sync,
+    {
+        GroupBy::new(self, key_fn)
+    }
+
+    /// Replace the whole contents with `entries`.
+    /// The new contents are built off to the side in parallel and swapped in
+    /// at once, so readers see either the old or the new dataset, never a mix.
+    pub fn replace_all<I>(&self, entries: I)
+    where
+        I: IntoParallelIterator<Item = (K, V)>,
+    {
+        self.assert_not_reentrant(None);
+        let mut staged = self.stage(entries);
+        {
+            let _swap = self.swap_lock.write().unwrap();
+            // Every shard is locked before the first swap, so single-key
+            // readers cannot observe a mix either
+            let mut guards = lock_all_shards!(self);
+            install_staged!(guards, staged);
+        }
+        self.op_counter.increment();
+        // `staged` now holds the old contents, freed here outside the lock
+    }
+
+    /// Exchange the contents of two stores. Both switch at the same moment:
+    /// every lock of both is taken before either is read, so a racing write
+    /// lands before the swap (and moves across with it) or after it.
+    pub fn swap_with(&self, other: &MyData<K, V>) {
+        if std::ptr::eq(self, other) {
+            return;
+        }
+        self.assert_not_reentrant(None);
+        other.assert_not_reentrant(None);
+
+        // Lock in address order so two opposite swaps cannot deadlock
+        let (first, second) = if (self as *const Self) < (other as *const Self) {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        {
+            let _first = first.swap_lock.write().unwrap();
+            let _second = second.swap_lock.write().unwrap();
+            let first_shards = lock_all_shards!(first);
+            let second_shards = lock_all_shards!(second);
+            let (mut mine, mut theirs) = if std::ptr::eq(first, self) {
+                (first_shards, second_shards)
+            } else {
+                (second_shards, first_shards)
+            };
+
+            // Stage on this thread: a rayon job stolen while we hold every
+            // lock could block on them and never let us finish
+            let mut for_self = self.empty_stage();
+            let mut for_other = other.empty_stage();
+            for table in theirs.iter_mut() {
+                for (key, value) in std::mem::take(&mut **table) {
+                    for_self[self.get_segment_index(&key)].insert(key, value);
+                }
+            }
+            for table in mine.iter_mut() {
+                for (key, value) in std::mem::take(&mut **table) {
+                    for_other[other.get_segment_index(&key)].insert(key, value);
+                }
+            }
+            install_staged!(mine, for_self);
+            install_staged!(theirs, for_other);
+        }
+        self.op_counter.increment();
+        other.op_counter.increment();
+    }
+
+    /// Empty segments laid out exactly like ours (same hashers and shard
+    /// counts) so their shard tables can be swapped in directly
+    fn empty_stage(&self) -> Vec<DashMap<K, V>> {
+        self.segments
+            .iter()
+            .map(|segment| DashMap::with_hasher_and_shard_amount(segment.hasher().clone(), segment.shards().len()))
+            .collect()
+    }
+
+    /// Fill an `empty_stage` with `entries` in parallel
+    fn stage<I>(&self, entries: I) -> Vec<DashMap<K, V>>
+    where
+        I: IntoParallelIterator<Item = (K, V)>,
+    {
+        let staged = self.empty_stage();
+        entries.into_par_iter().for_each(|(key, value)| {
+            staged[self.get_segment_index(&key)].insert(key, value);
+        });
+        staged
+    }
+
+    /// Scrub the store: check that every entry sits in the segment and shard
+    /// its key hashes to, so lookups can find it, that segment sizes match
+    /// their contents, and that no two leases can share a fencing token.
+    /// The op counter counts calls rather than contents and segments don't
+    /// account for their memory, so neither has anything to check against.
+    ///
+    /// With `repair`, misplaced entries are moved to where lookups expect
+    /// them; a copy shadowed by an entry already there is dropped. Lease
+    /// faults are only reported. Concurrent writes can show up as count
+    /// mismatches, so run it while the store is quiet.
+    pub fn verify(&self, repair: bool) -> VerifyReport<K> {
+        self.assert_not_reentrant(None);
+        let _scan = self.scan();
+
+        let scanned: Vec<(usize, Vec<Fault<K>>)> = self
+            .segments
+            .par_iter()
+            .enumerate()
+            .map(|(idx, segment)| {
+                let mut faults = Vec::new();
+                let mut counted = 0;
+                for (shard_idx, shard) in segment.shards().iter().enumerate() {
+                    for (key, _) in shard.read().iter() {
+                        counted += 1;
+                        let belongs_in = self.get_segment_index(key);
+                        if belongs_in != idx {
+                            faults.push(Fault::WrongSegment {
+                                key: key.clone(),
+                                found_in: idx,
+                                belongs_in,
+                            });
+                        } else if segment.determine_map(key) != shard_idx {
+                            faults.push(Fault::Unreachable {
+                                key: key.clone(),
+                                segment: idx,
+                            });
+                        }
+                    }
+                }
+                let reported = segment.len();
+                if reported != counted {
+                    faults.push(Fault::CountMismatch {
+                        segment: idx,
+                        reported,
+                        counted,
+                    });
+                }
+                (counted, faults)
+            })
+            .collect();
+
+        let entries = scanned.iter().map(|(counted, _)| counted).sum();
+        let mut faults: Vec<Fault<K>> = scanned.into_iter().flat_map(|(_, faults)| faults).collect();
+        faults.extend(self.leases.faults());
+        let mut report = VerifyReport {
+            segments: self.segments.len(),
+            entries,
+            faults,
+            repaired: 0,
+            dropped: 0,
+            checksum: None,
+        };
+        if repair && !report.faults.is_empty() {
+            self.repair_locked(&mut report);
+        }
+        report
+    }
+
+    /// Move every misplaced entry back to where lookups expect it
+    fn repair_locked(&self, report: &mut VerifyReport<K>) {
+        for (idx, segment) in self.segments.iter().enumerate() {
+            let mut misplaced: Vec<(K, V)> = Vec::new();
+            for (shard_idx, shard) in segment.shards().iter().enumerate() {
+                // Check placement rather than matching keys, so a correctly
+                // placed entry with the same key as a stray copy stays put
+                misplaced.extend(shard.write().extract_if(|(key, _)| {
+                    self.get_segment_index(key) != idx || segment.determine_map(key) != shard_idx
+                }));
+            }
+            for (key, value) in misplaced {
+                let home = &self.segments[self.get_segment_index(&key)];
+                if home.contains_key(&key) {
+                    report.dropped += 1;
+                } else {
+                    home.insert(key, value);
+                    report.repaired += 1;
+                }
+            }
+        }
+        self.op_counter.increment();
+    }
+
     /// Get a specific segment for direct access
-    /// This can be useful for batch operations on a segment
+    /// This can be useful for batch operations on a segment.
+    /// Scans through a segment are not covered by `replace_all`'s atomicity.
     pub fn get_segment(&self, segment_idx: usize) -> Option<&DashMap<K, V>> {
         if segment_idx < self.segments.len() {
             Some(&self.segments[segment_idx])
@@ -185,18 +742,373 @@ where
     where
         F: Fn(&K, &V) -> bool + Send + Sync + Clone,
     {
+        self.assert_not_reentrant(None);
+        let _scan = self.scan();
         let mut results = Vec::new();
 
-        for segment in &self.segments {
-            for entry in segment.iter() {
-                if predicate(entry.key(), entry.value()) {
-                    results.push((entry.key().clone(), entry.value().clone()));
+        for (idx, segment) in self.segments.iter().enumerate() {
+            for (shard_idx, shard) in segment.shards().iter().enumerate() {
+                let _held = self.hold((idx, shard_idx));
+                for (key, value) in shard.read().iter() {
+                    if predicate(key, value) {
+                        results.push((key.clone(), value.clone()));
+                    }
                 }
             }
         }
 
         results
     }
+
+    /// Like `find`, but gives up between segments and entries once `ctx` is
+    /// cancelled or expired
+    pub fn find_cancellable<F>(&self, predicate: F, ctx: &OpContext) -> OpResult<Vec<(K, V)>>
+    where
+        F: Fn(&K, &V) -> bool,
+    {
+        self.assert_not_reentrant(None);
+        let _scan = self.scan();
+        let mut results = Vec::new();
+
+        for (idx, segment) in self.segments.iter().enumerate() {
+            ctx.check()?;
+            for (shard_idx, shard) in segment.shards().iter().enumerate() {
+                let _held = self.hold((idx, shard_idx));
+                for (visited, (key, value)) in shard.read().iter().enumerate() {
+                    if visited % CANCEL_CHECK_INTERVAL == 0 {
+                        ctx.check()?;
+                    }
+                    if predicate(key, value) {
+                        results.push((key.clone(), value.clone()));
+                    }
+                }
+            }
+        }
+
+        Ok(results)
+    }
+}
+
+impl<K, V> MyData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Hash + Clone + Send + Sync + 'static,
+{
+    /// Order-independent fingerprint of the contents.
+    /// Two stores holding the same entries have the same hash, whatever their
+    /// segment layout or insertion order, and so do later builds of this
+    /// program, so the hash can be stored.
+    pub fn state_hash(&self) -> u64 {
+        self.assert_not_reentrant(None);
+        let _scan = self.scan();
+        let mut combined = 0u64;
+        let mut count = 0u64;
+        for segment in &self.segments {
+            for entry in segment.iter() {
+                // A fixed algorithm, so hashes agree across stores, processes and builds
+                let mut hasher = StableHasher::default();
+                entry.key().hash(&mut hasher);
+                entry.value().hash(&mut hasher);
+                combined = combined.wrapping_add(hasher.finish());
+                count += 1;
+            }
+        }
+        combined ^ count.rotate_left(32)
+    }
+
+    /// `verify`, plus a comparison of the contents against a checksum taken
+    /// earlier with `state_hash`, e.g. when a snapshot was written. The
+    /// contents are hashed as found, and again after a repair that changed them.
+    pub fn verify_checksum(&self, expected: u64, repair: bool) -> VerifyReport<K> {
+        let actual = self.state_hash();
+        let mut report = self.verify(repair);
+        let after_repair = (report.repaired + report.dropped > 0).then(|| self.state_hash());
+        report.checksum = Some(ChecksumCheck {
+            expected,
+            actual,
+            after_repair,
+        });
+        report
+    }
+}
+
+impl<K, V> MyData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Mergeable + Clone + Send + Sync + 'static,
+{
+    /// Fold every entry of `other` into this store with `Mergeable::merge`;
+    /// keys only in `other` are copied over. Replicas that merge each other's
+    /// state converge whatever order the merges run in.
+    ///
+    /// Keys themselves are grow-only: there are no tombstones, so a key
+    /// removed here comes back from any replica that still holds it. To delete
+    /// across replicas, merge a value that records the deletion instead, such
+    /// as an `LwwRegister<Option<_>>` set to `None` or an emptied `OrSet`.
+    pub fn merge_from(&self, other: &MyData<K, V>) {
+        if std::ptr::eq(self, other) {
+            return;
+        }
+        self.assert_not_reentrant(None);
+        other.assert_not_reentrant(None);
+
+        (0..other.segments.len()).into_par_iter().for_each(|idx| {
+            // Copy the segment out first so no lock on `other` is held while
+            // writing here; two stores merging from each other cannot deadlock
+            let incoming: Vec<(K, V)> = {
+                let _scan = other.scan();
+                other.segments[idx]
+                    .iter()
+                    .map(|entry| (entry.key().clone(), entry.value().clone()))
+                    .collect()
+            };
+            for (key, value) in incoming {
+                // Merging a state into a copy of itself changes nothing
+                self.upsert(key, || value.clone(), |_, existing| existing.merge(&value));
+            }
+        });
+    }
+}
+
+/// Parallel iterator over the entries of a `MyData`, from `par_iter()`.
+/// Work is split across segments and across the shards within each one.
+/// Like the other scans it holds off `replace_all` and `swap_with` until it
+/// is done, and each shard stays read-locked while its entries are visited,
+/// so a closure that writes to that shard panics.
+pub struct ParIter<'a, K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    data: &'a MyData<K, V>,
+}
+
+impl<'a, K, V> ParallelIterator for ParIter<'a, K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    type Item = RefMulti<'a, K, V>;
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>,
+    {
+        let data = self.data;
+        data.assert_not_reentrant(None);
+        let _scan = data.scan();
+        data.segments
+            .par_iter()
+            .flat_map(|segment| segment.par_iter())
+            .drive_unindexed(HoldingConsumer { data, base: consumer })
+    }
+}
+
+/// Passes items on to `base`, recording on the visiting thread that the
+/// item's shard is held while `base` consumes it, so reentrant calls from
+/// the closures are caught like they are in `for_each`
+struct HoldingConsumer<'a, K, V, C>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    data: &'a MyData<K, V>,
+    base: C,
+}
+
+impl<'a, K, V, C> Consumer<RefMulti<'a, K, V>> for HoldingConsumer<'a, K, V, C>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    C: Consumer<RefMulti<'a, K, V>>,
+{
+    type Folder = HoldingConsumer<'a, K, V, C::Folder>;
+    type Reducer = C::Reducer;
+    type Result = C::Result;
+
+    fn split_at(self, index: usize) -> (Self, Self, Self::Reducer) {
+        let (left, right, reducer) = self.base.split_at(index);
+        (
+            HoldingConsumer { data: self.data, base: left },
+            HoldingConsumer { data: self.data, base: right },
+            reducer,
+        )
+    }
+
+    fn into_folder(self) -> Self::Folder {
+        HoldingConsumer {
+            data: self.data,
+            base: self.base.into_folder(),
+        }
+    }
+
+    fn full(&self) -> bool {
+        self.base.full()
+    }
+}
+
+impl<'a, K, V, C> UnindexedConsumer<RefMulti<'a, K, V>> for HoldingConsumer<'a, K, V, C>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    C: UnindexedConsumer<RefMulti<'a, K, V>>,
+{
+    fn split_off_left(&self) -> Self {
+        HoldingConsumer {
+            data: self.data,
+            base: self.base.split_off_left(),
+        }
+    }
+
+    fn to_reducer(&self) -> Self::Reducer {
+        self.base.to_reducer()
+    }
+}
+
+impl<'a, K, V, F> Folder<RefMulti<'a, K, V>> for HoldingConsumer<'a, K, V, F>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    F: Folder<RefMulti<'a, K, V>>,
+{
+    type Result = F::Result;
+
+    fn consume(self, item: RefMulti<'a, K, V>) -> Self {
+        let _held = self.data.hold(self.data.shard_of(item.key()));
+        HoldingConsumer {
+            data: self.data,
+            base: self.base.consume(item),
+        }
+    }
+
+    fn complete(self) -> Self::Result {
+        self.base.complete()
+    }
+
+    fn full(&self) -> bool {
+        self.base.full()
+    }
+}
+
+/// Parallel iterator over mutable entries, from `par_iter_mut()`
+pub struct ParIterMut<'a, K, V> {
+    segments: &'a mut [DashMap<K, V>],
+}
+
+impl<'a, K, V> ParallelIterator for ParIterMut<'a, K, V>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync,
+{
+    type Item = RefMutMulti<'a, K, V>;
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>,
+    {
+        self.segments
+            .par_iter_mut()
+            .flat_map(|segment| segment.par_iter_mut())
+            .drive_unindexed(consumer)
+    }
+}
+
+/// Parallel iterator that consumes a `MyData` and yields owned entries
+pub struct IntoParIter<K, V> {
+    segments: Vec<DashMap<K, V>>,
+}
+
+impl<K, V> ParallelIterator for IntoParIter<K, V>
+where
+    K: Hash + Eq + Send,
+    V: Send,
+{
+    type Item = (K, V);
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: UnindexedConsumer<Self::Item>,
+    {
+        self.segments
+            .into_par_iter()
+            .flat_map(|segment| segment.into_par_iter())
+            .drive_unindexed(consumer)
+    }
+}
+
+impl<'a, K, V> IntoParallelIterator for &'a MyData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    type Iter = ParIter<'a, K, V>;
+    type Item = RefMulti<'a, K, V>;
+
+    fn into_par_iter(self) -> Self::Iter {
+        ParIter { data: self }
+    }
+}
+
+impl<'a, K, V> IntoParallelIterator for &'a mut MyData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    type Iter = ParIterMut<'a, K, V>;
+    type Item = RefMutMulti<'a, K, V>;
+
+    fn into_par_iter(self) -> Self::Iter {
+        ParIterMut {
+            segments: &mut self.segments,
+        }
+    }
+}
+
+impl<K, V> IntoParallelIterator for MyData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    type Iter = IntoParIter<K, V>;
+    type Item = (K, V);
+
+    fn into_par_iter(self) -> Self::Iter {
+        IntoParIter {
+            segments: self.segments,
+        }
+    }
+}
+
+impl<K, V> ParallelExtend<(K, V)> for MyData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn par_extend<I>(&mut self, par_iter: I)
+    where
+        I: IntoParallelIterator<Item = (K, V)>,
+    {
+        let data = &*self;
+        par_iter.into_par_iter().for_each(|(key, value)| {
+            data.insert(key, value);
+        });
+    }
+}
+
+/// Collects into a store with id 0 and one segment per rayon thread, at least four
+impl<K, V> FromParallelIterator<(K, V)> for MyData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn from_par_iter<I>(par_iter: I) -> Self
+    where
+        I: IntoParallelIterator<Item = (K, V)>,
+    {
+        let mut data = MyData::new(0, rayon::current_num_threads().max(4));
+        data.par_extend(par_iter);
+        data
+    }
 }
 
 // Implementation for safe cloning of the entire structure
@@ -208,4 +1120,302 @@ where
     fn clone(&self) -> Self {
         self.clone_data()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A one-segment store plus a key, another key in the same shard and one
+    /// in a different shard of that segment
+    fn store_with_shard_keys() -> (MyData<u64, u64>, u64, u64, u64) {
+        let store = MyData::new(1, 1);
+        for key in 0..256 {
+            store.insert(key, key);
+        }
+        let home = store.shard_of(&0);
+        let same = (1..256).find(|k| store.shard_of(k) == home).unwrap();
+        let other = (1..256).find(|k| store.shard_of(k) != home).unwrap();
+        (store, 0, same, other)
+    }
+
+    /// What `try_get` on the same-shard and other-shard keys returned
+    fn probe(store: &MyData<u64, u64>, same: u64, other: u64) -> (bool, bool) {
+        (store.try_get(&same).is_err(), store.try_get(&other).is_ok())
+    }
+
+    #[test]
+    fn for_each_rejects_only_the_held_shard() {
+        let (store, key, same, other) = store_with_shard_keys();
+        let store = Arc::new(store);
+        let seen = Arc::new(Mutex::new(None));
+        let (inner, result) = (Arc::clone(&store), Arc::clone(&seen));
+        store.for_each(move |k, _| {
+            if *k == key {
+                *result.lock().unwrap() = Some(probe(&inner, same, other));
+            }
+        });
+        assert_eq!(*seen.lock().unwrap(), Some((true, true)));
+    }
+
+    #[test]
+    fn for_each_cancellable_rejects_only_the_held_shard() {
+        let (store, key, same, other) = store_with_shard_keys();
+        let mut seen = None;
+        store
+            .for_each_cancellable(|k, _| {
+                if *k == key {
+                    seen = Some(probe(&store, same, other));
+                }
+            }, &OpContext::new())
+            .unwrap();
+        assert_eq!(seen, Some((true, true)));
+    }
+
+    #[test]
+    fn find_rejects_only_the_held_shard() {
+        let (store, key, same, other) = store_with_shard_keys();
+        let seen = Mutex::new(None);
+        store.find(|k, _| {
+            if *k == key {
+                *seen.lock().unwrap() = Some(probe(&store, same, other));
+            }
+            false
+        });
+        assert_eq!(seen.into_inner().unwrap(), Some((true, true)));
+    }
+
+    #[test]
+    fn find_cancellable_rejects_only_the_held_shard() {
+        let (store, key, same, other) = store_with_shard_keys();
+        let seen = Mutex::new(None);
+        store
+            .find_cancellable(|k, _| {
+                if *k == key {
+                    *seen.lock().unwrap() = Some(probe(&store, same, other));
+                }
+                false
+            }, &OpContext::new())
+            .unwrap();
+        assert_eq!(seen.into_inner().unwrap(), Some((true, true)));
+    }
+
+    #[test]
+    fn transaction_rejects_only_the_held_shard() {
+        let (store, key, same, other) = store_with_shard_keys();
+        let seen = store.transaction(&key, |_, value| {
+            *value += 1;
+            (probe(&store, same, other), store.try_insert(other, 0).is_ok())
+        });
+        assert_eq!(seen, Some(((true, true), true)));
+        assert_eq!(store.get(&other).map(|v| *v), Some(0));
+    }
+
+    #[test]
+    fn upsert_rejects_only_the_held_shard() {
+        let (store, _, same, other) = store_with_shard_keys();
+        let fresh = (256..1024).find(|k| store.shard_of(k) == store.shard_of(&same)).unwrap();
+        let seen = store.upsert(fresh, || 0, |_, _| (probe(&store, same, other), store.try_remove(&same).is_err()));
+        assert_eq!(seen, ((true, true), true));
+    }
+
+    #[test]
+    fn plain_calls_on_other_shards_work_inside_closures() {
+        let (store, key, _, other) = store_with_shard_keys();
+        let value = store.transaction(&key, |_, _| store.get(&other).map(|v| *v));
+        assert_eq!(value, Some(Some(other)));
+    }
+
+    #[test]
+    fn swap_with_keeps_racing_writes() {
+        let a = MyData::<u64, u64>::new(1, 4);
+        let b = MyData::<u64, u64>::new(2, 4);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for key in 0..20_000 {
+                    a.insert(key, key);
+                }
+            });
+            for _ in 0..200 {
+                a.swap_with(&b);
+            }
+        });
+        assert_eq!(a.len() + b.len(), 20_000);
+    }
+
+    #[test]
+    fn whole_store_writer_waits_for_a_held_ref() {
+        let store = MyData::<u64, u64>::new(1, 2);
+        store.insert(1, 1);
+        std::thread::scope(|s| {
+            let held = store.get(&1);
+            let writer = s.spawn(|| store.replace_all(vec![(2, 2)]));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!writer.is_finished());
+            assert_eq!(*held.unwrap(), 1);
+            writer.join().unwrap();
+        });
+        assert_eq!(store.keys(), vec![2]);
+    }
+
+    #[test]
+    fn whole_store_writers_are_not_starved_by_point_writers() {
+        let store = MyData::<u64, u64>::new(1, 4);
+        let stop = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let (store, stop) = (&store, &stop);
+                s.spawn(move || {
+                    for key in (t * 100..t * 100 + 100).cycle() {
+                        if stop.load(std::sync::atomic::Ordering::Relaxed) {
+                            break;
+                        }
+                        store.insert(key, key);
+                    }
+                });
+            }
+            let other = MyData::<u64, u64>::new(2, 4);
+            for round in 0..50 {
+                store.replace_all(vec![(1_000 + round, round)]);
+                store.swap_with(&other);
+            }
+            stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn nested_scans_do_not_wait_behind_a_queued_writer() {
+        let store = MyData::<u64, u64>::new(1, 2);
+        store.insert(1, 1);
+        std::thread::scope(|s| {
+            let outer = store.scan();
+            let writer = s.spawn(|| store.replace_all(vec![(2, 2)]));
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(store.find(|_, _| true), vec![(1, 1)]);
+            drop(outer);
+            writer.join().unwrap();
+        });
+        assert_eq!(store.keys(), vec![2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant access to shard")]
+    fn plain_calls_on_the_held_shard_panic() {
+        let (store, key, same, _) = store_with_shard_keys();
+        store.transaction(&key, |_, _| store.insert(same, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "reentrant whole-store access")]
+    fn whole_store_calls_panic_inside_closures() {
+        let (store, _, _, _) = store_with_shard_keys();
+        store.find(|_, _| store.is_empty());
+    }
+
+    #[test]
+    fn verify_checksum_hashes_the_contents_before_repairing_them() {
+        let store = MyData::<u64, u64>::new(0, 4);
+        for key in 0..100 {
+            store.insert(key, key);
+        }
+        let checksum = store.state_hash();
+
+        // A stale copy of key 5 in the wrong segment, shadowed by the real one
+        let wrong = (store.get_segment_index(&5) + 1) % store.num_segments();
+        store.get_segment(wrong).unwrap().insert(5, 999);
+
+        let report = store.verify_checksum(checksum, true);
+        let check = report.checksum.unwrap();
+        assert_eq!(report.dropped, 1);
+        assert!(!check.matches(), "the damage must show in the checksum");
+        assert_eq!(check.after_repair, Some(checksum));
+
+        let report = store.verify_checksum(checksum, true);
+        assert!(report.is_consistent());
+        assert_eq!(report.checksum.unwrap().after_repair, None);
+    }
+
+    #[test]
+    fn par_iter_rejects_only_the_visited_shard() {
+        let (store, key, same, other) = store_with_shard_keys();
+        let seen = Mutex::new(None);
+        store.par_iter().for_each(|entry| {
+            if *entry.key() == key {
+                *seen.lock().unwrap() = Some((probe(&store, same, other), store.try_remove(&same).is_err()));
+            }
+        });
+        assert_eq!(seen.into_inner().unwrap(), Some(((true, true), true)));
+        assert!(HELD_SHARDS.with_borrow(Vec::is_empty));
+    }
+
+    #[test]
+    fn par_iter_holds_off_whole_store_writers() {
+        let store = MyData::<u64, u64>::new(1, 4);
+        store.replace_all((0..64u64).into_par_iter().map(|k| (k, k)));
+        let started = std::sync::Barrier::new(2);
+        let visited = Mutex::new(Vec::new());
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                store.par_iter().for_each(|entry| {
+                    if *entry.key() == 0 {
+                        started.wait();
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                    visited.lock().unwrap().push(*entry.key());
+                });
+            });
+            started.wait();
+            store.replace_all((100..164u64).into_par_iter().map(|k| (k, k)));
+        });
+        let mut visited = visited.into_inner().unwrap();
+        visited.sort_unstable();
+        assert_eq!(visited, (0..64).collect::<Vec<_>>());
+    }
+
+    /// Largest value seen, as a minimal `Mergeable` that can be hashed
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Max(u64);
+
+    impl Mergeable for Max {
+        fn merge(&mut self, other: &Self) {
+            self.0 = self.0.max(other.0);
+        }
+    }
+
+    #[test]
+    fn merge_from_converges_in_either_order() {
+        let base = MyData::new(1, 4);
+        for key in 0..32u64 {
+            base.insert(key, Max(key));
+        }
+        let left = base.clone_data();
+        let right = base.clone_data();
+        for key in 0..16u64 {
+            left.insert(key, Max(key + 100));
+            right.insert(key + 8, Max(key + 200));
+        }
+        left.insert(1000, Max(1));
+        right.insert(2000, Max(2));
+
+        let left_then_right = left.clone_data();
+        left_then_right.merge_from(&right);
+        let right_then_left = right.clone_data();
+        right_then_left.merge_from(&left);
+
+        assert_eq!(left_then_right.state_hash(), right_then_left.state_hash());
+        assert_eq!(left_then_right.len(), 34);
+        for store in [&left_then_right, &right_then_left] {
+            assert_eq!(store.get(&0).map(|v| v.0), Some(100));
+            assert_eq!(store.get(&10).map(|v| v.0), Some(202));
+            assert_eq!(store.get(&20).map(|v| v.0), Some(212));
+            assert_eq!(store.get(&31).map(|v| v.0), Some(31));
+            assert_eq!(store.get(&1000).map(|v| v.0), Some(1));
+            assert_eq!(store.get(&2000).map(|v| v.0), Some(2));
+        }
+
+        // Merging again changes nothing
+        let settled = left_then_right.state_hash();
+        left_then_right.merge_from(&right_then_left);
+        assert_eq!(left_then_right.state_hash(), settled);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::data_structures::MyData;
+use crate::worker_utils::map_reduce;
+
+/// Count, sum, min and max of a numeric projection over one group
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aggregates {
+    pub count: usize,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Default for Aggregates {
+    fn default() -> Self {
+        Aggregates {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl Aggregates {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn merge(&mut self, other: Aggregates) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Mean of the group, or `None` for an empty group
+    pub fn avg(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+/// A pending group-by over a `MyData`, created by `MyData::group_by`.
+/// Each segment is grouped in parallel and the partial groups are merged.
+pub struct GroupBy<'a, K, V, F>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    data: &'a MyData<K, V>,
+    key_fn: F,
+}
+
+impl<'a, K, V, G, F> GroupBy<'a, K, V, F>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    G: Hash + Eq + Send,
+    F: Fn(&K, &V) -> G + Send + Sync,
+{
+    pub(crate) fn new(data: &'a MyData<K, V>, key_fn: F) -> Self {
+        GroupBy { data, key_fn }
+    }
+
+    /// Count, sum, min, max and average of `value` for every group
+    pub fn aggregate<P>(self, value: P) -> HashMap<G, Aggregates>
+    where
+        P: Fn(&K, &V) -> f64 + Send + Sync,
+    {
+        self.fold(
+            Aggregates::default,
+            |acc, k, v| acc.add(value(k, v)),
+            |acc, other| acc.merge(other),
+        )
+    }
+
+    /// Number of entries in every group
+    pub fn count(self) -> HashMap<G, usize> {
+        self.fold(|| 0, |count, _, _| *count += 1, |count, other| *count += other)
+    }
+
+    /// Custom aggregate: `init` creates an empty accumulator, `fold` adds one
+    /// entry to it and `merge` combines accumulators from different segments
+    pub fn fold<A, I, Fo, M>(self, init: I, fold: Fo, merge: M) -> HashMap<G, A>
+    where
+        A: Send,
+        I: Fn() -> A + Send + Sync,
+        Fo: Fn(&mut A, &K, &V) + Send + Sync,
+        M: Fn(&mut A, A) + Send + Sync,
+    {
+        let key_fn = &self.key_fn;
+        map_reduce(
+            self.data,
+            |_, segment| {
+                let mut groups: HashMap<G, A> = HashMap::new();
+                for entry in segment.iter() {
+                    let group = groups.entry(key_fn(entry.key(), entry.value())).or_insert_with(&init);
+                    fold(group, entry.key(), entry.value());
+                }
+                groups
+            },
+            |mut a, b| {
+                for (group, partial) in b {
+                    match a.get_mut(&group) {
+                        Some(existing) => merge(existing, partial),
+                        None => {
+                            a.insert(group, partial);
+                        }
+                    }
+                }
+                a
+            },
+        )
+        .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys 1..=20 over several segments, valued at ten times the key
+    fn store() -> MyData<u64, u64> {
+        let data = MyData::new(0, 4);
+        for key in 1..=20 {
+            data.insert(key, key * 10);
+        }
+        data
+    }
+
+    #[test]
+    fn groups_collect_every_entry_once() {
+        let data = store();
+        let counts = data.group_by(|k, _| k % 3).count();
+        assert_eq!(counts, HashMap::from([(0, 6), (1, 7), (2, 7)]));
+
+        let mut members = data.group_by(|k, _| k % 2 == 0).fold(
+            Vec::new,
+            |keys, k, _| keys.push(*k),
+            |keys, other| keys.extend(other),
+        );
+        for keys in members.values_mut() {
+            keys.sort_unstable();
+        }
+        assert_eq!(members[&true], (2..=20).step_by(2).collect::<Vec<_>>());
+        assert_eq!(members[&false], (1..=19).step_by(2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn aggregates_match_a_sequential_computation() {
+        let data = store();
+        let groups = data.group_by(|k, _| k % 3).aggregate(|_, v| *v as f64);
+
+        let zero = groups[&0];
+        // Keys 3, 6, ..., 18
+        assert_eq!(zero.count, 6);
+        assert_eq!(zero.sum, 630.0);
+        assert_eq!(zero.min, 30.0);
+        assert_eq!(zero.max, 180.0);
+        assert_eq!(zero.avg(), Some(105.0));
+
+        let one = groups[&1];
+        // Keys 1, 4, ..., 19
+        assert_eq!((one.count, one.sum, one.min, one.max), (7, 700.0, 10.0, 190.0));
+        assert_eq!(Aggregates::default().avg(), None);
+    }
+
+    #[test]
+    fn an_empty_store_has_no_groups() {
+        let data = MyData::<u64, u64>::new(0, 4);
+        assert!(data.group_by(|k, _| *k).count().is_empty());
+    }
+}
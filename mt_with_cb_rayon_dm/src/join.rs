@@ -0,0 +1,378 @@
+use rayon::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+use crate::data_structures::MyData;
+
+/// Visit every entry of `left` in parallel together with the matching value in
+/// `right`, if any. When both stores share a layout the probe only looks at the
+/// segment with the same index instead of hashing the key again.
+/// Each shard of `left` is copied out before probing, so no lock on `left` is
+/// held while waiting for one on `right`: two opposite joins, or a self-join,
+/// cannot deadlock with writers queued on either store.
+fn probe_parallel<K, V1, V2, F>(left: &MyData<K, V1>, right: &MyData<K, V2>, visit: F)
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    V2: Clone + Send + Sync + 'static,
+    F: Fn(&K, &V1, Option<&V2>) + Send + Sync,
+{
+    let aligned = left.shares_layout_with(right);
+
+    (0..left.num_segments()).into_par_iter().for_each(|idx| {
+        let Some(left_segment) = left.get_segment(idx) else {
+            return;
+        };
+        let right_segment = if aligned { right.get_segment(idx) } else { None };
+
+        for shard in left_segment.shards() {
+            let entries: Vec<(K, V1)> = shard.read().iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+            for (key, value) in &entries {
+                let matched = match right_segment {
+                    Some(segment) => segment.get(key),
+                    None => right.get(key),
+                };
+                visit(key, value, matched.as_ref().map(|r| r.value()));
+            }
+        }
+    });
+}
+
+/// Entries whose key is in both stores, combined with `combine`.
+/// The result has the same layout as `left`.
+pub fn inner_join<K, V1, V2, V3, F>(
+    left: &MyData<K, V1>,
+    right: &MyData<K, V2>,
+    id: usize,
+    combine: F,
+) -> MyData<K, V3>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    V2: Clone + Send + Sync + 'static,
+    V3: Clone + Send + Sync + 'static,
+    F: Fn(&K, &V1, &V2) -> V3 + Send + Sync,
+{
+    let output = MyData::new_with_layout(id, left);
+    probe_parallel(left, right, |key, lv, rv| {
+        if let Some(rv) = rv {
+            output.insert(key.clone(), combine(key, lv, rv));
+        }
+    });
+    output
+}
+
+/// Every entry of `left`, combined with the matching value of `right` if there is one.
+/// The result has the same layout as `left`.
+pub fn left_join<K, V1, V2, V3, F>(
+    left: &MyData<K, V1>,
+    right: &MyData<K, V2>,
+    id: usize,
+    combine: F,
+) -> MyData<K, V3>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    V2: Clone + Send + Sync + 'static,
+    V3: Clone + Send + Sync + 'static,
+    F: Fn(&K, &V1, Option<&V2>) -> V3 + Send + Sync,
+{
+    let output = MyData::new_with_layout(id, left);
+    probe_parallel(left, right, |key, lv, rv| {
+        output.insert(key.clone(), combine(key, lv, rv));
+    });
+    output
+}
+
+/// Entries of `left` whose key does not appear in `right`
+pub fn anti_join<K, V1, V2>(left: &MyData<K, V1>, right: &MyData<K, V2>, id: usize) -> MyData<K, V1>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    V2: Clone + Send + Sync + 'static,
+{
+    let output = MyData::new_with_layout(id, left);
+    probe_parallel(left, right, |key, lv, rv| {
+        if rv.is_none() {
+            output.insert(key.clone(), lv.clone());
+        }
+    });
+    output
+}
+
+/// Lazy inner join by key. Each step materialises the matches of one
+/// segment of the left store, so only one segment is locked at a time.
+pub struct JoinStream<'a, K, V1, V2>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    V2: Clone + Send + Sync + 'static,
+{
+    left: &'a MyData<K, V1>,
+    right: &'a MyData<K, V2>,
+    aligned: bool,
+    next_segment: usize,
+    buffer: VecDeque<(K, V1, V2)>,
+}
+
+/// Start a lazy inner join of two stores by key
+pub fn join_stream<'a, K, V1, V2>(left: &'a MyData<K, V1>, right: &'a MyData<K, V2>) -> JoinStream<'a, K, V1, V2>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    V2: Clone + Send + Sync + 'static,
+{
+    JoinStream {
+        left,
+        right,
+        aligned: left.shares_layout_with(right),
+        next_segment: 0,
+        buffer: VecDeque::new(),
+    }
+}
+
+impl<K, V1, V2> Iterator for JoinStream<'_, K, V1, V2>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    V2: Clone + Send + Sync + 'static,
+{
+    type Item = (K, V1, V2);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() {
+            let idx = self.next_segment;
+            let left_segment = self.left.get_segment(idx)?;
+            self.next_segment += 1;
+
+            let right_segment = if self.aligned { self.right.get_segment(idx) } else { None };
+            // Copy the segment out first, so no lock on `left` is held while probing `right`
+            let entries: Vec<(K, V1)> = left_segment.iter().map(|e| (e.key().clone(), e.value().clone())).collect();
+            for (key, lv) in entries {
+                let matched = match right_segment {
+                    Some(segment) => segment.get(&key).map(|r| r.value().clone()),
+                    None => self.right.get(&key).map(|r| r.value().clone()),
+                };
+                if let Some(rv) = matched {
+                    self.buffer.push_back((key, lv, rv));
+                }
+            }
+        }
+        self.buffer.pop_front()
+    }
+}
+
+/// One row of a derived-key join: the join key, the left entry and the right entry
+pub type DerivedRow<J, K1, V1, K2, V2> = (J, (K1, V1), (K2, V2));
+
+/// Lazy inner join on keys derived from the entries of two stores.
+/// The right side is built into a hash table up front (in parallel); the
+/// left side is probed one segment at a time as the iterator is consumed.
+pub struct DerivedJoin<'a, K1, V1, K2, V2, J, FL>
+where
+    K1: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+{
+    left: &'a MyData<K1, V1>,
+    left_key: FL,
+    table: HashMap<J, Vec<(K2, V2)>>,
+    next_segment: usize,
+    buffer: VecDeque<DerivedRow<J, K1, V1, K2, V2>>,
+}
+
+/// Join two stores on keys derived from each side's entries
+pub fn join_by<'a, K1, V1, K2, V2, J, FL, FR>(
+    left: &'a MyData<K1, V1>,
+    right: &MyData<K2, V2>,
+    left_key: FL,
+    right_key: FR,
+) -> DerivedJoin<'a, K1, V1, K2, V2, J, FL>
+where
+    K1: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    K2: Hash + Eq + Clone + Send + Sync + 'static,
+    V2: Clone + Send + Sync + 'static,
+    J: Hash + Eq + Clone + Send,
+    FL: Fn(&K1, &V1) -> J,
+    FR: Fn(&K2, &V2) -> J + Send + Sync,
+{
+    // Build phase: one partial table per segment, merged at the end
+    let table = (0..right.num_segments())
+        .into_par_iter()
+        .map(|idx| {
+            let mut partial: HashMap<J, Vec<(K2, V2)>> = HashMap::new();
+            if let Some(segment) = right.get_segment(idx) {
+                for entry in segment.iter() {
+                    partial
+                        .entry(right_key(entry.key(), entry.value()))
+                        .or_default()
+                        .push((entry.key().clone(), entry.value().clone()));
+                }
+            }
+            partial
+        })
+        .reduce(HashMap::new, |mut a, b| {
+            for (join_key, rows) in b {
+                a.entry(join_key).or_default().extend(rows);
+            }
+            a
+        });
+
+    DerivedJoin {
+        left,
+        left_key,
+        table,
+        next_segment: 0,
+        buffer: VecDeque::new(),
+    }
+}
+
+impl<K1, V1, K2, V2, J, FL> Iterator for DerivedJoin<'_, K1, V1, K2, V2, J, FL>
+where
+    K1: Hash + Eq + Clone + Send + Sync + 'static,
+    V1: Clone + Send + Sync + 'static,
+    K2: Clone,
+    V2: Clone,
+    J: Hash + Eq + Clone,
+    FL: Fn(&K1, &V1) -> J,
+{
+    type Item = DerivedRow<J, K1, V1, K2, V2>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() {
+            let segment = self.left.get_segment(self.next_segment)?;
+            self.next_segment += 1;
+
+            for entry in segment.iter() {
+                let join_key = (self.left_key)(entry.key(), entry.value());
+                if let Some(rows) = self.table.get(&join_key) {
+                    for row in rows {
+                        self.buffer.push_back((
+                            join_key.clone(),
+                            (entry.key().clone(), entry.value().clone()),
+                            row.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+        self.buffer.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Keys 0..10 on the left and 5..15 on the right, the right one either
+    /// laid out like the left or with its own hasher and segment count
+    fn stores(aligned: bool) -> (MyData<u64, u64>, MyData<u64, String>) {
+        let left = MyData::new(0, 4);
+        for key in 0..10 {
+            left.insert(key, key * 10);
+        }
+        let right = if aligned { MyData::new_with_layout(1, &left) } else { MyData::new(1, 3) };
+        assert_eq!(left.shares_layout_with(&right), aligned);
+        for key in 5..15 {
+            right.insert(key, format!("r{}", key));
+        }
+        (left, right)
+    }
+
+    fn sorted<V: Clone + Send + Sync + 'static>(data: &MyData<u64, V>) -> Vec<(u64, V)> {
+        let mut entries = data.find(|_, _| true);
+        entries.sort_by_key(|(key, _)| *key);
+        entries
+    }
+
+    #[test]
+    fn joins_match_on_overlapping_keys() {
+        for aligned in [true, false] {
+            let (left, right) = stores(aligned);
+
+            let inner = inner_join(&left, &right, 2, |_, l, r| format!("{}-{}", l, r));
+            let expected: Vec<(u64, String)> = (5..10).map(|k| (k, format!("{}-r{}", k * 10, k))).collect();
+            assert_eq!(sorted(&inner), expected, "aligned: {}", aligned);
+
+            let outer = left_join(&left, &right, 3, |_, l, r| (*l, r.cloned()));
+            let expected: Vec<(u64, (u64, Option<String>))> =
+                (0..10).map(|k| (k, (k * 10, (k >= 5).then(|| format!("r{}", k))))).collect();
+            assert_eq!(sorted(&outer), expected, "aligned: {}", aligned);
+
+            let anti = anti_join(&left, &right, 4);
+            assert_eq!(sorted(&anti), (0..5).map(|k| (k, k * 10)).collect::<Vec<_>>(), "aligned: {}", aligned);
+
+            let mut streamed: Vec<_> = join_stream(&left, &right).collect();
+            streamed.sort_by_key(|(key, _, _)| *key);
+            assert_eq!(streamed, (5..10).map(|k| (k, k * 10, format!("r{}", k))).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn join_by_matches_derived_keys() {
+        let (left, right) = stores(false);
+        let mut rows: Vec<_> = join_by(&left, &right, |_, v| *v / 10, |k, _| *k).collect();
+        rows.sort_by_key(|(join_key, _, _)| *join_key);
+        let expected: Vec<_> = (5..10).map(|k| (k, (k, k * 10), (k, format!("r{}", k)))).collect();
+        assert_eq!(rows, expected);
+
+        // Many-to-many: every left key pairs with every right key of the same parity
+        let rows: Vec<_> = join_by(&left, &right, |k, _| *k % 2, |k, _| *k % 2).collect();
+        assert_eq!(rows.len(), 2 * 5 * 5);
+        assert!(rows.iter().all(|(parity, (l, _), (r, _))| l % 2 == *parity && r % 2 == *parity));
+    }
+
+    #[test]
+    fn opposite_joins_run_together_with_writers() {
+        let (left, right) = stores(true);
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for key in (0..15).cycle() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    left.insert(key % 10, (key % 10) * 10);
+                    right.insert(key.max(5), format!("r{}", key.max(5)));
+                }
+            });
+            let backwards = s.spawn(|| {
+                for _ in 0..200 {
+                    assert_eq!(inner_join(&right, &left, 5, |_, r, _| r.clone()).len(), 5);
+                }
+            });
+            for _ in 0..200 {
+                assert_eq!(inner_join(&left, &right, 6, |_, l, _| *l).len(), 5);
+            }
+            backwards.join().unwrap();
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn self_join_does_not_block_writers() {
+        let data = MyData::<u64, u64>::new(0, 2);
+        for key in 0..1_000 {
+            data.insert(key, key);
+        }
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                // Rewrite the same keys so the store keeps its size
+                for key in (0..1_000).cycle() {
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    data.insert(key, key);
+                }
+            });
+            for _ in 0..20 {
+                let joined = inner_join(&data, &data, 1, |_, a, b| a + b);
+                assert_eq!(joined.len(), 1_000);
+                assert!(join_stream(&data, &data).take(10).all(|(k, a, b)| a == k && b == k));
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+}
@@ -0,0 +1,235 @@
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::verify::Fault;
+
+// Fewest acquires between two purges of lapsed grants
+const MIN_PURGE_INTERVAL: usize = 64;
+
+/// Proof of holding a lease on one key. The fencing token increases with
+/// every grant on the store, so a write carrying an older token can be told
+/// apart from one made by the current holder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaseToken<K> {
+    key: K,
+    fence: u64,
+    expires_at: Instant,
+}
+
+impl<K> LeaseToken<K> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Fencing token of the grant, unique and increasing per store
+    pub fn fence(&self) -> u64 {
+        self.fence
+    }
+
+    /// Time left before the lease lapses, zero once it has
+    pub fn remaining(&self) -> Duration {
+        self.expires_at.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Why a lease operation or leased write was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseError {
+    /// The lease ran out before it was renewed
+    Expired,
+    /// The lease lapsed and was granted again, to the given fencing token
+    Superseded { current: u64 },
+    /// Nobody holds a lease on the key
+    NotHeld,
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseError::Expired => f.write_str("lease expired"),
+            LeaseError::Superseded { current } => {
+                write!(f, "lease was superseded by fencing token {}", current)
+            }
+            LeaseError::NotHeld => f.write_str("no lease is held on the key"),
+        }
+    }
+}
+
+impl std::error::Error for LeaseError {}
+
+struct Grant {
+    fence: u64,
+    expires_at: Instant,
+}
+
+/// Current lease of every leased key. Lapsed grants count as free and are
+/// purged as new leases are taken, after at least as many acquires as the
+/// table held grants, so purging costs O(1) per acquire on average. Once a
+/// lapsed grant is purged, its token gets `NotHeld` rather than `Expired`.
+pub(crate) struct LeaseTable<K>
+where
+    K: Hash + Eq + Clone,
+{
+    grants: DashMap<K, Grant>,
+    next_fence: AtomicU64,
+    acquires_since_purge: AtomicUsize,
+    purge_interval: AtomicUsize,
+}
+
+impl<K> LeaseTable<K>
+where
+    K: Hash + Eq + Clone,
+{
+    pub(crate) fn new() -> Self {
+        LeaseTable {
+            grants: DashMap::new(),
+            next_fence: AtomicU64::new(1),
+            acquires_since_purge: AtomicUsize::new(0),
+            purge_interval: AtomicUsize::new(MIN_PURGE_INTERVAL),
+        }
+    }
+
+    pub(crate) fn acquire(&self, key: K, ttl: Duration) -> Option<LeaseToken<K>> {
+        let token = {
+            let now = Instant::now();
+            let mut grant = self.grants.entry(key.clone()).or_insert(Grant {
+                fence: 0,
+                expires_at: now,
+            });
+            if grant.fence != 0 && grant.expires_at > now {
+                return None;
+            }
+            grant.fence = self.next_fence.fetch_add(1, Ordering::Relaxed);
+            grant.expires_at = now + ttl;
+            LeaseToken {
+                key,
+                fence: grant.fence,
+                expires_at: grant.expires_at,
+            }
+        };
+        // Only with the grant unlocked, as purging locks every shard
+        let acquires = self.acquires_since_purge.fetch_add(1, Ordering::Relaxed) + 1;
+        if acquires >= self.purge_interval.load(Ordering::Relaxed) {
+            self.purge_lapsed();
+        }
+        Some(token)
+    }
+
+    /// Drop every grant that has lapsed
+    fn purge_lapsed(&self) {
+        self.acquires_since_purge.store(0, Ordering::Relaxed);
+        let now = Instant::now();
+        self.grants.retain(|_, grant| grant.expires_at > now);
+        self.purge_interval
+            .store(self.grants.len().max(MIN_PURGE_INTERVAL), Ordering::Relaxed);
+    }
+
+    pub(crate) fn renew(&self, token: &LeaseToken<K>, ttl: Duration) -> Result<LeaseToken<K>, LeaseError> {
+        let mut grant = self.grants.get_mut(&token.key).ok_or(LeaseError::NotHeld)?;
+        Self::check(&grant, token)?;
+        grant.expires_at = Instant::now() + ttl;
+        Ok(LeaseToken {
+            key: token.key.clone(),
+            fence: grant.fence,
+            expires_at: grant.expires_at,
+        })
+    }
+
+    pub(crate) fn release(&self, token: &LeaseToken<K>) -> Result<(), LeaseError> {
+        let mut outcome = Err(LeaseError::NotHeld);
+        self.grants.remove_if(&token.key, |_, grant| {
+            outcome = Self::check(grant, token);
+            outcome.is_ok()
+        });
+        outcome
+    }
+
+    /// Run `f` only if `token` is the key's valid lease. The grant stays
+    /// locked while `f` runs, so the lease cannot change hands midway.
+    pub(crate) fn while_held<R>(&self, token: &LeaseToken<K>, f: impl FnOnce() -> R) -> Result<R, LeaseError> {
+        let grant = self.grants.get(&token.key).ok_or(LeaseError::NotHeld)?;
+        Self::check(&grant, token)?;
+        let result = f();
+        drop(grant);
+        Ok(result)
+    }
+
+    pub(crate) fn active(&self) -> usize {
+        let now = Instant::now();
+        self.grants.iter().filter(|grant| grant.expires_at > now).count()
+    }
+
+    /// Grants whose fencing token is not unique, or would be handed out again
+    pub(crate) fn faults(&self) -> Vec<Fault<K>> {
+        let next = self.next_fence.load(Ordering::Relaxed);
+        let mut seen = HashSet::new();
+        let mut faults = Vec::new();
+        for grant in self.grants.iter() {
+            let fence = grant.fence;
+            if fence >= next {
+                faults.push(Fault::FenceAhead {
+                    key: grant.key().clone(),
+                    fence,
+                    next,
+                });
+            } else if !seen.insert(fence) {
+                faults.push(Fault::DuplicateFence {
+                    key: grant.key().clone(),
+                    fence,
+                });
+            }
+        }
+        faults
+    }
+
+    fn check(grant: &Grant, token: &LeaseToken<K>) -> Result<(), LeaseError> {
+        if grant.fence != token.fence {
+            Err(LeaseError::Superseded { current: grant.fence })
+        } else if grant.expires_at <= Instant::now() {
+            Err(LeaseError::Expired)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lapsed_grants_are_purged() {
+        let table = LeaseTable::new();
+        let held = table.acquire(0, Duration::from_secs(60)).unwrap();
+        let lapsed = table.acquire(1, Duration::ZERO).unwrap();
+        for key in 2..1_000 {
+            table.acquire(key, Duration::ZERO).unwrap();
+        }
+        assert!(table.grants.len() <= MIN_PURGE_INTERVAL + 1, "{} grants kept", table.grants.len());
+        assert_eq!(table.active(), 1);
+        assert_eq!(table.release(&lapsed), Err(LeaseError::NotHeld));
+        assert_eq!(table.release(&held), Ok(()));
+    }
+
+    #[test]
+    fn faults_flag_fencing_tokens_that_repeat() {
+        let table = LeaseTable::new();
+        let ttl = Duration::from_secs(60);
+        table.acquire("a", ttl).unwrap();
+        table.acquire("b", ttl).unwrap();
+        assert!(table.faults().is_empty());
+
+        // As if the fence counter had been restored from an old snapshot
+        table.next_fence.store(2, Ordering::Relaxed);
+        assert_eq!(table.faults(), vec![Fault::FenceAhead { key: "b", fence: 2, next: 2 }]);
+
+        table.acquire("c", ttl).unwrap();
+        let faults = table.faults();
+        assert_eq!(faults.len(), 1);
+        assert!(matches!(faults[0], Fault::DuplicateFence { fence: 2, .. }));
+    }
+}
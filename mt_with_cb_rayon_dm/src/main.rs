@@ -1,16 +1,58 @@
+mod async_data;
+mod cache;
+mod cancellation;
+mod codec;
+mod counter;
+mod crdt;
 mod data_structures;
+mod group;
+mod join;
+mod lease;
+mod merge;
+mod priority;
+mod query;
+mod queue;
+mod recorder;
+mod sampling;
+mod stm;
+mod supervisor;
+mod textio;
+mod tiered;
+mod verify;
+mod versioned;
 mod worker_utils;
 
 use std::sync::Arc;
 use crossbeam::channel;
 use rayon::prelude::*;
 use std::thread;
+use std::time::{Duration, Instant};
 
-use data_structures::MyData;
+use async_data::{AsyncData, ScanPool};
+use cache::{BackingStore, CachedData, FileBackingStore, WriteMode};
+use cancellation::{CancellationToken, OpContext, OpError};
+use counter::StripedCounter;
+use crdt::{GCounter, LwwRegister, Mergeable, OrSet, PNCounter};
+use data_structures::{MyData, ReentrantAccess};
+use join::{anti_join, inner_join, join_by, join_stream, left_join};
+use lease::LeaseError;
+use merge::{Add, ListAppend, MergeData, MergeMode, SetUnion};
+use priority::{priority_channel, LaneConfig, Priority};
+use query::Query;
+use queue::{worker_channel, OverflowPolicy, QueueConfig};
+use recorder::{OperationRecorder, RecordedOp, RecordingSource, ReplaySpeed, Replayer};
+use sampling::{count_where, distinct_count, quantiles, sample};
+use stm::StmData;
+use supervisor::{panic_message, SupervisorConfig, WorkerHandle, WorkerState};
+use textio::{export_csv, export_jsonl, import_csv, import_jsonl, Number, PlainText, TextCodec};
+use tiered::{TierConfig, TieredData};
+use verify::Fault;
+use versioned::{At, HistoryConfig, VersionedData};
 
 use worker_utils::{
     Operation, create_worker_fn, process_keys_parallel,
-    scoped_data_processing, parallel_segment_process, batch_process_parallel
+    scoped_data_processing, parallel_segment_process, batch_process_parallel,
+    Combiner, ScopedExecutor, map_segments, map_reduce, map_reduce_with, map_reduce_until, map_reduce_many
 };
 
 fn main() {
@@ -67,8 +109,8 @@ fn main() {
     // Example 2: Worker-based approach with Crossbeam channels
     println!("\nExample 2: Worker-based approach with Crossbeam channels");
     {
-        // Create channels for communicating with the worker
-        let (sender, receiver) = channel::unbounded();
+        // Create a bounded queue so a fast producer cannot outrun the worker
+        let (sender, receiver) = worker_channel(QueueConfig::bounded(64, OverflowPolicy::Block));
 
         // Spawn a worker thread
         let worker_data = Arc::clone(&data);
@@ -87,8 +129,9 @@ fn main() {
             sender.send(Operation::Get(key, response_sender)).unwrap();
 
             match response_receiver.recv() {
-                Ok(Some(value)) => println!("Retrieved: {}", value),
-                Ok(None) => println!("Not found"),
+                Ok(Ok(Some(value))) => println!("Retrieved: {}", value),
+                Ok(Ok(None)) => println!("Not found"),
+                Ok(Err(e)) => println!("Request skipped: {}", e),
                 Err(_) => println!("Error receiving response"),
             }
         }
@@ -99,12 +142,13 @@ fn main() {
         sender.send(Operation::Find(predicate, response_sender)).unwrap();
 
         match response_receiver.recv() {
-            Ok(results) => {
+            Ok(Ok(results)) => {
                 println!("Found {} entries with value > 1095", results.len());
                 for (k, v) in results.iter().take(3) {
                     println!("  {} = {}", k, v);
                 }
             },
+            Ok(Err(e)) => println!("Find skipped: {}", e),
             Err(_) => println!("Error receiving find results"),
         }
 
@@ -117,35 +161,31 @@ fn main() {
         let (response_sender, response_receiver) = channel::bounded(1);
         sender.send(Operation::Get(key_to_remove.clone(), response_sender)).unwrap();
         match response_receiver.recv() {
-            Ok(Some(_)) => println!("Error: Key still exists after removal"),
-            Ok(None) => println!("Verified key was successfully removed"),
+            Ok(Ok(Some(_))) => println!("Error: Key still exists after removal"),
+            Ok(Ok(None)) => println!("Verified key was successfully removed"),
+            Ok(Err(e)) => println!("Request skipped: {}", e),
             Err(_) => println!("Error receiving response"),
         }
 
-        // Use Clear operation
-        sender.send(Operation::Clear).unwrap();
-        println!("Sent Clear operation to worker");
-
-        // Send a couple more operations after Clear to verify it worked
+        // Swap in a fresh dataset in one step; unlike Clear followed by
+        // repopulating, readers never see the store empty in between
         let key = "post-clear-test".to_string();
-        sender.send(Operation::Insert(key.clone(), 42)).unwrap();
+        let mut fresh: Vec<(String, u64)> = (0..100).map(|i| (format!("repop-key-{}", i), i as u64)).collect();
+        fresh.push((key.clone(), 42));
+        sender.send(Operation::ReplaceAll(fresh)).unwrap();
+        println!("Sent ReplaceAll operation to worker");
 
         let (response_sender, response_receiver) = channel::bounded(1);
         sender.send(Operation::Get(key.clone(), response_sender)).unwrap();
         match response_receiver.recv() {
-            Ok(Some(value)) => println!("After Clear, inserted and retrieved: {}", value),
-            _ => println!("Failed to insert/retrieve after Clear"),
+            Ok(Ok(Some(value))) => println!("After ReplaceAll, retrieved: {}", value),
+            _ => println!("Failed to retrieve after ReplaceAll"),
         }
 
         // Shutdown the worker
         sender.send(Operation::Shutdown).unwrap();
         let _ = worker_handle.join();
-
-        // Now repopulate
-        println!("Repopulating data after worker...");
-        (0..100).into_par_iter().for_each(|i| {
-            data.insert(format!("repop-key-{}", i), i as u64);
-        });
+        println!("Entries after replace: {}", data.len());
     }
 
     // Example 3: Using the transaction method
@@ -175,6 +215,17 @@ fn main() {
             let count = segment.iter().filter(|entry| *entry.value() > 50).count();
             println!("Segment has {} values greater than 50", count);
         });
+
+        // The map-reduce helpers return the counts instead of printing them
+        let per_segment = map_segments(&data, |_, segment| {
+            segment.iter().filter(|entry| *entry.value() > 50).count()
+        });
+        let total = map_reduce(
+            &data,
+            |_, segment| segment.iter().filter(|entry| *entry.value() > 50).count(),
+            |a, b| a + b,
+        );
+        println!("Per-segment counts in segment order: {:?}, total: {:?}", per_segment, total);
     }
 
     // Example 5: Using batch_process_parallel with multiple data structures
@@ -231,7 +282,1636 @@ fn main() {
             (idx, count, avg)
         });
 
-        println!("Processed {} segments", stats.len());
+        println!("Processed {} segments", stats.iter().filter(|r| r.is_ok()).count());
+
+        // Panicking segments are reported instead of silently dropped,
+        // and 1024 segments no longer means 1024 threads
+        let many_segments = MyData::<String, u64>::new(700, 1024);
+        for i in 0..5000u64 {
+            many_segments.insert(format!("scoped-key-{}", i), i);
+        }
+        let executor = ScopedExecutor::new(4);
+        let results = executor.run(&many_segments, |idx, segment| {
+            if idx % 300 == 299 {
+                panic!("segment {} is corrupt", idx);
+            }
+            (thread::current().id(), segment.len())
+        });
+        let threads: std::collections::HashSet<_> = results.iter().flatten().map(|(id, _)| *id).collect();
+        let entries: usize = results.iter().flatten().map(|(_, len)| len).sum();
+        println!(
+            "Bounded executor: {} segments on {} threads, {} entries in healthy segments",
+            results.len(),
+            threads.len(),
+            entries
+        );
+        for failure in results.iter().filter_map(|r| r.as_ref().err()) {
+            println!("  segment {} failed: {} ({})", failure.segment, failure.message, failure);
+        }
+    }
+
+    // Example 8: Backpressure with bounded worker queues
+    println!("\nExample 8: Backpressure with bounded worker queues");
+    {
+        // With no worker draining the queue we can watch each overflow policy kick in
+        let policies = [
+            OverflowPolicy::Reject,
+            OverflowPolicy::DropOldest,
+            OverflowPolicy::BlockTimeout(Duration::from_millis(5)),
+        ];
+        for policy in policies {
+            let (sender, _receiver) = worker_channel(QueueConfig::bounded(4, policy));
+            let mut refused = Vec::new();
+            for i in 0..10u64 {
+                if let Err(e) = sender.send(Operation::<String, u64>::Insert(format!("bp-key-{}", i), i)) {
+                    if refused.is_empty() {
+                        println!("{:?}: first refusal: {}", policy, e);
+                    }
+                    // The refused operation is handed back to us
+                    refused.push(e.into_inner());
+                }
+            }
+
+            let stats = sender.stats();
+            println!(
+                "{:?}: depth {}/{}, enqueued {}, rejected {}, timed out {}, dropped {}, max wait {:?}",
+                policy,
+                stats.depth,
+                stats.capacity.unwrap_or(0),
+                stats.enqueued,
+                stats.rejected,
+                stats.timed_out,
+                stats.dropped,
+                stats.max_wait
+            );
+        }
+
+        // A producer that sheds optional work while the worker is falling behind
+        let queue_data = Arc::new(MyData::<String, u64>::new(8, 4));
+        let (sender, receiver) = worker_channel(QueueConfig::bounded(16, OverflowPolicy::Block));
+        let worker_handle = thread::spawn(create_worker_fn(Arc::clone(&queue_data), receiver));
+
+        let mut shed = 0;
+        for i in 0..1000u64 {
+            // Odd keys are "nice to have" and get dropped under load
+            if i % 2 == 1 && sender.is_overloaded(0.75) {
+                shed += 1;
+                continue;
+            }
+            sender.send(Operation::Insert(format!("bp-key-{}", i), i)).unwrap();
+        }
+        sender.send(Operation::Shutdown).unwrap();
+        let _ = worker_handle.join();
+
+        let stats = sender.stats();
+        println!(
+            "Shed {} inserts, enqueued {}, max depth {}, total producer wait {:?}, load now {:.2}",
+            shed,
+            stats.enqueued,
+            stats.max_depth,
+            stats.total_wait,
+            sender.load()
+        );
+        println!("Worker stored {} entries", queue_data.len());
+        // The worker dropped its end when it stopped, so further sends fail fast
+        if let Err(e) = sender.send(Operation::Insert("bp-late".to_string(), 0)) {
+            println!("Send after the worker exited: {}", e);
+        }
+
+        // Unbounded queues never push back, so their load always reads as zero
+        let (unbounded_sender, _receiver) = worker_channel::<Operation<String, u64>>(QueueConfig::unbounded());
+        println!(
+            "Unbounded queue: capacity {:?}, load {:.2}, default config {:?}",
+            unbounded_sender.capacity(),
+            unbounded_sender.load(),
+            QueueConfig::default()
+        );
+    }
+
+    // Example 9: Supervised workers with graceful drain and restart
+    println!("\nExample 9: Supervised workers with graceful drain and restart");
+    {
+        let supervised_data = Arc::new(MyData::<String, u64>::new(9, 4));
+        let (sender, receiver) = worker_channel(QueueConfig::bounded(256, OverflowPolicy::Block));
+
+        let config = SupervisorConfig {
+            max_restarts: 2,
+            ..SupervisorConfig::default()
+        }
+        .on_panic(|report| {
+            println!(
+                "Supervisor caught panic #{}: '{}' (restarting: {})",
+                report.panic_count, report.message, report.will_restart
+            );
+        });
+        let mut handle = WorkerHandle::spawn(Arc::clone(&supervised_data), receiver, config);
+
+        for i in 0..100u64 {
+            sender.send(Operation::Insert(format!("sup-key-{}", i), i)).unwrap();
+        }
+
+        // A buggy user predicate used to kill the worker silently
+        let (response_sender, response_receiver) = channel::bounded(1);
+        let predicate = Arc::new(|_: &String, v: &u64| -> bool {
+            if *v == 42 {
+                panic!("predicate cannot handle 42");
+            }
+            false
+        });
+        sender.send(Operation::Find(predicate, response_sender)).unwrap();
+        match response_receiver.recv() {
+            Ok(Ok(results)) => println!("Find returned {} entries", results.len()),
+            Ok(Err(e)) => println!("Find skipped: {}", e),
+            Err(_) => println!("Find was abandoned because the worker panicked"),
+        }
+
+        // The restarted worker keeps serving requests
+        let (response_sender, response_receiver) = channel::bounded(1);
+        sender.send(Operation::Get("sup-key-7".to_string(), response_sender)).unwrap();
+        println!("After restart, sup-key-7 = {:?}", response_receiver.recv().ok().and_then(Result::ok).flatten());
+
+        // A join with a tiny timeout fails while the worker is still running
+        if let Err(e) = handle.join_timeout(Duration::from_millis(1)) {
+            println!("join_timeout: {}", e);
+        }
+
+        // Graceful shutdown processes everything that was already queued
+        for i in 100..200u64 {
+            sender.send(Operation::Insert(format!("sup-key-{}", i), i)).unwrap();
+        }
+        handle.shutdown_graceful();
+        let stats = handle.join_timeout(Duration::from_secs(5)).unwrap();
+        println!(
+            "Graceful: state {:?}, processed {}, panics {}, restarts {}, last panic {:?}, entries {}",
+            stats.state,
+            stats.processed,
+            stats.panics,
+            stats.restarts,
+            stats.last_panic,
+            supervised_data.len()
+        );
+
+        // shutdown_now leaves queued work behind
+        let (sender, receiver) = worker_channel(QueueConfig::unbounded());
+        let handle = WorkerHandle::spawn(Arc::clone(&supervised_data), receiver, SupervisorConfig::default());
+        for i in 0..50_000u64 {
+            sender.send(Operation::Insert(format!("now-key-{}", i), i)).unwrap();
+        }
+        handle.shutdown_now();
+        let mut handle = handle;
+        let stats = handle.join_timeout(Duration::from_secs(5)).unwrap();
+        println!(
+            "Immediate: state {:?}, processed {}, left in queue {}",
+            stats.state,
+            stats.processed,
+            sender.depth()
+        );
+
+        // A worker that keeps panicking is eventually given up on
+        let (sender, receiver) = worker_channel(QueueConfig::unbounded());
+        let config = SupervisorConfig {
+            max_restarts: 1,
+            restart_backoff: Duration::from_millis(1),
+            ..SupervisorConfig::default()
+        };
+        let mut handle = WorkerHandle::spawn(Arc::clone(&supervised_data), receiver, config);
+        for _ in 0..3 {
+            let (response_sender, _response_receiver) = channel::bounded(1);
+            let predicate = Arc::new(|_: &String, _: &u64| -> bool { panic!("always broken") });
+            sender.send(Operation::Find(predicate, response_sender)).unwrap();
+        }
+        let stats = handle.join_timeout(Duration::from_secs(5)).unwrap();
+        println!(
+            "Repeated panics: state {:?} (failed: {}), panics {}, restarts {}",
+            stats.state,
+            stats.state == WorkerState::Failed,
+            stats.panics,
+            stats.restarts
+        );
+    }
+
+    // Example 10: Priority lanes for worker operations
+    println!("\nExample 10: Priority lanes for worker operations");
+    {
+        let lane_data = Arc::new(MyData::<String, u64>::new(10, 8));
+        let (sender, receiver) = priority_channel(LaneConfig::default());
+
+        // Queue a large bulk load before the worker starts so the lanes are contended
+        for i in 0..20_000u64 {
+            sender.send(Priority::Bulk, Operation::Insert(format!("lane-key-{}", i), i)).unwrap();
+        }
+        for i in 0..100u64 {
+            sender.send(Priority::Normal, Operation::Insert(format!("lane-normal-{}", i), i)).unwrap();
+        }
+        let mut handle = WorkerHandle::spawn(Arc::clone(&lane_data), receiver, SupervisorConfig::default());
+
+        // Interactive reads jump ahead of the remaining bulk inserts
+        let started = Instant::now();
+        let mut found = 0;
+        for i in 0..10u64 {
+            let (response_sender, response_receiver) = channel::bounded(1);
+            sender
+                .send(Priority::Interactive, Operation::Get(format!("lane-key-{}", i), response_sender))
+                .unwrap();
+            if let Ok(Ok(Some(_))) = response_receiver.recv() {
+                found += 1;
+            }
+        }
+        println!("10 interactive gets answered in {:?} ({} found)", started.elapsed(), found);
+
+        handle.shutdown_graceful();
+        let stats = handle.join_timeout(Duration::from_secs(10)).unwrap();
+        println!("Worker processed {} operations, store has {} entries", stats.processed, lane_data.len());
+
+        for lane in sender.lane_stats() {
+            println!(
+                "  {:?}: served {}, promoted {}, depth {}, mean latency {:?}, max latency {:?}",
+                lane.priority, lane.served, lane.promoted, lane.depth, lane.mean_latency, lane.max_latency
+            );
+        }
+    }
+
+    // Example 11: Serializable queries instead of opaque predicates
+    println!("\nExample 11: Serializable queries for Find");
+    {
+        let query_data = Arc::new(MyData::<String, u64>::new(11, 8));
+        for i in 0..500u64 {
+            query_data.insert(format!("user-{}", i), i);
+            query_data.insert(format!("order-{}", i), i * 3);
+        }
+
+        let text = r#"key ~ "user-4?" AND (value >= 45 or not value != 40) order by value desc limit 3"#;
+        let query = Query::parse(text).unwrap();
+        // The canonical text form can be logged and parsed back into the same query
+        println!("Parsed:     {}", query);
+        println!("Round trip: {}", Query::parse(&query.to_string()).unwrap() == query);
+
+        // Run in parallel across segments
+        let compiled = query.compile::<String, u64>();
+        let results = compiled.run(&query_data);
+        println!("Parallel query results: {:?}", results);
+
+        // The same query as a plain predicate for MyData::find
+        let predicate = compiled.predicate();
+        let unordered = query_data.find(|k, v| predicate(k, v));
+        println!("find() with the compiled predicate matched {} entries", unordered.len());
+
+        // And through the worker protocol, where it shows up readably in logs
+        let (sender, receiver) = worker_channel(QueueConfig::default());
+        let worker_handle = thread::spawn(create_worker_fn(Arc::clone(&query_data), receiver));
+        let wire_text = r#"key ~ "order-*" and value > 1490"#;
+        let compiled = Query::parse(wire_text).unwrap().compile::<String, u64>();
+        println!("Sending query to worker: {}", compiled.query());
+        let (response_sender, response_receiver) = channel::bounded(1);
+        sender.send(Operation::Query(compiled, response_sender)).unwrap();
+        println!("Worker query results: {:?}", response_receiver.recv().ok().and_then(Result::ok).unwrap_or_default());
+        sender.send(Operation::Shutdown).unwrap();
+        let _ = worker_handle.join();
+
+        // An empty filter matches everything
+        let everything = Query::parse("limit 2").unwrap().compile::<String, u64>().run(&query_data);
+        println!("'limit 2' returned {} entries", everything.len());
+
+        // Malformed queries report where they went wrong
+        for bad in ["value >", "key ~ user-*", "value = 1.5.2", "(key = \"a\""] {
+            match Query::parse(bad) {
+                Ok(q) => println!("Unexpectedly parsed '{}' as {}", bad, q),
+                Err(e) => println!("Parse error for '{}': {}", bad, e),
+            }
+        }
+    }
+
+    // Example 12: Deadlines and cancellation for queued operations
+    println!("\nExample 12: Deadlines and cancellation");
+    {
+        let deadline_data = Arc::new(MyData::<String, u64>::new(12, 8));
+        for i in 0..200_000u64 {
+            deadline_data.insert(format!("dl-key-{}", i), i);
+        }
+
+        let (sender, receiver) = worker_channel(QueueConfig::unbounded());
+        let worker_handle = thread::spawn(create_worker_fn(Arc::clone(&deadline_data), receiver));
+
+        // A slow scan keeps the worker busy...
+        let (slow_sender, slow_receiver) = channel::bounded(1);
+        let slow_predicate = Arc::new(|_: &String, v: &u64| {
+            if v.is_multiple_of(10_000) {
+                thread::sleep(Duration::from_millis(2));
+            }
+            false
+        });
+        let scan_token = CancellationToken::new();
+        let scan_ctx = OpContext::new().with_token(scan_token.clone());
+        sender
+            .send(Operation::Find(slow_predicate, slow_sender).with_context(scan_ctx))
+            .unwrap();
+
+        // ...so a Get with a short deadline expires while it waits in the queue
+        let (get_sender, get_receiver) = channel::bounded(1);
+        let get_ctx = OpContext::new().with_timeout(Duration::from_millis(1));
+        println!("Get deadline set: {}", get_ctx.deadline().is_some());
+        sender
+            .send(Operation::Get("dl-key-1".to_string(), get_sender).with_context(get_ctx))
+            .unwrap();
+
+        // A request the caller gives up on before the worker reaches it
+        let abandoned_token = CancellationToken::new();
+        let (abandoned_sender, abandoned_receiver) = channel::bounded(1);
+        sender
+            .send(Operation::Get("dl-key-2".to_string(), abandoned_sender)
+                .with_context(OpContext::new().with_token(abandoned_token.clone())))
+            .unwrap();
+        abandoned_token.cancel();
+
+        // Cancel the long scan part way through
+        thread::sleep(Duration::from_millis(5));
+        scan_token.cancel();
+
+        match slow_receiver.recv().unwrap() {
+            Ok(results) => println!("Scan finished with {} results", results.len()),
+            Err(OpError::Cancelled) => println!("Scan was cancelled part way through"),
+            Err(e) => println!("Scan failed: {}", e),
+        }
+        match get_receiver.recv().unwrap() {
+            Err(OpError::TimedOut) => println!("Get timed out in the queue and was skipped"),
+            other => println!("Get returned {:?}", other),
+        }
+        println!("Abandoned get: {:?}", abandoned_receiver.recv().unwrap());
+
+        // The same checks are available without a worker
+        let expired = OpContext::new().with_deadline(Instant::now());
+        let direct = deadline_data.find_cancellable(|_, v| *v < 10, &expired);
+        println!("Direct find with an expired deadline: {:?}", direct.map(|r| r.len()));
+        let mut touched = 0;
+        let walked = deadline_data.for_each_cancellable(|_, _| touched += 1, &OpContext::new());
+        println!("for_each_cancellable visited {} entries: {:?}", touched, walked);
+
+        sender.send(Operation::Shutdown).unwrap();
+        let _ = worker_handle.join();
+    }
+
+    // Example 13: Record and replay of operation streams
+    println!("\nExample 13: Record and replay of operation streams");
+    {
+        let recording_path = std::env::temp_dir().join("mt_with_cb_rayon_dm_ops.rec");
+        let recorder = Arc::new(OperationRecorder::create(&recording_path).unwrap());
+
+        // Record everything a supervised worker receives from two producers
+        let recorded_data = Arc::new(MyData::<String, u64>::new(13, 8));
+        let (sender, receiver) = worker_channel(QueueConfig::default());
+        let source = RecordingSource::new(receiver, Arc::clone(&recorder));
+        let mut handle = WorkerHandle::spawn(Arc::clone(&recorded_data), source, SupervisorConfig::default());
+
+        let producers: Vec<_> = (0..2u64)
+            .map(|p| {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    for i in 0..500u64 {
+                        let key = format!("rec-{}-{}", p, i % 200);
+                        sender.send(Operation::Insert(key.clone(), i)).unwrap();
+                        if i % 7 == 0 {
+                            sender.send(Operation::Remove(key)).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let (response_sender, _response_receiver) = channel::bounded(1);
+        let query = Query::parse("value > 490").unwrap().compile::<String, u64>();
+        sender.send(Operation::Query(query, response_sender)).unwrap();
+
+        handle.shutdown_graceful();
+        handle.join_timeout(Duration::from_secs(5)).unwrap();
+        recorder.checkpoint(&recorded_data).unwrap();
+        recorder.flush().unwrap();
+        println!(
+            "Recorded {} records ({} lost to write errors), original state hash {:016x}",
+            recorder.records(),
+            recorder.failed_records(),
+            recorded_data.state_hash()
+        );
+
+        let replayer = Replayer::<String, u64>::open(&recording_path).unwrap();
+        let threads: std::collections::HashSet<u64> = replayer.records().iter().map(|r| r.thread).collect();
+        let last = replayer.records().last().unwrap();
+        println!(
+            "Loaded {} records from {} receiving thread(s), last at {:?}: {:?}",
+            replayer.records().len(),
+            threads.len(),
+            last.at,
+            last.op
+        );
+
+        // Replay directly into a fresh store and verify the checkpoint
+        let fresh = MyData::<String, u64>::new(14, 4);
+        let report = replayer.replay_into(&fresh);
+        println!(
+            "Direct replay: applied {}, skipped {}, checkpoints {}, verified {}, hash {:016x} in {:?}",
+            report.applied,
+            report.skipped,
+            report.checkpoints_verified,
+            report.verified(),
+            report.final_hash,
+            report.elapsed
+        );
+
+        // A speed factor that would stall or overflow is refused up front
+        if let Err(e) = replayer.clone().with_speed(ReplaySpeed::Accelerated(0.0)) {
+            println!("Rejected replay speed: {}", e);
+        }
+
+        // Replay through a fresh worker at ten times the original speed
+        let worker_data = Arc::new(MyData::<String, u64>::new(15, 8));
+        let (sender, receiver) = worker_channel(QueueConfig::default());
+        let worker_handle = thread::spawn(create_worker_fn(Arc::clone(&worker_data), receiver));
+        let fast = replayer.clone().with_speed(ReplaySpeed::Accelerated(10.0)).unwrap();
+        let mut report = fast.replay_to_worker(&sender);
+        // Fails if the recording already shut the worker down
+        let _ = sender.send(Operation::Shutdown);
+        let _ = worker_handle.join();
+        replayer.verify_final(&worker_data, &mut report);
+        println!(
+            "Worker replay: applied {} in {:?}, verified {}",
+            report.applied, report.elapsed, report.verified()
+        );
+
+        // Tampering with the replayed state is caught by verification
+        let tampered = MyData::<String, u64>::new(16, 4);
+        tampered.insert("extra".to_string(), 1);
+        let report = replayer.clone().with_speed(ReplaySpeed::Original).unwrap().replay_into(&tampered);
+        for (idx, expected, actual) in &report.mismatches {
+            println!("Mismatch at record {}: expected {:016x}, got {:016x}", idx, expected, actual);
+        }
+
+        let scans = replayer
+            .records()
+            .iter()
+            .filter(|r| matches!(r.op, RecordedOp::Find | RecordedOp::Query(_)))
+            .count();
+        println!("Recording contains {} scans", scans);
+        let _ = std::fs::remove_file(&recording_path);
+    }
+
+    // Example 14: Segment-level map-reduce
+    println!("\nExample 14: Segment-level map-reduce");
+    {
+        let mr_data = Arc::new(MyData::<String, u64>::new(17, 16));
+        for i in 0..10_000u64 {
+            mr_data.insert(format!("mr-key-{}", i), i);
+        }
+
+        // Ordered combination: segment ids come back in order with either combiner
+        let tree = map_reduce_with(&mr_data, |idx, _| vec![idx], |mut a, b| { a.extend(b); a }, Combiner::Tree);
+        let sequential = map_reduce_with(&mr_data, |idx, _| vec![idx], |mut a, b| { a.extend(b); a }, Combiner::Sequential);
+        println!("Tree order: {:?}", tree);
+        println!("Same order sequentially: {}", tree == sequential);
+
+        // Partial aggregation: (count, sum, max) per segment, merged into one
+        let (count, sum, max) = map_reduce(
+            &mr_data,
+            |_, segment| {
+                segment.iter().fold((0usize, 0u64, 0u64), |(c, s, m), entry| {
+                    (c + 1, s + *entry.value(), m.max(*entry.value()))
+                })
+            },
+            |a, b| (a.0 + b.0, a.1 + b.1, a.2.max(b.2)),
+        )
+        .unwrap_or_default();
+        println!("Count {}, sum {}, max {}, mean {:.1}", count, sum, max, sum as f64 / count as f64);
+
+        // Early termination: stop looking once a segment has found a match
+        let found = map_reduce_until(
+            &mr_data,
+            |_, segment| segment.iter().filter(|e| *e.value() % 997 == 0).map(|e| e.key().clone()).collect::<Vec<_>>(),
+            |mut a, b| { a.extend(b); a },
+            |partial| !partial.is_empty(),
+        )
+        .unwrap_or_default();
+        println!("Early-terminated search found {} of the 11 multiples of 997 before stopping", found.len());
+
+        // Several stores at once, tagged by store index
+        let stores: Vec<Arc<MyData<String, u64>>> = (0..3u64)
+            .map(|s| {
+                let store = Arc::new(MyData::<String, u64>::new(18 + s as usize, 4));
+                for i in 0..(10 * (s + 1)) {
+                    store.insert(format!("store-{}-{}", s, i), i);
+                }
+                store
+            })
+            .collect();
+        let sizes = map_reduce_many(
+            &stores,
+            |ds_idx, _, segment| vec![(ds_idx, segment.len())],
+            |mut a, b| { a.extend(b); a },
+        )
+        .unwrap_or_default();
+        let mut per_store = [0usize; 3];
+        for (ds_idx, len) in sizes {
+            per_store[ds_idx] += len;
+        }
+        println!("Entries per store via map_reduce_many: {:?}", per_store);
+    }
+
+    // Example 15: Hash joins across MyData instances
+    println!("\nExample 15: Hash joins across MyData instances");
+    {
+        let customers = MyData::<u64, String>::new(20, 8);
+        for id in 0..1000u64 {
+            customers.insert(id, format!("customer-{}", id));
+        }
+
+        // Same hasher and segment count, so joins can probe segment by segment
+        let balances = MyData::<u64, u64>::new_with_layout(21, &customers);
+        for id in (0..1000u64).step_by(3) {
+            balances.insert(id, id * 10);
+        }
+        // Independent layout, so every probe hashes the key again
+        let flagged = MyData::<u64, bool>::new(22, 5);
+        for id in (0..1000u64).step_by(10) {
+            flagged.insert(id, true);
+        }
+        println!(
+            "Layouts aligned: balances {}, flagged {}",
+            customers.shares_layout_with(&balances),
+            customers.shares_layout_with(&flagged)
+        );
+
+        let with_balance = inner_join(&customers, &balances, 23, |_, name, balance| format!("{} owes {}", name, balance));
+        let everyone = left_join(&customers, &flagged, 24, |_, name, flag| (name.clone(), flag.copied().unwrap_or(false)));
+        let unflagged = anti_join(&customers, &flagged, 25);
+        println!(
+            "Inner join: {} rows, left join: {} rows ({} flagged), anti join: {} rows",
+            with_balance.len(),
+            everyone.len(),
+            everyone.find(|_, (_, flag)| *flag).len(),
+            unflagged.len()
+        );
+        if let Some(row) = with_balance.get(&42) {
+            println!("  customer 42: {}", *row);
+        }
+
+        // Lazy stream: consume only what is needed
+        let first_three: Vec<(u64, String, bool)> = join_stream(&customers, &flagged).take(3).collect();
+        println!("First streamed rows: {:?}", first_three.len());
+
+        // Join on a derived key: orders reference customers through their value
+        let orders = MyData::<String, u64>::new(26, 4);
+        for order in 0..50u64 {
+            orders.insert(format!("order-{}", order), order % 7);
+        }
+        let mut order_names: Vec<(String, String)> = join_by(
+            &orders,
+            &customers,
+            |_, customer_id| *customer_id,
+            |id, _| *id,
+        )
+        .map(|(_, (order, _), (_, name))| (order, name))
+        .collect();
+        order_names.sort();
+        println!("Derived-key join produced {} rows, e.g. {:?}", order_names.len(), order_names.first());
+    }
+
+    // Example 16: Tiered storage with a disk tier for cold entries
+    println!("\nExample 16: Tiered storage with a disk tier for cold entries");
+    {
+        let config = TierConfig {
+            directory: std::env::temp_dir().join(format!("mydata-tiers-{}", std::process::id())),
+            memory_budget: 200,
+            cold_after: Duration::from_millis(150),
+            spill_interval: Duration::from_millis(50),
+        };
+        let tiered = TieredData::<String, u64>::new(27, 4, config.clone()).expect("create tier files");
+
+        for i in 0..1000u64 {
+            tiered.insert(format!("item-{}", i), i * i);
+        }
+        // Keep a small working set hot while the background thread spills the rest
+        let deadline = Instant::now() + Duration::from_millis(300);
+        while Instant::now() < deadline {
+            for i in 0..20u64 {
+                tiered.get(&format!("item-{}", i)).expect("read tier");
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = tiered.stats();
+        println!(
+            "After background spilling: {} hot, {} cold, {} spilled",
+            stats.hot_entries, stats.cold_entries, stats.spilled
+        );
+
+        // Cold entries are faulted back in transparently
+        let faulted = (500..600u64)
+            .into_par_iter()
+            .filter(|i| tiered.get(&format!("item-{}", i)).expect("read tier") == Some(i * i))
+            .count();
+        println!("Faulted {} cold entries back with the right values", faulted);
+        println!(
+            "Removed item-7: {:?}, missing key: {:?}",
+            tiered.remove(&"item-7".to_string()).expect("remove from tier"),
+            tiered.get(&"nope".to_string()).expect("read tier")
+        );
+
+        tiered.stop_spilling();
+        println!("Synchronous spill moved {} more entries", tiered.spill());
+        let stats = tiered.stats();
+        println!(
+            "Tier stats: {} entries total (empty: {}), hot hit rate {:.1}%, cold hit rate {:.1}%, {} misses, {} spill errors",
+            tiered.len(),
+            tiered.is_empty(),
+            stats.hot_hit_rate() * 100.0,
+            stats.cold_hit_rate() * 100.0,
+            stats.misses,
+            stats.spill_errors
+        );
+        println!("Cold files: {} bytes on disk, {} compactions", stats.cold_bytes, stats.compactions);
+
+        drop(tiered);
+        let _ = std::fs::remove_dir(&config.directory);
+    }
+
+    // Example 17: Read-through cache with write-through and write-behind
+    println!("\nExample 17: Read-through cache with write-through and write-behind");
+    {
+        let directory = std::env::temp_dir().join(format!("mydata-backing-{}", std::process::id()));
+        let backing = FileBackingStore::<String, u64>::new(&directory)
+            .expect("create backing directory")
+            .with_latency(Duration::from_millis(20));
+        for i in 0..10u64 {
+            backing.store(&format!("user-{}", i), &(i * 100)).expect("seed backing store");
+        }
+
+        let through = CachedData::new(28, 4, backing, WriteMode::WriteThrough);
+        // Eight concurrent misses on one key share a single slow load
+        let values: Vec<Option<u64>> = thread::scope(|s| {
+            let readers: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| through.get(&"user-3".to_string()).expect("load user-3")))
+                .collect();
+            readers.into_iter().map(|r| r.join().unwrap()).collect()
+        });
+        let stats = through.stats();
+        println!(
+            "Concurrent misses returned {:?}: {} backing loads ({} at the file store), {} coalesced",
+            values[0], stats.loads, through.backing().load_count(), stats.coalesced
+        );
+        through.get(&"user-3".to_string()).expect("cached");
+        through.insert("user-42".to_string(), 4200).expect("write through");
+        println!(
+            "After a hit and a write: {} hits, {} written, backing saw {} stores",
+            through.stats().hits,
+            through.stats().written,
+            through.backing().store_count()
+        );
+        drop(through);
+
+        let behind = CachedData::new(
+            29,
+            4,
+            FileBackingStore::<String, u64>::new(&directory).expect("open backing directory"),
+            WriteMode::WriteBehind { flush_interval: Duration::from_millis(200), max_pending: 1000 },
+        );
+        for i in 0..50u64 {
+            behind.insert(format!("event-{}", i), i).expect("queue write");
+        }
+        behind.remove(&"user-0".to_string()).expect("queue delete");
+        println!("Write-behind queued {} writes, backing saw {} so far", behind.stats().pending, behind.backing().store_count());
+        behind.flush().expect("flush");
+
+        // Evicted keys are read back from the backing store
+        behind.evict(&"event-7".to_string());
+        println!(
+            "After flush: {} pending, {} written; event-7 reloaded as {:?}, user-0 now {:?}, flush errors: {}",
+            behind.stats().pending,
+            behind.stats().written,
+            behind.get(&"event-7".to_string()).expect("reload"),
+            behind.get(&"user-0".to_string()).expect("load deleted"),
+            behind.stats().flush_errors
+        );
+        println!("Cache holds {} of the keys, {} misses so far", behind.stats().cached, behind.stats().misses);
+
+        drop(behind);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    // Example 18: Version history and time-travel reads
+    println!("\nExample 18: Version history and time-travel reads");
+    {
+        let config = HistoryConfig::within(Duration::from_millis(200))
+            .and_last(5)
+            .with_gc_interval(Duration::from_millis(50));
+        let accounts = VersionedData::<String, u64>::new(30, 4, config);
+
+        let opened = accounts.insert("alice".to_string(), 100);
+        let before_updates = std::time::SystemTime::now();
+        thread::sleep(Duration::from_millis(5));
+        (1..=8u64).into_par_iter().for_each(|i| {
+            accounts.insert(format!("acct-{}", i), i);
+        });
+        for balance in [150, 90, 240] {
+            accounts.insert("alice".to_string(), balance);
+        }
+        println!(
+            "alice now {:?}, at version {} {:?}, before the updates {:?}",
+            accounts.get(&"alice".to_string()),
+            opened,
+            accounts.get_at(&"alice".to_string(), At::Version(opened)),
+            accounts.get_at(&"alice".to_string(), At::Time(before_updates))
+        );
+
+        println!("Removed acct-3: {:?}", accounts.remove(&"acct-3".to_string()));
+        let history: Vec<(u64, Option<u64>)> = accounts
+            .history(&"acct-3".to_string())
+            .into_iter()
+            .map(|v| (v.version, v.value))
+            .collect();
+        println!("acct-3 history: {:?}", history);
+        println!(
+            "{} live keys, {} versions retained at version {}",
+            accounts.len(),
+            accounts.retained_versions(),
+            accounts.current_version()
+        );
+
+        // Wait for the background collector to trim history past the window
+        thread::sleep(Duration::from_millis(350));
+        accounts.stop_gc();
+        println!(
+            "After GC: {} versions retained, {} pruned (manual pass pruned {} more), alice history length {}",
+            accounts.retained_versions(),
+            accounts.pruned_versions(),
+            accounts.gc(),
+            accounts.history(&"alice".to_string()).len()
+        );
+
+        // Count-based retention only
+        let audit = VersionedData::<u64, String>::new(31, 2, HistoryConfig::last(2));
+        for state in ["draft", "review", "approved"] {
+            audit.insert(7, state.to_string());
+        }
+        audit.gc();
+        println!("Audit trail keeps last 2: {:?}", audit.history(&7).into_iter().map(|v| v.value).collect::<Vec<_>>());
+    }
+
+    // Example 19: Software transactional memory
+    println!("\nExample 19: Software transactional memory");
+    {
+        let bank = Arc::new(StmData::<String, i64>::new(32, 4, HistoryConfig::last(4)));
+        bank.atomically(|tx| {
+            for i in 0..10 {
+                tx.write(format!("acct-{}", i), 1000);
+            }
+            Ok(())
+        });
+
+        // Concurrent transfers conflict on shared accounts and are retried
+        (0..400u64).into_par_iter().for_each(|i| {
+            let from = format!("acct-{}", i % 10);
+            let to = format!("acct-{}", (i * 7 + 3) % 10);
+            bank.atomically(|tx| {
+                let a = tx.read(&from)?.unwrap_or(0);
+                let b = tx.read(&to)?.unwrap_or(0);
+                if from != to {
+                    tx.write(from.clone(), a - 5);
+                    tx.write(to.clone(), b + 5);
+                }
+                Ok(())
+            });
+        });
+        let total: i64 = bank.atomically(|tx| {
+            let mut total = 0;
+            for i in 0..10 {
+                total += tx.read(&format!("acct-{}", i))?.unwrap_or(0);
+            }
+            Ok(total)
+        });
+        let stats = bank.stats();
+        println!(
+            "Total after 400 transfers: {} ({} commits, {} conflicts)",
+            total, stats.commits, stats.conflicts
+        );
+
+        // `or_else`: pay from checking, falling back to savings when it is short
+        bank.atomically(|tx| {
+            tx.write("checking".to_string(), 20);
+            tx.write("savings".to_string(), 500);
+            Ok(())
+        });
+        let paid_from = bank.atomically(|tx| {
+            tx.or_else(
+                |tx| {
+                    let balance = tx.read(&"checking".to_string())?.unwrap_or(0);
+                    if balance < 100 {
+                        return tx.retry();
+                    }
+                    tx.write("checking".to_string(), balance - 100);
+                    Ok("checking")
+                },
+                |tx| {
+                    let balance = tx.read(&"savings".to_string())?.unwrap_or(0);
+                    tx.write("savings".to_string(), balance - 100);
+                    Ok("savings")
+                },
+            )
+        });
+        println!(
+            "Paid 100 from {}: checking {:?}, savings {:?}",
+            paid_from,
+            bank.get(&"checking".to_string()),
+            bank.get(&"savings".to_string())
+        );
+
+        // `retry`: block until a producer fills the slot
+        let consumer = {
+            let bank = Arc::clone(&bank);
+            thread::spawn(move || {
+                bank.atomically(|tx| match tx.read(&"mailbox".to_string())? {
+                    Some(message) => {
+                        tx.remove(&"mailbox".to_string());
+                        Ok(message)
+                    }
+                    None => tx.retry(),
+                })
+            })
+        };
+        thread::sleep(Duration::from_millis(20));
+        bank.atomically(|tx| {
+            tx.write("mailbox".to_string(), 42);
+            Ok(())
+        });
+        println!(
+            "Consumer woke up with {}, mailbox now {:?}, {} retries, mailbox history length {}",
+            consumer.join().unwrap(),
+            bank.get(&"mailbox".to_string()),
+            bank.stats().retries,
+            bank.versions().history(&"mailbox".to_string()).len()
+        );
+    }
+
+    // Example 20: Atomic bulk replace and swap
+    println!("\nExample 20: Atomic bulk replace and swap");
+    {
+        let live = Arc::new(MyData::<u64, u64>::new(33, 8));
+        live.replace_all((0..10_000u64).into_par_iter().map(|k| (k, 1)));
+
+        // Readers check that every scan sees one complete generation
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let live = Arc::clone(&live);
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    let mut scans = 0;
+                    let mut torn = 0;
+                    while !stop.load(std::sync::atomic::Ordering::Relaxed) {
+                        let rows = live.find(|_, _| true);
+                        let generation = rows.first().map(|(_, g)| *g).unwrap_or(0);
+                        let expected = if generation % 2 == 1 { 10_000 } else { 20_000 };
+                        if rows.len() != expected || rows.iter().any(|(_, g)| *g != generation) {
+                            torn += 1;
+                        }
+                        scans += 1;
+                    }
+                    (scans, torn)
+                })
+            })
+            .collect();
+
+        for generation in 2..=9u64 {
+            let size = if generation % 2 == 1 { 10_000 } else { 20_000 };
+            live.replace_all((0..size).into_par_iter().map(move |k| (k, generation)));
+            thread::sleep(Duration::from_millis(5));
+        }
+        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        let (scans, torn) = readers
+            .into_iter()
+            .map(|r| r.join().unwrap())
+            .fold((0, 0), |acc, r| (acc.0 + r.0, acc.1 + r.1));
+        println!("{} scans during 8 replacements, {} saw a partial dataset", scans, torn);
+
+        let standby = MyData::<u64, u64>::new(34, 4);
+        standby.replace_all(vec![(1, 100), (2, 200)]);
+        live.swap_with(&standby);
+        println!(
+            "After swap_with: live has {} entries (key 1 = {:?}), standby has {}",
+            live.len(),
+            live.get(&1).map(|v| *v),
+            standby.len()
+        );
+    }
+
+    // Example 21: Reentrancy detection in closures
+    println!("\nExample 21: Reentrancy detection in closures");
+    {
+        let store = Arc::new(MyData::<u64, u64>::new(35, 2));
+        store.replace_all((0..8u64).into_par_iter().map(|k| (k, k)));
+        let describe = |result: Result<(), ReentrantAccess>| match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("rejected ({})", e),
+        };
+
+        // for_each: touching the shard being visited is rejected, other shards are fine
+        let outcomes = Arc::new(std::sync::Mutex::new((0, 0)));
+        let inner = Arc::clone(&store);
+        let counts = Arc::clone(&outcomes);
+        store.for_each(move |key, value| {
+            let mut counts = counts.lock().unwrap();
+            match inner.try_get(&((key + 1) % 8)) {
+                Ok(_) => counts.0 += 1,
+                Err(_) => counts.1 += 1,
+            }
+            *value += 1;
+        });
+        let (allowed, rejected) = *outcomes.lock().unwrap();
+        println!("for_each: {} lookups allowed, {} rejected", allowed, rejected);
+
+        // transaction: writing back through the store would deadlock on the entry
+        let mut reentrant = None;
+        store.transaction(&3, |key, value| {
+            reentrant = Some(store.try_insert(*key, *value * 2).map(|_| ()));
+            *value *= 2;
+        });
+        println!("transaction: try_insert {}", describe(reentrant.unwrap()));
+
+        // find and find_cancellable: removing or scanning from the predicate
+        let removal = std::sync::Mutex::new(None);
+        let found = store.find(|key, _| {
+            if *key == 5 {
+                *removal.lock().unwrap() = Some(store.try_remove(key).map(|_| ()));
+            }
+            *key < 3
+        });
+        println!("find: {} matches, try_remove {}", found.len(), describe(removal.into_inner().unwrap().unwrap()));
+        let ctx = OpContext::new();
+        let lookup = std::sync::Mutex::new(None);
+        let _ = store.find_cancellable(|key, _| {
+            lookup.lock().unwrap().get_or_insert_with(|| store.try_get(key).map(|_| ()));
+            false
+        }, &ctx);
+        println!("find_cancellable: try_get {}", describe(lookup.into_inner().unwrap().unwrap()));
+
+        // for_each_cancellable: any whole-store call from inside is rejected too
+        let mut visited = 0;
+        store
+            .for_each_cancellable(|key, _| {
+                visited += 1;
+                if *key == 0 {
+                    let blocked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| store.len()));
+                    if let Err(payload) = blocked {
+                        println!("for_each_cancellable: len() panicked: {}", panic_message(payload.as_ref()));
+                    }
+                }
+            }, &ctx)
+            .unwrap();
+
+        // Plain APIs panic with a clear message instead of hanging
+        let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            store.transaction(&1, |key, _| store.get(key).map(|v| *v))
+        }));
+        if let Err(payload) = outcome {
+            println!("transaction: get() panicked: {}", panic_message(payload.as_ref()));
+        }
+        println!("Store still usable after {} visits: {} entries", visited, store.len());
+    }
+
+    // Example 22: Sampling and approximate aggregates
+    println!("\nExample 22: Sampling and approximate aggregates");
+    {
+        let latencies = MyData::<u64, u64>::new(36, 16);
+        // Skewed values: mostly fast requests with a long tail
+        latencies.replace_all((0..200_000u64).into_par_iter().map(|id| {
+            let spread = (id.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 40) % 1000;
+            (id, if spread < 950 { 10 + spread / 10 } else { 100 + spread * 5 })
+        }));
+
+        let picked = sample(&latencies, 5);
+        println!("Random sample of {}: {:?}", picked.len(), picked.iter().map(|(_, v)| v).collect::<Vec<_>>());
+
+        let started = Instant::now();
+        let slow = count_where(&latencies, |_, v| *v > 1000, 5_000);
+        let approx_time = started.elapsed();
+        let started = Instant::now();
+        let exact = latencies.find(|_, v| *v > 1000).len();
+        println!(
+            "Requests over 1000: ~{} in {:?} (exact {} in {:?}, margin {:.0})",
+            slow,
+            approx_time,
+            exact,
+            started.elapsed(),
+            slow.margin()
+        );
+
+        let distinct = distinct_count(&latencies, |_, v| *v, 12);
+        println!("Distinct latency values: ~{} (exact {})", distinct, {
+            let mut values: Vec<u64> = latencies.find(|_, _| true).into_iter().map(|(_, v)| v).collect();
+            values.sort_unstable();
+            values.dedup();
+            values.len()
+        });
+
+        let estimates = quantiles(&latencies, |_, v| *v as f64, &[0.5, 0.95, 0.99], 10_000);
+        for (q, estimate) in [0.5, 0.95, 0.99].iter().zip(&estimates) {
+            println!("  p{:.0}: {}{}", q * 100.0, estimate, if estimate.is_exact() { " exact" } else { "" });
+        }
+        let small = MyData::<u64, u64>::new(37, 2);
+        small.replace_all((0..100u64).into_par_iter().map(|k| (k, k)));
+        println!("On a small store the answer is exact: {}", count_where(&small, |_, v| *v < 10, 1000));
+    }
+
+    // Example 23: Group-by aggregation
+    println!("\nExample 23: Group-by aggregation");
+    {
+        let orders = MyData::<u64, (String, f64)>::new(38, 8);
+        let regions = ["north", "south", "east", "west"];
+        orders.replace_all((0..20_000u64).into_par_iter().map(|id| {
+            let region = regions[(id % 4) as usize].to_string();
+            (id, (region, 5.0 + (id % 97) as f64))
+        }));
+
+        let by_region = orders.group_by(|_, (region, _)| region.clone()).aggregate(|_, (_, amount)| *amount);
+        let mut names: Vec<&String> = by_region.keys().collect();
+        names.sort();
+        for name in names {
+            let stats = &by_region[name];
+            println!(
+                "  {}: {} orders, total {:.0}, min {:.0}, max {:.0}, avg {:.2}",
+                name,
+                stats.count,
+                stats.sum,
+                stats.min,
+                stats.max,
+                stats.avg().unwrap_or(0.0)
+            );
+        }
+
+        let large = orders.group_by(|_, (_, amount)| *amount >= 50.0).count();
+        println!("Orders >= 50: {:?}, smaller: {:?}", large.get(&true), large.get(&false));
+
+        // Custom aggregate: the highest order id per region
+        let latest = orders
+            .group_by(|_, (region, _)| region.clone())
+            .fold(|| 0u64, |max, id, _| *max = (*max).max(*id), |max, other| *max = (*max).max(other));
+        println!("Latest order in the north: {:?}", latest.get("north"));
+    }
+
+    // Example 24: Merge operators
+    println!("\nExample 24: Merge operators");
+    {
+        // Eager add: concurrent increments without a read-modify-write loop
+        let hits = MergeData::new(39, 8, Add, MergeMode::Eager);
+        (0..40_000u64).into_par_iter().for_each(|i| hits.merge(format!("page-{}", i % 4), 1u64));
+        println!("Hits on page-0: {:?} (expected 10000)", hits.get(&"page-0".to_string()));
+
+        // Lazy set union: operands queue up until the key is read
+        let tags = MergeData::new(40, 4, SetUnion, MergeMode::Lazy { max_pending: 64 });
+        for (user, tag) in [(1, "rust"), (1, "go"), (2, "rust"), (1, "rust"), (2, "zig")] {
+            tags.merge(user, std::collections::HashSet::from([tag.to_string()]));
+        }
+        println!("Pending before read: {}", tags.stats().pending);
+        let mut user_1: Vec<String> = tags.get(&1).unwrap_or_default().into_iter().collect();
+        user_1.sort();
+        println!("Tags of user 1: {:?}, pending after read: {}", user_1, tags.stats().pending);
+
+        // Lazy list append: a long queue is folded once it reaches max_pending
+        let log = MergeData::new(41, 4, ListAppend, MergeMode::Lazy { max_pending: 10 });
+        log.put("events", vec!["created".to_string()]);
+        for i in 0..25 {
+            log.merge("events", vec![format!("event-{}", i)]);
+        }
+        let stats = log.stats();
+        println!(
+            "Event log: {} merges, {} applied early, {} still pending",
+            stats.merges, stats.deferred_applied, stats.pending
+        );
+        let events = log.remove(&"events").unwrap_or_default();
+        println!("Removed {} events, first {:?}, last {:?}, keys left: {}", events.len(), events.first(), events.last(), log.len());
+
+        // Custom operator from a closure: keep the running maximum
+        let peaks = MergeData::new(42, 4, |old: Option<u32>, new: u32| old.map_or(new, |old| old.max(new)), MergeMode::Eager);
+        for reading in [12, 40, 7, 33] {
+            peaks.merge("sensor", reading);
+        }
+        println!("Peak reading: {:?}", peaks.get(&"sensor"));
+    }
+
+    // Example 25: Striped counters versus a single shared atomic
+    println!("\nExample 25: Striped counters versus a single shared atomic");
+    {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 1_000_000;
+
+        let shared = AtomicUsize::new(0);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..PER_THREAD {
+                        shared.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        let shared_time = start.elapsed();
+
+        let striped = StripedCounter::new();
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..PER_THREAD {
+                        striped.increment();
+                    }
+                });
+            }
+        });
+        let striped_time = start.elapsed();
+
+        println!(
+            "Single AtomicUsize: {} in {:?}; StripedCounter ({} cells): {} in {:?}",
+            shared.load(Ordering::Relaxed),
+            shared_time,
+            striped.stripes(),
+            striped.sum(),
+            striped_time
+        );
+        println!(
+            "Speedup: {:.1}x",
+            shared_time.as_secs_f64() / striped_time.as_secs_f64().max(f64::EPSILON)
+        );
+
+        // op_count stays exact under parallel inserts from rayon
+        let counted = MyData::<usize, usize>::new(43, 16);
+        let start = Instant::now();
+        (0..200_000).into_par_iter().for_each(|i| {
+            counted.insert(i, i);
+        });
+        println!(
+            "Parallel inserts: {} entries, op_count {} (expected 200000), {:?}",
+            counted.len(),
+            counted.op_count(),
+            start.elapsed()
+        );
+
+        let lumpy = StripedCounter::with_stripes(3);
+        lumpy.add(5);
+        println!("with_stripes(3) rounds up to {} cells, sum {}", lumpy.stripes(), lumpy.sum());
+    }
+
+    // Example 26: Leases with fencing tokens
+    println!("\nExample 26: Leases with fencing tokens");
+    {
+        let jobs = Arc::new(MyData::<String, String>::new(44, 4));
+        let key = "job-1".to_string();
+
+        // Four workers race for the same job; exactly one wins
+        let winners: Vec<_> = (0..4)
+            .map(|worker| {
+                let jobs = Arc::clone(&jobs);
+                let key = key.clone();
+                thread::spawn(move || jobs.lease(key, Duration::from_millis(50)).map(|t| (worker, t)))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .filter_map(|h| h.join().unwrap())
+            .collect();
+        let (worker, first) = winners.into_iter().next().expect("one worker wins the lease");
+        println!("Worker {} holds job-1 with fencing token {}", worker, first.fence());
+
+        let claimed = jobs.insert_leased(&first, format!("claimed by worker {}", worker));
+        println!("Write with the live lease: {:?}", claimed.map(|_| "ok"));
+
+        // The holder stalls past its ttl and another worker takes over
+        thread::sleep(Duration::from_millis(60));
+        let second = jobs.lease(key.clone(), Duration::from_millis(200)).expect("lapsed lease is free");
+        println!("New fencing token after expiry: {} (> {})", second.fence(), first.fence());
+
+        match jobs.insert_leased(&first, "late write".to_string()) {
+            Err(LeaseError::Superseded { current }) => println!("Stale holder's write rejected, current token {}", current),
+            other => println!("Unexpected: {:?}", other),
+        }
+
+        let renewed = jobs.renew(&second, Duration::from_secs(1)).expect("second lease is live");
+        println!(
+            "Renewed token {} with {}ms left, active leases: {}",
+            renewed.fence(),
+            renewed.remaining().as_millis(),
+            jobs.active_leases()
+        );
+        jobs.insert_leased(&renewed, "finished".to_string()).expect("lease is live");
+        println!("Value now: {:?}", jobs.get(&key).map(|v| v.value().clone()));
+
+        jobs.release(&renewed).expect("release held lease");
+        println!(
+            "After release: remove_leased -> {:?}, renew -> {:?}, active leases: {}",
+            jobs.remove_leased(&renewed),
+            jobs.renew(&renewed, Duration::from_secs(1)).err(),
+            jobs.active_leases()
+        );
+
+        let short = jobs.lease("job-2".to_string(), Duration::from_millis(5)).expect("job-2 is free");
+        thread::sleep(Duration::from_millis(10));
+        println!("Renewing a lapsed lease: {:?}", jobs.renew(&short, Duration::from_secs(1)).err());
+    }
+
+    // Example 27: CRDT values and replica merging
+    println!("\nExample 27: CRDT values and replica merging");
+    {
+        // The merge laws themselves are property-tested in crdt.rs
+
+        // Two replicas of one store diverge, then reconcile in both directions at once
+        let replica_a = MyData::<String, PNCounter>::new(45, 4);
+        for item in ["apples", "pears"] {
+            let mut stock = PNCounter::new();
+            stock.increment(0, 10);
+            replica_a.insert(item.to_string(), stock);
+        }
+        let replica_b = replica_a.clone_data();
+
+        replica_a.transaction(&"apples".to_string(), |_, stock| stock.decrement(0, 3));
+        replica_b.transaction(&"apples".to_string(), |_, stock| stock.decrement(1, 4));
+        replica_b.upsert("plums".to_string(), PNCounter::new, |_, stock| stock.increment(1, 5));
+
+        let snapshot_a = replica_a.clone_data();
+        thread::scope(|s| {
+            s.spawn(|| replica_a.merge_from(&replica_b));
+            s.spawn(|| replica_b.merge_from(&snapshot_a));
+        });
+        // A second pass is a no-op: merging is idempotent
+        replica_a.merge_from(&replica_b);
+
+        let mut items = replica_a.keys();
+        items.sort();
+        let converged = items.iter().all(|item| {
+            replica_b.get(item).map(|v| v.value().clone()) == replica_a.get(item).map(|v| v.value().clone())
+        }) && replica_a.len() == replica_b.len();
+        for item in &items {
+            println!("  {}: {}", item, replica_a.get(item).map_or(0, |v| v.value().value()));
+        }
+        println!("Replicas converged: {}", converged);
+
+        // OR-Set: an add concurrent with a remove survives; LWW keeps the later write
+        let mut cart_a = OrSet::new();
+        cart_a.add(0, "book");
+        let mut cart_b = cart_a.clone();
+        cart_a.remove(&"book");
+        cart_b.add(1, "book");
+        cart_b.add(1, "pen");
+        cart_a.merge(&cart_b);
+        let mut cart: Vec<_> = cart_a.iter().copied().collect();
+        cart.sort();
+        println!(
+            "Cart after concurrent remove/add: {:?} ({} items, has book: {})",
+            cart,
+            cart_a.len(),
+            cart_a.contains(&"book")
+        );
+
+        // Grow-only counter: merging the same state twice counts it once
+        let mut views_a = GCounter::new();
+        views_a.increment(0, 3);
+        let mut views_b = GCounter::new();
+        views_b.increment(1, 4);
+        views_a.merge(&views_b);
+        views_a.merge(&views_b);
+        println!("Page views across replicas: {}", views_a.value());
+
+        let mut title = LwwRegister::new("draft", 1, 0);
+        title.merge(&LwwRegister::new("final", 5, 1));
+        title.set("stale", 3, 0);
+        println!("Title: {} (timestamp {}), empty set: {}", title.get(), title.timestamp(), OrSet::<u8>::new().is_empty());
+    }
+
+    // Example 28: Scrubbing and repairing a store
+    println!("\nExample 28: Scrubbing and repairing a store");
+    {
+        let store = MyData::<String, u64>::new(46, 4);
+        for i in 0..1000u64 {
+            store.insert(format!("item-{}", i), i);
+        }
+        let checksum = store.state_hash();
+        let report = store.verify_checksum(checksum, false);
+        println!("Healthy store: consistent = {}", report.is_consistent());
+
+        // Simulate damage: writes that bypassed key routing
+        let wrong_segment = |key: &String| (store.get_segment_index(key) + 1) % store.num_segments();
+        let stray = "stray".to_string();
+        store.get_segment(wrong_segment(&stray)).unwrap().insert(stray.clone(), 1);
+        let shadow = "item-5".to_string();
+        store.get_segment(wrong_segment(&shadow)).unwrap().insert(shadow.clone(), 999);
+
+        // ...and an entry in the wrong shard of its own segment
+        let lost = "lost".to_string();
+        let home = store.get_segment(store.get_segment_index(&lost)).unwrap();
+        let shard = (home.determine_map(&lost) + 1) % home.shards().len();
+        let hash = home.hash_usize(&lost) as u64;
+        home.shards()[shard]
+            .write()
+            .insert_unique(hash, (lost.clone(), 7), |(k, _)| home.hash_usize(k) as u64);
+
+        println!(
+            "Lookups before repair: stray = {:?}, lost = {:?}",
+            store.get(&stray).map(|v| *v.value()),
+            store.get(&lost).map(|v| *v.value())
+        );
+
+        let report = store.verify_checksum(checksum, false);
+        println!("{}", report);
+        let misplaced = report
+            .faults
+            .iter()
+            .filter(|f| matches!(f, Fault::WrongSegment { .. } | Fault::Unreachable { .. }))
+            .count();
+        let mismatched = report.faults.iter().any(|f| matches!(f, Fault::CountMismatch { .. }));
+        println!("Misplaced entries: {}, count mismatches: {}", misplaced, mismatched);
+
+        let report = store.verify(true);
+        println!("Repair: moved {}, dropped {} shadowed copies", report.repaired, report.dropped);
+        println!(
+            "Lookups after repair: stray = {:?}, lost = {:?}, item-5 = {:?}",
+            store.get(&stray).map(|v| *v.value()),
+            store.get(&lost).map(|v| *v.value()),
+            store.get(&shadow).map(|v| *v.value())
+        );
+
+        store.remove(&stray);
+        store.remove(&lost);
+        println!("After removing the strays: {}", store.verify_checksum(checksum, false));
+    }
+
+    // Example 29: Rayon adaptors directly on MyData
+    println!("\nExample 29: Rayon adaptors directly on MyData");
+    {
+        // collect() builds a store from any parallel iterator of pairs
+        let mut scores: MyData<u32, u64> = (0..50_000u32).into_par_iter().map(|i| (i, (i % 1000) as u64)).collect();
+        println!("Collected {} entries into {} segments", scores.len(), scores.num_segments());
+
+        let high: u64 = scores
+            .par_iter()
+            .filter(|entry| *entry.value() >= 900)
+            .map(|entry| *entry.value())
+            .sum();
+        let max_key = scores.par_iter().map(|entry| *entry.key()).max();
+        println!("Sum of scores >= 900: {}, largest key: {:?}", high, max_key);
+
+        scores.par_iter_mut().for_each(|mut entry| *entry.value_mut() *= 2);
+        println!("Doubled: key 999 -> {:?}", scores.get(&999).map(|v| *v.value()));
+
+        scores.par_extend((50_000..60_000u32).into_par_iter().map(|i| (i, 1)));
+        println!("After par_extend: {} entries", scores.len());
+
+        // Consuming iteration hands out owned pairs
+        let mut top: Vec<(u32, u64)> = scores.into_par_iter().filter(|&(_, v)| v == 1998).collect();
+        top.par_sort_unstable();
+        println!("{} entries scored 1998, first {:?}", top.len(), top.first());
+    }
+
+    // Example 30: Async facade on a tokio runtime
+    println!("\nExample 30: Async facade on a tokio runtime");
+    {
+        // A single-threaded runtime: anything that blocked it would stop the ticker
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("build tokio runtime");
+
+        runtime.block_on(async {
+            let store = AsyncData::new(Arc::new(MyData::<String, u64>::new(47, 8)), ScanPool::Rayon);
+
+            let writers: Vec<_> = (0..4u64)
+                .map(|task| {
+                    let store = store.clone();
+                    tokio::spawn(async move {
+                        for i in 0..250u64 {
+                            store.insert(format!("key-{}-{}", task, i), task * 1000 + i).await;
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.await.expect("writer task");
+            }
+            println!(
+                "Async inserts: {} entries, key-2-7 = {:?}, removed key-0-0 = {:?}",
+                store.data().len(),
+                store.get(&"key-2-7".to_string()).await,
+                store.remove(&"key-0-0".to_string()).await
+            );
+
+            // A watcher sees the value it starts from and every later change
+            let mut config = store.watch("config".to_string()).await;
+            let mut feed = store.changes();
+            let initial = config.current();
+            let watcher = tokio::spawn(async move {
+                let mut seen = vec![initial];
+                while let Some(value) = config.changed().await {
+                    seen.push(value);
+                    if value.is_none() {
+                        break;
+                    }
+                }
+                seen
+            });
+            for version in 1..=3 {
+                store.insert("config".to_string(), version).await;
+                tokio::task::yield_now().await;
+            }
+            store.remove(&"config".to_string()).await;
+            println!("Watcher saw {:?}", watcher.await.expect("watcher task"));
+
+            let mut feed_keys = Vec::new();
+            for _ in 0..4 {
+                if let Some(change) = feed.next().await {
+                    feed_keys.push(format!("{}={:?}", change.key, change.value));
+                }
+            }
+            println!("Change feed: {:?}, missed {}", feed_keys, feed.missed());
+
+            // A big scan runs on rayon while the runtime keeps ticking
+            let big = AsyncData::new(Arc::new(MyData::<u64, u64>::new(48, 16)), ScanPool::Blocking);
+            big.data().replace_all((0..300_000u64).into_par_iter().map(|i| (i, i % 1000)));
+            let ticks = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let ticker = {
+                let ticks = Arc::clone(&ticks);
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                        ticks.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    }
+                })
+            };
+            let found = big
+                .find(|_, v| {
+                    // Make the scan slow enough to notice
+                    std::hint::black_box((0..50).sum::<u64>());
+                    *v == 999
+                })
+                .await;
+            let on_rayon = store.scan(|data| data.keys().len()).await;
+            ticker.abort();
+            println!(
+                "Scan found {} entries; runtime ticked {} times meanwhile; rayon scan counted {} keys",
+                found.len(),
+                ticks.load(std::sync::atomic::Ordering::Relaxed),
+                on_rayon
+            );
+
+            // A point read on a shard busy with a slow synchronous scan moves off the runtime
+            let slow_scan = {
+                let data = Arc::clone(big.data());
+                std::thread::spawn(move || {
+                    data.for_each(|_, v| {
+                        if *v == 0 {
+                            thread::sleep(Duration::from_micros(50));
+                        }
+                    })
+                })
+            };
+            let mut reads = 0;
+            while !slow_scan.is_finished() {
+                big.get(&(reads % 300_000)).await;
+                reads += 1;
+                tokio::task::yield_now().await;
+            }
+            slow_scan.join().expect("slow scan");
+            let stats = big.stats();
+            println!(
+                "During a blocking for_each: {} reads, {} inline, {} offloaded, {} scans",
+                reads, stats.inline, stats.offloaded, stats.scans
+            );
+        });
+    }
+
+    // Example 31: CSV and JSON Lines export and import
+    println!("\nExample 31: CSV and JSON Lines export and import");
+    {
+        use std::io::{BufReader, BufWriter};
+
+        let source = MyData::<String, u64>::new(49, 8);
+        source.replace_all((0..20_000u64).into_par_iter().map(|i| (format!("item-{}", i), i * 3)));
+        // Keys that need quoting or escaping
+        for (i, key) in ["comma, inside", "say \"hi\"", "two\nlines", " padded ", "", "caf\u{e9} \u{1f980}", "tab\there"]
+            .iter()
+            .enumerate()
+        {
+            source.insert(key.to_string(), i as u64);
+        }
+
+        let dir = std::env::temp_dir().join(format!("mydata-textio-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("create export directory");
+        let csv_path = dir.join("store.csv");
+        let jsonl_path = dir.join("store.jsonl");
+
+        let mut callbacks = 0;
+        let written = export_csv(
+            &source,
+            BufWriter::new(std::fs::File::create(&csv_path).expect("create csv")),
+            &PlainText,
+            &Number,
+            |_| callbacks += 1,
+        )
+        .expect("export csv");
+        println!(
+            "CSV: {} of {:?} entries, {} bytes, {} progress callbacks",
+            written.records, written.total, written.bytes, callbacks
+        );
+        let written = export_jsonl(
+            &source,
+            BufWriter::new(std::fs::File::create(&jsonl_path).expect("create jsonl")),
+            &PlainText,
+            &Number,
+            |_| {},
+        )
+        .expect("export jsonl");
+        println!("JSONL: {} entries, {} bytes", written.records, written.bytes);
+
+        let from_csv = MyData::<String, u64>::new(50, 4);
+        let report = import_csv(
+            &from_csv,
+            BufReader::new(std::fs::File::open(&csv_path).expect("open csv")),
+            &PlainText,
+            &Number,
+            |p| {
+                if p.records % 10_000 == 0 {
+                    println!("  ...read {} lines", p.records);
+                }
+            },
+        )
+        .expect("import csv");
+        let from_jsonl = MyData::<String, u64>::new(51, 16);
+        let jsonl_report = import_jsonl(
+            &from_jsonl,
+            BufReader::new(std::fs::File::open(&jsonl_path).expect("open jsonl")),
+            &PlainText,
+            &Number,
+            |_| {},
+        )
+        .expect("import jsonl");
+        println!(
+            "Round trips: csv {} imported (clean {}, same contents {}), jsonl {} imported (clean {}, same contents {})",
+            report.imported,
+            report.is_clean(),
+            from_csv.state_hash() == source.state_hash(),
+            jsonl_report.imported,
+            jsonl_report.is_clean(),
+            from_jsonl.state_hash() == source.state_hash()
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // A custom codec: tag lists as "a|b|c", or a JSON array of strings
+        struct Tags;
+        impl TextCodec<Vec<String>> for Tags {
+            fn encode(&self, tags: &Vec<String>) -> String {
+                tags.join("|")
+            }
+
+            fn decode(&self, text: &str) -> Result<Vec<String>, String> {
+                if text.is_empty() {
+                    return Ok(Vec::new());
+                }
+                if text.contains(['\n', '\r']) {
+                    return Err("tags cannot span lines".to_string());
+                }
+                Ok(text.split('|').map(str::to_string).collect())
+            }
+        }
+        let tagged = MyData::<u32, Vec<String>>::new(52, 4);
+        tagged.insert(1, vec!["red".to_string(), "large, heavy".to_string()]);
+        tagged.insert(2, Vec::new());
+        let mut buf = Vec::new();
+        export_csv(&tagged, &mut buf, &Number, &Tags, |_| {}).expect("export to memory");
+        let copy = MyData::<u32, Vec<String>>::new(53, 2);
+        import_csv(&copy, buf.as_slice(), &Number, &Tags, |_| {}).expect("import from memory");
+        println!("Custom codec round trip: 1 -> {:?}", copy.get(&1).map(|v| v.value().clone()));
+
+        // Bad input is skipped and reported by line
+        let bad_csv = "key,value\na,1\nb,lots\n\"multi\nline\",2\nc,3,4\nd,\"5\"x\n\"open,6\n";
+        let target = MyData::<String, u64>::new(54, 4);
+        let report = import_csv(&target, bad_csv.as_bytes(), &PlainText, &Number, |_| {}).expect("read csv");
+        println!("CSV import: {} imported, {} failed", report.imported, report.failed);
+        for error in &report.errors {
+            println!("  {}", error);
+        }
+
+        let bad_jsonl = concat!(
+            "{\"key\":\"a\",\"value\":1}\n",
+            "{\"key\":\"b\"}\n",
+            "not json\n",
+            "{\"key\":\"c\",\"value\":\"x\"}\n",
+            "\n",
+            "{\"value\": 7, \"key\": \"\\ud83e\\udd80 \\\"quoted\\\"\"}\n",
+            "{\"key\":\"d\",\"value\":1,\"extra\":true}\n",
+        );
+        let report = import_jsonl(&target, bad_jsonl.as_bytes(), &PlainText, &Number, |_| {}).expect("read jsonl");
+        println!("JSONL import: {} imported, {} failed", report.imported, report.failed);
+        for error in &report.errors {
+            println!("  {}", error);
+        }
+        let mut keys = target.keys();
+        keys.sort();
+        println!("Keys now: {:?}", keys);
     }
 
     // Final statistics
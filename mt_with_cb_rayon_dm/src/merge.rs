@@ -0,0 +1,328 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::AddAssign;
+
+use crate::counter::StripedCounter;
+use crate::data_structures::MyData;
+
+/// Combines an operand into a stored value, like a RocksDB merge operator.
+/// Operands must be applied in the order they were merged.
+pub trait MergeOperator<V>: Send + Sync + 'static {
+    type Operand: Clone + Send + Sync + 'static;
+
+    /// Apply `operand` to the current value, or to nothing if the key is absent
+    fn apply(&self, existing: Option<V>, operand: Self::Operand) -> V;
+}
+
+/// Numeric add; a missing key starts from `V::default()`
+pub struct Add;
+
+impl<V> MergeOperator<V> for Add
+where
+    V: AddAssign + Default + Send + Sync + Clone + 'static,
+{
+    type Operand = V;
+
+    fn apply(&self, existing: Option<V>, operand: V) -> V {
+        let mut value = existing.unwrap_or_default();
+        value += operand;
+        value
+    }
+}
+
+/// Union of sets
+pub struct SetUnion;
+
+impl<T> MergeOperator<HashSet<T>> for SetUnion
+where
+    T: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    type Operand = HashSet<T>;
+
+    fn apply(&self, existing: Option<HashSet<T>>, operand: HashSet<T>) -> HashSet<T> {
+        let mut set = existing.unwrap_or_default();
+        set.extend(operand);
+        set
+    }
+}
+
+/// Append to a list, keeping merge order
+pub struct ListAppend;
+
+impl<T> MergeOperator<Vec<T>> for ListAppend
+where
+    T: Clone + Send + Sync + 'static,
+{
+    type Operand = Vec<T>;
+
+    fn apply(&self, existing: Option<Vec<T>>, operand: Vec<T>) -> Vec<T> {
+        let mut list = existing.unwrap_or_default();
+        list.extend(operand);
+        list
+    }
+}
+
+/// Any `Fn(Option<V>, V) -> V` is a merge operator taking values as operands
+impl<V, F> MergeOperator<V> for F
+where
+    V: Clone + Send + Sync + 'static,
+    F: Fn(Option<V>, V) -> V + Send + Sync + 'static,
+{
+    type Operand = V;
+
+    fn apply(&self, existing: Option<V>, operand: V) -> V {
+        self(existing, operand)
+    }
+}
+
+/// When merge operands are combined with the stored value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeMode {
+    /// Apply each operand immediately, under the segment lock
+    Eager,
+    /// Queue operands with the entry and apply them on the next read, or
+    /// once `max_pending` are queued for one key
+    Lazy { max_pending: usize },
+}
+
+/// A stored value plus the operands not yet applied to it
+#[derive(Clone)]
+struct Slot<V, O> {
+    value: Option<V>,
+    pending: Vec<O>,
+}
+
+impl<V, O> Slot<V, O> {
+    fn empty() -> Self {
+        Slot {
+            value: None,
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Counters describing merge activity
+#[derive(Debug, Clone, Copy)]
+pub struct MergeStats {
+    pub merges: usize,
+    /// Operands applied later than they were merged, in lazy mode
+    pub deferred_applied: usize,
+    /// Operands currently queued across all keys
+    pub pending: usize,
+}
+
+/// A `MyData` whose values are updated with a merge operator chosen at
+/// construction, so read-modify-write becomes a single `merge` call
+pub struct MergeData<K, V, M>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    M: MergeOperator<V>,
+{
+    data: MyData<K, Slot<V, M::Operand>>,
+    operator: M,
+    mode: MergeMode,
+    merges: StripedCounter,
+    deferred_applied: StripedCounter,
+}
+
+impl<K, V, M> MergeData<K, V, M>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    M: MergeOperator<V>,
+{
+    pub fn new(id: usize, num_segments: usize, operator: M, mode: MergeMode) -> Self {
+        MergeData {
+            data: MyData::new(id, num_segments),
+            operator,
+            mode,
+            merges: StripedCounter::new(),
+            deferred_applied: StripedCounter::new(),
+        }
+    }
+
+    /// Combine `operand` into the value at `key`. Concurrent merges to the
+    /// same key are serialised by its segment lock, so none are lost.
+    pub fn merge(&self, key: K, operand: M::Operand) {
+        self.data.upsert(key, Slot::empty, |_, slot| match self.mode {
+            MergeMode::Eager => slot.value = Some(self.operator.apply(slot.value.take(), operand)),
+            MergeMode::Lazy { max_pending } => {
+                slot.pending.push(operand);
+                if slot.pending.len() >= max_pending {
+                    self.resolve(slot);
+                }
+            }
+        });
+        self.merges.increment();
+    }
+
+    /// Overwrite the value at `key`, discarding any queued operands
+    pub fn put(&self, key: K, value: V) {
+        self.data.insert(
+            key,
+            Slot {
+                value: Some(value),
+                pending: Vec::new(),
+            },
+        );
+    }
+
+    /// The value at `key` with every merged operand applied
+    pub fn get(&self, key: &K) -> Option<V> {
+        let needs_resolve = self.data.get(key).is_some_and(|slot| !slot.pending.is_empty());
+        if needs_resolve {
+            // Apply under the write lock and keep the result, so the
+            // operands are only folded once
+            self.data
+                .transaction(key, |_, slot| {
+                    self.resolve(slot);
+                    slot.value.clone()
+                })
+                .flatten()
+        } else {
+            self.data.get(key).and_then(|slot| slot.value.clone())
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.data.remove(key).and_then(|(_, mut slot)| {
+            self.resolve(&mut slot);
+            slot.value
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn stats(&self) -> MergeStats {
+        let pending = (0..self.data.num_segments())
+            .filter_map(|idx| self.data.get_segment(idx))
+            .map(|segment| segment.iter().map(|slot| slot.pending.len()).sum::<usize>())
+            .sum();
+        MergeStats {
+            merges: self.merges.sum(),
+            deferred_applied: self.deferred_applied.sum(),
+            pending,
+        }
+    }
+
+    /// Fold queued operands into the value, oldest first
+    fn resolve(&self, slot: &mut Slot<V, M::Operand>) {
+        if slot.pending.is_empty() {
+            return;
+        }
+        self.deferred_applied.add(slot.pending.len());
+        let mut value = slot.value.take();
+        for operand in slot.pending.drain(..) {
+            value = Some(self.operator.apply(value, operand));
+        }
+        slot.value = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [MergeMode; 2] = [MergeMode::Eager, MergeMode::Lazy { max_pending: 4 }];
+
+    #[test]
+    fn add_sums_operands_and_starts_from_default() {
+        for mode in MODES {
+            let data = MergeData::new(0, 4, Add, mode);
+            for n in 1..=10u64 {
+                data.merge("a", n);
+            }
+            data.put("b", 100);
+            data.merge("b", 5);
+            assert_eq!(data.get(&"a"), Some(55), "{mode:?}");
+            assert_eq!(data.get(&"b"), Some(105), "{mode:?}");
+            assert_eq!(data.get(&"c"), None, "{mode:?}");
+            assert_eq!(data.stats().merges, 11);
+        }
+    }
+
+    #[test]
+    fn set_union_collects_every_member() {
+        for mode in MODES {
+            let data = MergeData::new(0, 4, SetUnion, mode);
+            for n in 0..10u32 {
+                data.merge(n % 2, HashSet::from([n, n + 2]));
+            }
+            assert_eq!(
+                data.get(&0),
+                Some((0..=10).step_by(2).collect()),
+                "{mode:?}"
+            );
+            assert_eq!(
+                data.get(&1),
+                Some((1..=11).step_by(2).collect()),
+                "{mode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn list_append_keeps_merge_order() {
+        for mode in MODES {
+            let data = MergeData::new(0, 4, ListAppend, mode);
+            for n in 0..10u32 {
+                data.merge("list", vec![n, n]);
+            }
+            let expected: Vec<u32> = (0..10).flat_map(|n| [n, n]).collect();
+            assert_eq!(data.remove(&"list"), Some(expected), "{mode:?}");
+            assert_eq!(data.len(), 0);
+        }
+    }
+
+    #[test]
+    fn closures_are_merge_operators() {
+        for mode in MODES {
+            let max =
+                |existing: Option<i64>, operand: i64| existing.map_or(operand, |e| e.max(operand));
+            let data = MergeData::new(0, 4, max, mode);
+            for n in [3, -7, 12, 5, 12, 0] {
+                data.merge("max", n);
+            }
+            assert_eq!(data.get(&"max"), Some(12), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn lazy_get_applies_pending_operands_in_arrival_order() {
+        // Not commutative, so any reordering shows up in the result
+        let concat =
+            |existing: Option<String>, operand: String| existing.unwrap_or_default() + &operand;
+        let data = MergeData::new(0, 4, concat, MergeMode::Lazy { max_pending: 100 });
+        data.put("word", "a".to_string());
+        for part in ["b", "c", "d", "e"] {
+            data.merge("word", part.to_string());
+        }
+        let before = data.stats();
+        assert_eq!(before.pending, 4);
+        assert_eq!(before.deferred_applied, 0);
+
+        assert_eq!(data.get(&"word").as_deref(), Some("abcde"));
+        let after = data.stats();
+        assert_eq!(after.pending, 0);
+        assert_eq!(after.deferred_applied, 4);
+
+        // Resolved operands are not applied a second time
+        assert_eq!(data.get(&"word").as_deref(), Some("abcde"));
+        assert_eq!(data.stats().deferred_applied, 4);
+    }
+
+    #[test]
+    fn lazy_mode_resolves_once_max_pending_are_queued() {
+        let data = MergeData::new(0, 4, Add, MergeMode::Lazy { max_pending: 3 });
+        for _ in 0..7 {
+            data.merge("n", 1u64);
+        }
+        let stats = data.stats();
+        assert_eq!(stats.pending, 1);
+        assert_eq!(stats.deferred_applied, 6);
+        assert_eq!(data.get(&"n"), Some(7));
+    }
+}
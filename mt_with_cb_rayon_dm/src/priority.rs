@@ -0,0 +1,347 @@
+use crossbeam::channel::RecvTimeoutError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::counter::StripedCounter;
+use crate::queue::{worker_channels, Evictable, QueueConfig, SendError, WorkerReceiver, WorkerSender};
+use crate::worker_utils::{Operation, OperationSource};
+
+/// Priority class of an operation sent to a worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Latency-sensitive requests such as user-facing `Get`s
+    Interactive,
+    Normal,
+    /// Throughput work such as large batches of inserts
+    Bulk,
+}
+
+impl Priority {
+    /// All lanes, from highest to lowest priority
+    pub const ALL: [Priority; 3] = [Priority::Interactive, Priority::Normal, Priority::Bulk];
+
+    fn index(self) -> usize {
+        match self {
+            Priority::Interactive => 0,
+            Priority::Normal => 1,
+            Priority::Bulk => 2,
+        }
+    }
+}
+
+/// Configuration for a set of priority lanes
+#[derive(Debug, Clone, Copy)]
+pub struct LaneConfig {
+    /// Queue configuration applied to each lane
+    pub queue: QueueConfig,
+    /// Relative share of the worker each lane gets when all are busy,
+    /// indexed in `Priority::ALL` order
+    pub weights: [u32; 3],
+    /// A lane with work that has not been served for this long gets the next
+    /// turn regardless of weights, once per threshold
+    pub starvation_threshold: Duration,
+}
+
+impl Default for LaneConfig {
+    fn default() -> Self {
+        LaneConfig {
+            queue: QueueConfig::unbounded(),
+            weights: [8, 4, 1],
+            starvation_threshold: Duration::from_millis(100),
+        }
+    }
+}
+
+/// An operation together with the time it was enqueued
+struct Queued<K, V> {
+    operation: Operation<K, V>,
+    enqueued_at: Instant,
+}
+
+impl<K, V> Evictable for Queued<K, V> {
+    fn evictable(&self) -> bool {
+        self.operation.evictable()
+    }
+}
+
+/// Latency counters for one lane
+#[derive(Default)]
+struct LaneMetrics {
+    served: StripedCounter,
+    promoted: StripedCounter,
+    latency_nanos: StripedCounter,
+    max_latency_nanos: AtomicU64,
+}
+
+/// A point-in-time snapshot of one lane
+#[derive(Debug, Clone, Copy)]
+pub struct LaneStats {
+    pub priority: Priority,
+    /// Operations waiting in this lane
+    pub depth: usize,
+    pub served: usize,
+    /// Operations served early because they hit the starvation threshold
+    pub promoted: usize,
+    /// Mean time between enqueue and the worker picking the operation up
+    pub mean_latency: Duration,
+    pub max_latency: Duration,
+}
+
+/// Sending half of a set of priority lanes
+pub struct PrioritySender<K, V> {
+    lanes: [WorkerSender<Queued<K, V>>; 3],
+    metrics: Arc<[LaneMetrics; 3]>,
+}
+
+impl<K, V> Clone for PrioritySender<K, V> {
+    fn clone(&self) -> Self {
+        PrioritySender {
+            lanes: self.lanes.clone(),
+            metrics: Arc::clone(&self.metrics),
+        }
+    }
+}
+
+impl<K, V> PrioritySender<K, V> {
+    /// Enqueue an operation in the lane for `priority`
+    pub fn send(&self, priority: Priority, operation: Operation<K, V>) -> Result<(), SendError<Operation<K, V>>> {
+        let queued = Queued {
+            operation,
+            enqueued_at: Instant::now(),
+        };
+        self.lanes[priority.index()].send(queued).map_err(|e| match e {
+            SendError::Full(q) => SendError::Full(q.operation),
+            SendError::Timeout(q) => SendError::Timeout(q.operation),
+            SendError::Disconnected(q) => SendError::Disconnected(q.operation),
+        })
+    }
+
+    /// Statistics for every lane, in `Priority::ALL` order
+    pub fn lane_stats(&self) -> Vec<LaneStats> {
+        Priority::ALL
+            .iter()
+            .map(|&priority| {
+                let metrics = &self.metrics[priority.index()];
+                let served = metrics.served.sum();
+                let total = metrics.latency_nanos.sum() as u64;
+                LaneStats {
+                    priority,
+                    depth: self.lanes[priority.index()].depth(),
+                    served,
+                    promoted: metrics.promoted.sum(),
+                    mean_latency: Duration::from_nanos(if served > 0 { total / served as u64 } else { 0 }),
+                    max_latency: Duration::from_nanos(metrics.max_latency_nanos.load(Ordering::Relaxed)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Scheduling state, only touched by the worker that owns the receiver
+struct Scheduler<K, V> {
+    // The oldest operation of each lane, pulled off the channel so its age is known
+    heads: [Option<Queued<K, V>>; 3],
+    // Smooth weighted round-robin credit of each lane
+    credit: [i64; 3],
+    // When each lane was last handed to the worker
+    last_served: [Instant; 3],
+    disconnected: [bool; 3],
+}
+
+/// Receiving half of a set of priority lanes.
+/// Picks lanes by smooth weighted round-robin and promotes starving operations.
+pub struct PriorityReceiver<K, V> {
+    lanes: [WorkerReceiver<Queued<K, V>>; 3],
+    scheduler: Mutex<Scheduler<K, V>>,
+    weights: [u32; 3],
+    starvation_threshold: Duration,
+    metrics: Arc<[LaneMetrics; 3]>,
+}
+
+/// Create a set of priority lanes with the given configuration
+pub fn priority_channel<K, V>(config: LaneConfig) -> (PrioritySender<K, V>, PriorityReceiver<K, V>) {
+    // The lanes share one wakeup, so an idle worker can wait on all of them
+    let (senders, receivers) = worker_channels(config.queue);
+    let metrics = Arc::new(<[LaneMetrics; 3]>::default());
+
+    let sender = PrioritySender {
+        lanes: senders,
+        metrics: Arc::clone(&metrics),
+    };
+    let receiver = PriorityReceiver {
+        lanes: receivers,
+        scheduler: Mutex::new(Scheduler {
+            heads: [None, None, None],
+            credit: [0; 3],
+            last_served: [Instant::now(); 3],
+            disconnected: [false; 3],
+        }),
+        weights: config.weights,
+        starvation_threshold: config.starvation_threshold,
+        metrics,
+    };
+
+    (sender, receiver)
+}
+
+impl<K, V> PriorityReceiver<K, V> {
+    /// Pull the next item of every lane whose head slot is empty
+    fn refill(&self, scheduler: &mut Scheduler<K, V>) {
+        for (idx, lane) in self.lanes.iter().enumerate() {
+            if scheduler.heads[idx].is_none() && !scheduler.disconnected[idx] {
+                match lane.try_recv() {
+                    Ok(queued) => scheduler.heads[idx] = Some(queued),
+                    Err(e) if e.is_disconnected() => scheduler.disconnected[idx] = true,
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+
+    /// Choose which non-empty lane to serve next
+    fn pick_lane(&self, scheduler: &mut Scheduler<K, V>) -> Option<usize> {
+        let now = Instant::now();
+
+        // Starvation protection: a lane that has had work but no turn for a whole
+        // threshold is served once. Keyed on the lane rather than the item's age,
+        // so a deep backlog of old items cannot turn the lanes into one FIFO.
+        let starving = (0..3)
+            .filter_map(|idx| {
+                let head = scheduler.heads[idx].as_ref()?;
+                Some((idx, head.enqueued_at.max(scheduler.last_served[idx])))
+            })
+            .filter(|(_, waiting_since)| now.duration_since(*waiting_since) >= self.starvation_threshold)
+            .min_by_key(|(_, waiting_since)| *waiting_since);
+        if let Some((idx, _)) = starving {
+            self.metrics[idx].promoted.increment();
+            return Some(idx);
+        }
+
+        // Smooth weighted round-robin over the lanes that have work
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for idx in 0..3 {
+            if scheduler.heads[idx].is_none() {
+                continue;
+            }
+            let weight = self.weights[idx].max(1) as i64;
+            scheduler.credit[idx] += weight;
+            total += weight;
+            if best.is_none_or(|b| scheduler.credit[idx] > scheduler.credit[b]) {
+                best = Some(idx);
+            }
+        }
+        if let Some(idx) = best {
+            scheduler.credit[idx] -= total;
+        }
+        best
+    }
+
+    /// Hand out the head of `idx` and record its queue latency
+    fn take(&self, scheduler: &mut Scheduler<K, V>, idx: usize) -> Operation<K, V> {
+        let queued = scheduler.heads[idx].take().expect("picked lane has a head");
+        scheduler.last_served[idx] = Instant::now();
+        let latency = queued.enqueued_at.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+        let metrics = &self.metrics[idx];
+        metrics.served.increment();
+        metrics.latency_nanos.add(latency as usize);
+        metrics.max_latency_nanos.fetch_max(latency, Ordering::Relaxed);
+        queued.operation
+    }
+}
+
+impl<K, V> OperationSource<K, V> for PriorityReceiver<K, V>
+where
+    K: Send + 'static,
+    V: Send + 'static,
+{
+    fn recv_timeout(&self, timeout: Duration) -> Result<Operation<K, V>, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut scheduler = self.scheduler.lock().unwrap();
+
+        // Every lane wakes the same waiter, so waiting on one waits on all
+        self.lanes[0].wait_until(Some(deadline), || {
+            self.refill(&mut scheduler);
+            scheduler.heads.iter().any(Option::is_some) || scheduler.disconnected.iter().all(|&d| d)
+        });
+
+        if let Some(idx) = self.pick_lane(&mut scheduler) {
+            return Ok(self.take(&mut scheduler, idx));
+        }
+        if scheduler.disconnected.iter().all(|&d| d) {
+            return Err(RecvTimeoutError::Disconnected);
+        }
+        Err(RecvTimeoutError::Timeout)
+    }
+
+    fn try_recv(&self) -> Option<Operation<K, V>> {
+        let mut scheduler = self.scheduler.lock().unwrap();
+        self.refill(&mut scheduler);
+        self.pick_lane(&mut scheduler)
+            .map(|idx| self.take(&mut scheduler, idx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn lanes(weights: [u32; 3], starvation_threshold: Duration) -> (PrioritySender<u64, u64>, PriorityReceiver<u64, u64>) {
+        priority_channel(LaneConfig {
+            weights,
+            starvation_threshold,
+            ..LaneConfig::default()
+        })
+    }
+
+    fn next_key(receiver: &PriorityReceiver<u64, u64>) -> u64 {
+        match receiver.try_recv() {
+            Some(Operation::Insert(key, _)) => key,
+            _ => panic!("expected a queued insert"),
+        }
+    }
+
+    #[test]
+    fn lanes_share_the_worker_by_weight() {
+        let (sender, receiver) = lanes([8, 4, 1], Duration::from_secs(3600));
+        for (lane, priority) in Priority::ALL.iter().enumerate() {
+            for i in 0..20 {
+                sender.send(*priority, Operation::Insert(lane as u64 * 100 + i, 0)).unwrap();
+            }
+        }
+        let mut served = [0; 3];
+        for _ in 0..13 {
+            served[(next_key(&receiver) / 100) as usize] += 1;
+        }
+        assert_eq!(served, [8, 4, 1]);
+    }
+
+    #[test]
+    fn a_starving_lane_is_promoted_once() {
+        let (sender, receiver) = lanes([8, 4, 1], Duration::from_millis(20));
+        sender.send(Priority::Bulk, Operation::Insert(200, 0)).unwrap();
+        sender.send(Priority::Bulk, Operation::Insert(201, 0)).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        for i in 0..20 {
+            sender.send(Priority::Interactive, Operation::Insert(i, 0)).unwrap();
+        }
+        assert_eq!(next_key(&receiver), 200);
+        // The lane was just served, so its next item waits for its weighted turn
+        assert!(next_key(&receiver) < 100);
+        assert_eq!(sender.lane_stats()[2].promoted, 1);
+    }
+
+    #[test]
+    fn bulk_backlog_does_not_delay_interactive_work() {
+        let (sender, receiver) = lanes([8, 4, 1], Duration::from_millis(10));
+        for i in 0..1_000 {
+            sender.send(Priority::Bulk, Operation::Insert(200 + i, 0)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(20));
+        sender.send(Priority::Interactive, Operation::Insert(0, 0)).unwrap();
+        let first: Vec<u64> = (0..3).map(|_| next_key(&receiver)).collect();
+        assert!(first.contains(&0), "interactive op waited behind {:?}", first);
+    }
+}
@@ -0,0 +1,917 @@
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use crate::cancellation::{OpContext, OpResult};
+use crate::data_structures::{MyData, CANCEL_CHECK_INTERVAL};
+use crate::worker_utils::Predicate;
+
+/// A constant appearing in a query. Integers are 128-bit so that every
+/// `u64` and `i64` key or value can be compared exactly.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Int(i128),
+    Float(f64),
+    Str(String),
+}
+
+impl Literal {
+    fn as_field(&self) -> FieldValue<'_> {
+        match self {
+            Literal::Int(i) => FieldValue::Int(*i),
+            Literal::Float(x) => FieldValue::Float(*x),
+            Literal::Str(s) => FieldValue::Str(s),
+        }
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Int(i) => write!(f, "{}", i),
+            // Debug formatting keeps a decimal point or exponent, so the value
+            // parses back as a float; it also gives `NaN`, `inf` and `-inf`,
+            // which the tokenizer reads back too
+            Literal::Float(x) => write!(f, "{:?}", x),
+            Literal::Str(s) => write_quoted(f, s),
+        }
+    }
+}
+
+/// A key or value as a query sees it, borrowed from the entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue<'a> {
+    Int(i128),
+    Float(f64),
+    Str(&'a str),
+}
+
+impl FieldValue<'_> {
+    /// Numbers compare with numbers and strings with strings; any other
+    /// combination is not comparable.
+    pub fn compare(&self, other: &FieldValue<'_>) -> Option<Ordering> {
+        match (self, other) {
+            (FieldValue::Int(a), FieldValue::Int(b)) => Some(a.cmp(b)),
+            (FieldValue::Int(a), FieldValue::Float(b)) => (*a as f64).partial_cmp(b),
+            (FieldValue::Float(a), FieldValue::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (FieldValue::Float(a), FieldValue::Float(b)) => a.partial_cmp(b),
+            (FieldValue::Str(a), FieldValue::Str(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    /// Where a value sorts regardless of direction: numbers, then strings,
+    /// then NaN, which has no place among the numbers
+    fn sort_rank(&self) -> u8 {
+        match self {
+            FieldValue::Float(x) if x.is_nan() => 2,
+            FieldValue::Int(_) | FieldValue::Float(_) => 0,
+            FieldValue::Str(_) => 1,
+        }
+    }
+
+    /// A total order for `order by`. Values of different ranks keep their
+    /// rank order in both directions, so NaN always sorts last.
+    fn sort_cmp(&self, other: &FieldValue<'_>, direction: Direction) -> Ordering {
+        let ordering = match (self, other) {
+            (FieldValue::Int(a), FieldValue::Int(b)) => a.cmp(b),
+            (FieldValue::Int(a), FieldValue::Float(b)) => cmp_int_float(*a, *b),
+            (FieldValue::Float(a), FieldValue::Int(b)) => cmp_int_float(*b, *a).reverse(),
+            (FieldValue::Float(a), FieldValue::Float(b)) => a.total_cmp(b),
+            (FieldValue::Str(a), FieldValue::Str(b)) => a.cmp(b),
+            _ => return self.sort_rank().cmp(&other.sort_rank()),
+        };
+        let ordering = match direction {
+            Direction::Asc => ordering,
+            Direction::Desc => ordering.reverse(),
+        };
+        self.sort_rank().cmp(&other.sort_rank()).then(ordering)
+    }
+
+    /// Whether the value, as text, matches a glob; only numeric keys are
+    /// formatted for this
+    fn matches_glob(&self, pattern: &str) -> bool {
+        match self {
+            FieldValue::Int(i) => glob_matches(pattern, &i.to_string()),
+            FieldValue::Float(x) => glob_matches(pattern, &x.to_string()),
+            FieldValue::Str(s) => glob_matches(pattern, s),
+        }
+    }
+}
+
+/// Exact order of an integer and a float, with NaN after every integer
+fn cmp_int_float(a: i128, b: f64) -> Ordering {
+    if b.is_nan() {
+        return Ordering::Less;
+    }
+    match (a as f64).partial_cmp(&b) {
+        // Equal as floats means `b` is a whole number, so compare as integers
+        Some(Ordering::Equal) => a.cmp(&(b as i128)),
+        Some(ordering) => ordering,
+        None => Ordering::Less,
+    }
+}
+
+/// Types that can appear as keys or values in a query
+pub trait QueryField {
+    fn field_value(&self) -> FieldValue<'_>;
+}
+
+impl QueryField for String {
+    fn field_value(&self) -> FieldValue<'_> {
+        FieldValue::Str(self)
+    }
+}
+
+macro_rules! int_query_field {
+    ($($t:ty),*) => {
+        $(impl QueryField for $t {
+            fn field_value(&self) -> FieldValue<'_> {
+                // Lossless: every integer type here fits in an i128
+                FieldValue::Int(*self as i128)
+            }
+        })*
+    };
+}
+
+int_query_field!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl QueryField for f32 {
+    fn field_value(&self) -> FieldValue<'_> {
+        FieldValue::Float(*self as f64)
+    }
+}
+
+impl QueryField for f64 {
+    fn field_value(&self) -> FieldValue<'_> {
+        FieldValue::Float(*self)
+    }
+}
+
+/// The part of an entry a condition looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Key,
+    Value,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Field::Key => "key",
+            Field::Value => "value",
+        })
+    }
+}
+
+/// Comparison operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            CmpOp::Eq => ordering == Ordering::Equal,
+            CmpOp::Ne => ordering != Ordering::Equal,
+            CmpOp::Lt => ordering == Ordering::Less,
+            CmpOp::Le => ordering != Ordering::Greater,
+            CmpOp::Gt => ordering == Ordering::Greater,
+            CmpOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CmpOp::Eq => "=",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        })
+    }
+}
+
+/// Boolean filter over an entry
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// Matches every entry
+    True,
+    /// Key matches a glob where `*` is any run of characters and `?` is one character
+    KeyGlob(String),
+    Compare(Field, CmpOp, Literal),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+impl Expr {
+    /// Evaluate the filter against a key and value
+    pub fn matches(&self, key: FieldValue<'_>, value: FieldValue<'_>) -> bool {
+        match self {
+            Expr::True => true,
+            Expr::KeyGlob(pattern) => key.matches_glob(pattern),
+            Expr::Compare(field, op, literal) => {
+                let actual = match field {
+                    Field::Key => key,
+                    Field::Value => value,
+                };
+                actual
+                    .compare(&literal.as_field())
+                    .is_some_and(|ordering| op.holds(ordering))
+            }
+            Expr::And(a, b) => a.matches(key, value) && b.matches(key, value),
+            Expr::Or(a, b) => a.matches(key, value) || b.matches(key, value),
+            Expr::Not(inner) => !inner.matches(key, value),
+        }
+    }
+
+    // Binding strength used to decide where parentheses are needed when printing
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Or(..) => 1,
+            Expr::And(..) => 2,
+            _ => 3,
+        }
+    }
+
+    fn fmt_child(&self, f: &mut fmt::Formatter<'_>, parent: u8) -> fmt::Result {
+        if self.precedence() < parent {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::True => f.write_str("true"),
+            Expr::KeyGlob(pattern) => {
+                f.write_str("key ~ ")?;
+                write_quoted(f, pattern)
+            }
+            Expr::Compare(field, op, literal) => write!(f, "{} {} {}", field, op, literal),
+            Expr::And(a, b) => {
+                a.fmt_child(f, 2)?;
+                f.write_str(" and ")?;
+                b.fmt_child(f, 3)
+            }
+            Expr::Or(a, b) => {
+                a.fmt_child(f, 1)?;
+                f.write_str(" or ")?;
+                b.fmt_child(f, 2)
+            }
+            Expr::Not(inner) => {
+                f.write_str("not ")?;
+                inner.fmt_child(f, 3)
+            }
+        }
+    }
+}
+
+/// Sort direction for `order by`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+/// A complete query: filter, optional ordering and optional limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    pub filter: Expr,
+    pub order: Option<(Field, Direction)>,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    /// Parse the textual form of a query, e.g.
+    /// `key ~ "user-*" and not value < 10 order by value desc limit 5`
+    pub fn parse(text: &str) -> Result<Query, ParseError> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let query = parser.query()?;
+        match parser.peek() {
+            None => Ok(query),
+            Some(token) => Err(parser.error(format!("unexpected {}", token.kind))),
+        }
+    }
+
+    /// Bind the query to concrete key and value types
+    pub fn compile<K, V>(&self) -> CompiledQuery<K, V>
+    where
+        K: QueryField + 'static,
+        V: QueryField + 'static,
+    {
+        let filter = self.filter.clone();
+        let predicate: Predicate<K, V> =
+            Arc::new(move |k: &K, v: &V| filter.matches(k.field_value(), v.field_value()));
+
+        CompiledQuery {
+            query: Arc::new(self.clone()),
+            predicate,
+            compare: Arc::new(|field, direction, a: &(K, V), b: &(K, V)| match field {
+                Field::Key => a.0.field_value().sort_cmp(&b.0.field_value(), direction),
+                Field::Value => a.1.field_value().sort_cmp(&b.1.field_value(), direction),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.filter)?;
+        if let Some((field, direction)) = self.order {
+            let direction = match direction {
+                Direction::Asc => "asc",
+                Direction::Desc => "desc",
+            };
+            write!(f, " order by {} {}", field, direction)?;
+        }
+        if let Some(limit) = self.limit {
+            write!(f, " limit {}", limit)?;
+        }
+        Ok(())
+    }
+}
+
+// Orders two entries by one field, without copying either
+type CompareFn<K, V> = Arc<dyn Fn(Field, Direction, &(K, V), &(K, V)) -> Ordering + Send + Sync>;
+
+/// A query bound to concrete key and value types, ready to run
+pub struct CompiledQuery<K, V> {
+    // Shared, so clones and operations carrying the query stay small
+    query: Arc<Query>,
+    predicate: Predicate<K, V>,
+    compare: CompareFn<K, V>,
+}
+
+impl<K, V> Clone for CompiledQuery<K, V> {
+    fn clone(&self) -> Self {
+        CompiledQuery {
+            query: Arc::clone(&self.query),
+            predicate: Arc::clone(&self.predicate),
+            compare: Arc::clone(&self.compare),
+        }
+    }
+}
+
+impl<K, V> CompiledQuery<K, V> {
+    /// The query this was compiled from
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+}
+
+impl<K, V> CompiledQuery<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// The filter as a predicate usable with `MyData::find` or `Operation::Find`
+    pub fn predicate(&self) -> Predicate<K, V> {
+        Arc::clone(&self.predicate)
+    }
+
+    /// Apply ordering and limit to entries that already passed the filter
+    fn finish(&self, results: &mut Vec<(K, V)>) {
+        if let Some((field, direction)) = self.query.order {
+            results.sort_by(|a, b| (self.compare)(field, direction, a, b));
+        }
+        if let Some(limit) = self.query.limit {
+            results.truncate(limit);
+        }
+    }
+
+    /// Evaluate the query against every segment in parallel using Rayon
+    pub fn run(&self, data: &MyData<K, V>) -> Vec<(K, V)> {
+        self.run_cancellable(data, &OpContext::new())
+            .expect("a default context never cancels")
+    }
+
+    /// Like `run`, but every segment gives up once `ctx` is cancelled or expired
+    pub fn run_cancellable(&self, data: &MyData<K, V>, ctx: &OpContext) -> OpResult<Vec<(K, V)>> {
+        let per_segment: Vec<Vec<(K, V)>> = (0..data.num_segments())
+            .into_par_iter()
+            .map(|idx| {
+                let mut matches = Vec::new();
+                if let Some(segment) = data.get_segment(idx) {
+                    ctx.check()?;
+                    for (visited, entry) in segment.iter().enumerate() {
+                        if visited % CANCEL_CHECK_INTERVAL == 0 {
+                            ctx.check()?;
+                        }
+                        if (self.predicate)(entry.key(), entry.value()) {
+                            matches.push((entry.key().clone(), entry.value().clone()));
+                        }
+                    }
+                }
+                // Each segment only needs to contribute its own top `limit` entries
+                self.finish(&mut matches);
+                Ok(matches)
+            })
+            .collect::<OpResult<_>>()?;
+
+        let mut results: Vec<(K, V)> = per_segment.into_iter().flatten().collect();
+        self.finish(&mut results);
+        Ok(results)
+    }
+}
+
+/// Error produced when query text cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset into the query text
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Match `text` against a glob where `*` is any run of characters and `?` is one character
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character and try again
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn write_quoted(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            _ => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Word(String),
+    Str(String),
+    Number(Literal),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Word(w) => write!(f, "'{}'", w),
+            TokenKind::Str(s) => write!(f, "string \"{}\"", s),
+            TokenKind::Number(n) => write!(f, "number {}", n),
+            TokenKind::Symbol(s) => write!(f, "'{}'", s),
+        }
+    }
+}
+
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ParseError> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let (position, c) = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // Two-character operators first so `<=` is not read as `<` then `=`
+        let next = chars.get(i + 1).map(|&(_, c)| c);
+        let symbol = match (c, next) {
+            ('<', Some('=')) => Some(("<=", 2)),
+            ('>', Some('=')) => Some((">=", 2)),
+            ('!', Some('=')) => Some(("!=", 2)),
+            ('<', _) => Some(("<", 1)),
+            ('>', _) => Some((">", 1)),
+            ('=', _) => Some(("=", 1)),
+            ('~', _) => Some(("~", 1)),
+            ('(', _) => Some(("(", 1)),
+            (')', _) => Some((")", 1)),
+            _ => None,
+        };
+        if let Some((symbol, width)) = symbol {
+            tokens.push(Token {
+                kind: TokenKind::Symbol(symbol),
+                position,
+            });
+            i += width;
+            continue;
+        }
+
+        if c == '"' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => {
+                        return Err(ParseError {
+                            position,
+                            message: "unterminated string".to_string(),
+                        })
+                    }
+                    Some(&(_, '"')) => break,
+                    Some(&(_, '\\')) => {
+                        if let Some(&(_, escaped)) = chars.get(i + 1) {
+                            value.push(escaped);
+                        }
+                        i += 2;
+                    }
+                    Some(&(_, other)) => {
+                        value.push(other);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(Token {
+                kind: TokenKind::Str(value),
+                position,
+            });
+            i += 1;
+            continue;
+        }
+
+        if c == '-' && word_at(&chars, i + 1).eq_ignore_ascii_case("inf") {
+            tokens.push(Token {
+                kind: TokenKind::Number(Literal::Float(f64::NEG_INFINITY)),
+                position,
+            });
+            i += 4;
+            continue;
+        }
+
+        if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].1.is_ascii_digit() || chars[i].1 == '.') {
+                i += 1;
+            }
+            // An exponent, as in `1e20` or `2.5E-7`
+            if matches!(chars.get(i), Some(&(_, 'e' | 'E'))) {
+                let digits = match chars.get(i + 1) {
+                    Some(&(_, '+' | '-')) => i + 2,
+                    _ => i + 1,
+                };
+                if chars.get(digits).is_some_and(|&(_, d)| d.is_ascii_digit()) {
+                    i = digits;
+                    while i < chars.len() && chars[i].1.is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let raw: String = chars[start..i].iter().map(|&(_, c)| c).collect();
+            let literal = if raw.contains(['.', 'e', 'E']) {
+                raw.parse().map(Literal::Float).ok()
+            } else {
+                raw.parse().map(Literal::Int).ok()
+            };
+            let literal = literal.ok_or_else(|| ParseError {
+                position,
+                message: format!("invalid number '{}'", raw),
+            })?;
+            tokens.push(Token {
+                kind: TokenKind::Number(literal),
+                position,
+            });
+            continue;
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].1.is_alphanumeric() || chars[i].1 == '_') {
+                i += 1;
+            }
+            let word = chars[start..i].iter().map(|&(_, c)| c).collect::<String>().to_ascii_lowercase();
+            let kind = match word.as_str() {
+                "inf" => TokenKind::Number(Literal::Float(f64::INFINITY)),
+                "nan" => TokenKind::Number(Literal::Float(f64::NAN)),
+                _ => TokenKind::Word(word),
+            };
+            tokens.push(Token { kind, position });
+            continue;
+        }
+
+        return Err(ParseError {
+            position,
+            message: format!("unexpected character '{}'", c),
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// The run of word characters starting at `start`
+fn word_at(chars: &[(usize, char)], start: usize) -> String {
+    chars
+        .iter()
+        .skip(start)
+        .take_while(|&&(_, c)| c.is_alphanumeric() || c == '_')
+        .map(|&(_, c)| c)
+        .collect()
+}
+
+/// Recursive descent parser over the token stream
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn error(&self, message: String) -> ParseError {
+        let position = self
+            .peek()
+            .map(|t| t.position)
+            .or_else(|| self.tokens.last().map(|t| t.position + 1))
+            .unwrap_or(0);
+        ParseError { position, message }
+    }
+
+    fn peek_kind(&self) -> Option<TokenKind> {
+        self.peek().map(|t| t.kind.clone())
+    }
+
+    fn at_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token { kind: TokenKind::Word(w), .. }) if w == word)
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.at_word(word) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(format!("expected '{}'", word)))
+        }
+    }
+
+    fn query(&mut self) -> Result<Query, ParseError> {
+        let filter = if self.peek().is_none() || self.at_word("order") || self.at_word("limit") {
+            Expr::True
+        } else {
+            self.or_expr()?
+        };
+
+        let mut order = None;
+        if self.at_word("order") {
+            self.pos += 1;
+            self.expect_word("by")?;
+            let field = self.field()?;
+            let direction = if self.at_word("desc") {
+                self.pos += 1;
+                Direction::Desc
+            } else {
+                if self.at_word("asc") {
+                    self.pos += 1;
+                }
+                Direction::Asc
+            };
+            order = Some((field, direction));
+        }
+
+        let mut limit = None;
+        if self.at_word("limit") {
+            self.pos += 1;
+            match self.peek_kind() {
+                Some(TokenKind::Number(Literal::Int(n))) if usize::try_from(n).is_ok() => {
+                    self.pos += 1;
+                    limit = usize::try_from(n).ok();
+                }
+                _ => return Err(self.error("expected a non-negative limit".to_string())),
+            }
+        }
+
+        Ok(Query { filter, order, limit })
+    }
+
+    fn or_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.and_expr()?;
+        while self.at_word("or") {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and_expr()?));
+        }
+        Ok(expr)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.unary()?;
+        while self.at_word("and") {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.at_word("not") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.at_word("true") {
+            self.pos += 1;
+            return Ok(Expr::True);
+        }
+        if matches!(self.peek(), Some(Token { kind: TokenKind::Symbol("("), .. })) {
+            self.pos += 1;
+            let expr = self.or_expr()?;
+            return match self.peek_kind() {
+                Some(TokenKind::Symbol(")")) => {
+                    self.pos += 1;
+                    Ok(expr)
+                }
+                _ => Err(self.error("expected ')'".to_string())),
+            };
+        }
+        self.condition()
+    }
+
+    fn field(&mut self) -> Result<Field, ParseError> {
+        if self.at_word("key") {
+            self.pos += 1;
+            Ok(Field::Key)
+        } else if self.at_word("value") {
+            self.pos += 1;
+            Ok(Field::Value)
+        } else {
+            Err(self.error("expected 'key' or 'value'".to_string()))
+        }
+    }
+
+    fn condition(&mut self) -> Result<Expr, ParseError> {
+        let field = self.field()?;
+        let op = match self.peek_kind() {
+            Some(TokenKind::Symbol("~")) if field == Field::Key => {
+                self.pos += 1;
+                return match self.peek_kind() {
+                    Some(TokenKind::Str(pattern)) => {
+                        self.pos += 1;
+                        Ok(Expr::KeyGlob(pattern))
+                    }
+                    _ => Err(self.error("expected a quoted glob pattern".to_string())),
+                };
+            }
+            Some(TokenKind::Symbol("=")) => CmpOp::Eq,
+            Some(TokenKind::Symbol("!=")) => CmpOp::Ne,
+            Some(TokenKind::Symbol("<")) => CmpOp::Lt,
+            Some(TokenKind::Symbol("<=")) => CmpOp::Le,
+            Some(TokenKind::Symbol(">")) => CmpOp::Gt,
+            Some(TokenKind::Symbol(">=")) => CmpOp::Ge,
+            _ => return Err(self.error("expected a comparison operator".to_string())),
+        };
+        self.pos += 1;
+        let literal = match self.peek_kind() {
+            Some(TokenKind::Number(literal)) => literal,
+            Some(TokenKind::Str(s)) => Literal::Str(s),
+            _ => return Err(self.error("expected a number or string".to_string())),
+        };
+        self.pos += 1;
+        Ok(Expr::Compare(field, op, literal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store<V: Clone + Send + Sync + 'static>(entries: &[(u64, V)]) -> MyData<u64, V> {
+        let data = MyData::new(0, 4);
+        for (key, value) in entries {
+            data.insert(*key, value.clone());
+        }
+        data
+    }
+
+    fn keys<V>(mut results: Vec<(u64, V)>) -> Vec<u64> {
+        results.sort_by_key(|(k, _)| *k);
+        results.into_iter().map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn large_unsigned_values_compare_exactly() {
+        let data = store(&[(1, u64::MAX), (2, 1u64 << 63), (3, 5)]);
+        let query = Query::parse("value > 9223372036854775807").unwrap();
+        assert_eq!(keys(query.compile().run(&data)), vec![1, 2]);
+
+        let query = Query::parse("value = 18446744073709551615").unwrap();
+        assert_eq!(keys(query.compile().run(&data)), vec![1]);
+
+        let query = Query::parse("order by value desc limit 2").unwrap();
+        assert_eq!(query.compile().run(&data), vec![(1, u64::MAX), (2, 1u64 << 63)]);
+    }
+
+    #[test]
+    fn order_by_a_field_with_nan_sorts_it_last() {
+        let entries: Vec<(u64, f64)> = (0..200)
+            .map(|k| (k, if k % 7 == 0 { f64::NAN } else { (k * 37 % 101) as f64 - 50.0 }))
+            .collect();
+        let data = store(&entries);
+        let nans = entries.iter().filter(|(_, v)| v.is_nan()).count();
+
+        for direction in ["asc", "desc"] {
+            let query = Query::parse(&format!("order by value {}", direction)).unwrap();
+            let results = query.compile().run(&data);
+            let (numbers, tail) = results.split_at(results.len() - nans);
+            assert!(tail.iter().all(|(_, v)| v.is_nan()), "{}", direction);
+            let values: Vec<f64> = numbers.iter().map(|(_, v)| *v).collect();
+            let mut sorted = values.clone();
+            sorted.sort_by(f64::total_cmp);
+            if direction == "desc" {
+                sorted.reverse();
+            }
+            assert_eq!(values, sorted);
+        }
+
+        let query = Query::parse("order by value desc limit 3").unwrap();
+        assert!(query.compile().run(&data).iter().all(|(_, v)| !v.is_nan()));
+    }
+
+    #[test]
+    fn float_literals_round_trip_through_text() {
+        for x in [0.5, -2.0, 1e20, 1.5e-7, -3e300, f64::INFINITY, f64::NEG_INFINITY, f64::MAX, f64::MIN_POSITIVE] {
+            let query = Query {
+                filter: Expr::Compare(Field::Value, CmpOp::Lt, Literal::Float(x)),
+                order: None,
+                limit: None,
+            };
+            assert_eq!(Query::parse(&query.to_string()), Ok(query), "{:?}", x);
+        }
+
+        let text = Query {
+            filter: Expr::Compare(Field::Value, CmpOp::Ne, Literal::Float(f64::NAN)),
+            order: None,
+            limit: None,
+        }
+        .to_string();
+        match Query::parse(&text).unwrap().filter {
+            Expr::Compare(Field::Value, CmpOp::Ne, Literal::Float(x)) => assert!(x.is_nan()),
+            other => panic!("{} parsed as {:?}", text, other),
+        }
+    }
+
+    #[test]
+    fn float_values_compare_against_exponent_literals() {
+        let data = store(&[(1, 1e21f64), (2, 1e19), (3, f64::INFINITY)]);
+        let query = Query::parse("value > 1e20 and value < inf").unwrap();
+        assert_eq!(keys(query.compile().run(&data)), vec![1]);
+    }
+
+    #[test]
+    fn string_queries_match_without_copying_the_entries() {
+        let data = MyData::new(0, 4);
+        for (key, value) in [("user-1", 3), ("user-2", 1), ("admin", 2)] {
+            data.insert(key.to_string(), value as u64);
+        }
+        let query = Query::parse("key ~ \"user-*\" order by key desc").unwrap();
+        let results = query.compile().run(&data);
+        assert_eq!(results, vec![("user-2".to_string(), 1), ("user-1".to_string(), 3)]);
+    }
+}
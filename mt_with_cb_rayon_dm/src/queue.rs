@@ -0,0 +1,589 @@
+use crossbeam::channel::{RecvError, RecvTimeoutError, TryRecvError};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::counter::StripedCounter;
+
+/// What a producer should do when a bounded worker queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the worker makes room
+    Block,
+    /// Wait up to the given duration, then give the item back
+    BlockTimeout(Duration),
+    /// Give the item back immediately
+    Reject,
+    /// Discard the oldest evictable item to make room for the new one.
+    /// Needs a capacity of at least one.
+    DropOldest,
+}
+
+/// Configuration for a worker queue
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// Maximum number of queued items, `None` for an unbounded queue
+    pub capacity: Option<usize>,
+    /// Behaviour when the queue is full (ignored for unbounded queues)
+    pub overflow: OverflowPolicy,
+}
+
+impl QueueConfig {
+    /// A queue that never applies backpressure
+    pub fn unbounded() -> Self {
+        QueueConfig {
+            capacity: None,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+
+    /// A queue holding at most `capacity` items
+    pub fn bounded(capacity: usize, overflow: OverflowPolicy) -> Self {
+        QueueConfig {
+            capacity: Some(capacity),
+            overflow,
+        }
+    }
+}
+
+/// Items that a `DropOldest` queue may discard to make room
+pub trait Evictable {
+    /// Whether this item may be dropped without ever being processed.
+    /// Control items and requests whose caller waits for a reply must say no.
+    fn evictable(&self) -> bool;
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig::bounded(1024, OverflowPolicy::Block)
+    }
+}
+
+/// Error returned when an item could not be enqueued.
+/// The rejected item is handed back so the producer can retry or shed it.
+pub enum SendError<T> {
+    /// The queue was full and the policy is `Reject`
+    Full(T),
+    /// The queue stayed full for the whole `BlockTimeout` duration
+    Timeout(T),
+    /// The worker has gone away
+    Disconnected(T),
+}
+
+impl<T> SendError<T> {
+    /// Take back the item that could not be sent
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::Full(item) | SendError::Timeout(item) | SendError::Disconnected(item) => item,
+        }
+    }
+}
+
+// Written by hand so that `T` does not need to implement Debug (operations hold closures)
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full(_) => f.write_str("Full(..)"),
+            SendError::Timeout(_) => f.write_str("Timeout(..)"),
+            SendError::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full(_) => f.write_str("worker queue is full"),
+            SendError::Timeout(_) => f.write_str("timed out waiting for room in worker queue"),
+            SendError::Disconnected(_) => f.write_str("worker queue is disconnected"),
+        }
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+/// Counters shared by every clone of a `WorkerSender`. Totals are striped
+/// so parallel producers don't contend on them; maxima stay single atomics.
+#[derive(Default)]
+struct QueueMetrics {
+    enqueued: StripedCounter,
+    rejected: StripedCounter,
+    timed_out: StripedCounter,
+    dropped: StripedCounter,
+    max_depth: AtomicUsize,
+    wait_nanos: StripedCounter,
+    max_wait_nanos: AtomicU64,
+}
+
+/// A point-in-time snapshot of queue metrics
+#[derive(Debug, Clone, Copy)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub capacity: Option<usize>,
+    pub enqueued: usize,
+    pub rejected: usize,
+    pub timed_out: usize,
+    pub dropped: usize,
+    pub max_depth: usize,
+    /// Total time producers spent waiting for room
+    pub total_wait: Duration,
+    /// Longest single wait for room
+    pub max_wait: Duration,
+}
+
+/// A condition variable with its own lock, for waiting on state that lives
+/// behind other locks. Checks done while holding the bell's lock cannot miss
+/// a ring, because ringing takes the same lock.
+#[derive(Default)]
+struct Bell {
+    lock: Mutex<()>,
+    rung: Condvar,
+}
+
+impl Bell {
+    fn ring(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.rung.notify_all();
+    }
+
+    /// Call `ready` until it returns true, waiting for a ring in between.
+    /// Returns false if `deadline` passed first.
+    fn wait_until(&self, deadline: Option<Instant>, mut ready: impl FnMut() -> bool) -> bool {
+        let mut guard = self.lock.lock().unwrap();
+        loop {
+            if ready() {
+                return true;
+            }
+            guard = match deadline {
+                None => self.rung.wait(guard).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.rung.wait_timeout(guard, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+/// Wakes receivers when an item arrives or the last sender goes away.
+/// The lanes of a priority channel share one, so a single worker can wait on
+/// all of them at once.
+struct Arrivals {
+    bell: Bell,
+    // Receivers blocked waiting, each of which lets a zero-capacity queue take one item
+    waiting: AtomicUsize,
+    // Room bells of every queue using this, rung when a receiver starts waiting
+    rooms: Vec<Arc<Bell>>,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receivers: usize,
+}
+
+/// The queue behind one `WorkerSender`/`WorkerReceiver` pair
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    // Rung when an item leaves the queue, a receiver starts waiting, or the
+    // last receiver goes away
+    room: Arc<Bell>,
+    arrivals: Arc<Arrivals>,
+    config: QueueConfig,
+    metrics: QueueMetrics,
+}
+
+impl<T> Shared<T> {
+    fn has_room(&self, state: &State<T>) -> bool {
+        match self.config.capacity {
+            // Items are only handed straight to a waiting receiver
+            Some(0) => state.items.len() < self.arrivals.waiting.load(Ordering::SeqCst),
+            Some(capacity) => state.items.len() < capacity,
+            None => true,
+        }
+    }
+}
+
+/// Sending half of a worker queue that applies the configured overflow policy
+pub struct WorkerSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receiving half of a worker queue. The queue disconnects once every
+/// sender or every receiver is gone.
+pub struct WorkerReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for WorkerSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        WorkerSender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for WorkerSender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            // Let waiting receivers see the disconnect
+            self.shared.arrivals.bell.ring();
+        }
+    }
+}
+
+impl<T> Clone for WorkerReceiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().receivers += 1;
+        WorkerReceiver {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for WorkerReceiver<T> {
+    fn drop(&mut self) {
+        let orphaned = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.receivers -= 1;
+            if state.receivers > 0 {
+                return;
+            }
+            std::mem::take(&mut state.items)
+        };
+        // Nobody will process these; dropping them lets callers waiting on a
+        // reply see the disconnect. Blocked senders give up as well.
+        drop(orphaned);
+        self.shared.room.ring();
+    }
+}
+
+/// Create a worker queue with the given configuration
+///
+/// # Panics
+///
+/// If the policy is `DropOldest` and the capacity is zero, since there would
+/// never be anything to drop.
+pub fn worker_channel<T>(config: QueueConfig) -> (WorkerSender<T>, WorkerReceiver<T>) {
+    let ([sender], [receiver]) = worker_channels(config);
+    (sender, receiver)
+}
+
+/// Create `N` worker queues with the same configuration whose receivers can
+/// be waited on together with `WorkerReceiver::wait_until`
+pub(crate) fn worker_channels<T, const N: usize>(config: QueueConfig) -> ([WorkerSender<T>; N], [WorkerReceiver<T>; N]) {
+    assert!(
+        !(config.overflow == OverflowPolicy::DropOldest && config.capacity == Some(0)),
+        "DropOldest needs a capacity of at least one"
+    );
+
+    let rooms: [Arc<Bell>; N] = std::array::from_fn(|_| Arc::new(Bell::default()));
+    let arrivals = Arc::new(Arrivals {
+        bell: Bell::default(),
+        waiting: AtomicUsize::new(0),
+        rooms: rooms.to_vec(),
+    });
+    let shared: [Arc<Shared<T>>; N] = rooms.map(|room| {
+        Arc::new(Shared {
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                senders: 1,
+                receivers: 1,
+            }),
+            room,
+            arrivals: Arc::clone(&arrivals),
+            config,
+            metrics: QueueMetrics::default(),
+        })
+    });
+
+    let senders = std::array::from_fn(|idx| WorkerSender {
+        shared: Arc::clone(&shared[idx]),
+    });
+    (senders, shared.map(|shared| WorkerReceiver { shared }))
+}
+
+impl<T: Evictable> WorkerSender<T> {
+    /// Enqueue an item, applying the overflow policy if the queue is full
+    pub fn send(&self, item: T) -> Result<(), SendError<T>> {
+        let started = Instant::now();
+        let result = self.push(item);
+        self.record_wait(started.elapsed());
+
+        let depth = result?;
+        self.shared.metrics.enqueued.increment();
+        self.shared.metrics.max_depth.fetch_max(depth, Ordering::Relaxed);
+        self.shared.arrivals.bell.ring();
+        Ok(())
+    }
+
+    /// Add `item` to the back of the queue once the policy allows it.
+    /// Returns the depth right after it was added.
+    fn push(&self, item: T) -> Result<usize, SendError<T>> {
+        let shared = &*self.shared;
+        let metrics = &shared.metrics;
+        let deadline = match shared.config.overflow {
+            OverflowPolicy::BlockTimeout(timeout) => Some(Instant::now() + timeout),
+            _ => None,
+        };
+
+        let mut item = Some(item);
+        let mut outcome = None;
+        let mut evicted = Vec::new();
+        let arrived = shared.room.wait_until(deadline, || {
+            let mut state = shared.state.lock().unwrap();
+            if state.receivers == 0 {
+                outcome = Some(Err(SendError::Disconnected(item.take().unwrap())));
+                return true;
+            }
+            while !shared.has_room(&state) {
+                match shared.config.overflow {
+                    OverflowPolicy::Block | OverflowPolicy::BlockTimeout(_) => return false,
+                    OverflowPolicy::Reject => {
+                        metrics.rejected.increment();
+                        outcome = Some(Err(SendError::Full(item.take().unwrap())));
+                        return true;
+                    }
+                    OverflowPolicy::DropOldest => match state.items.iter().position(T::evictable) {
+                        Some(idx) => {
+                            evicted.extend(state.items.remove(idx));
+                            metrics.dropped.increment();
+                        }
+                        // Everything queued has to reach the worker, so there is
+                        // nothing we may drop
+                        None => {
+                            metrics.rejected.increment();
+                            outcome = Some(Err(SendError::Full(item.take().unwrap())));
+                            return true;
+                        }
+                    },
+                }
+            }
+            state.items.push_back(item.take().unwrap());
+            outcome = Some(Ok(state.items.len()));
+            true
+        });
+
+        if !arrived {
+            metrics.timed_out.increment();
+            return Err(SendError::Timeout(item.take().unwrap()));
+        }
+        outcome.unwrap()
+    }
+}
+
+impl<T> WorkerSender<T> {
+    fn record_wait(&self, waited: Duration) {
+        let nanos = waited.as_nanos().min(u64::MAX as u128) as u64;
+        self.shared.metrics.wait_nanos.add(nanos as usize);
+        self.shared.metrics.max_wait_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Number of items currently waiting in the queue
+    pub fn depth(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+
+    /// Maximum number of queued items, `None` if unbounded
+    pub fn capacity(&self) -> Option<usize> {
+        self.shared.config.capacity
+    }
+
+    /// Fraction of the queue currently in use (0.0 to 1.0).
+    /// Always 0.0 for unbounded queues.
+    pub fn load(&self) -> f64 {
+        match self.shared.config.capacity {
+            Some(0) => 1.0,
+            Some(capacity) => self.depth() as f64 / capacity as f64,
+            None => 0.0,
+        }
+    }
+
+    /// Whether producers should shed work given a load threshold (0.0 to 1.0)
+    pub fn is_overloaded(&self, threshold: f64) -> bool {
+        self.load() >= threshold
+    }
+
+    /// Take a snapshot of the queue metrics
+    pub fn stats(&self) -> QueueStats {
+        let metrics = &self.shared.metrics;
+        QueueStats {
+            depth: self.depth(),
+            capacity: self.shared.config.capacity,
+            enqueued: metrics.enqueued.sum(),
+            rejected: metrics.rejected.sum(),
+            timed_out: metrics.timed_out.sum(),
+            dropped: metrics.dropped.sum(),
+            max_depth: metrics.max_depth.load(Ordering::Relaxed),
+            total_wait: Duration::from_nanos(metrics.wait_nanos.sum() as u64),
+            max_wait: Duration::from_nanos(metrics.max_wait_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl<T> WorkerReceiver<T> {
+    /// Take the next item if one is immediately available
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        match state.items.pop_front() {
+            Some(item) => {
+                drop(state);
+                self.shared.room.ring();
+                Ok(item)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Wait for the next item
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.recv_deadline(None).map_err(|_| RecvError)
+    }
+
+    /// Wait up to `timeout` for the next item
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.recv_deadline(Some(Instant::now() + timeout))
+    }
+
+    fn recv_deadline(&self, deadline: Option<Instant>) -> Result<T, RecvTimeoutError> {
+        let mut outcome = Err(RecvTimeoutError::Timeout);
+        self.wait_until(deadline, || match self.try_recv() {
+            Ok(item) => {
+                outcome = Ok(item);
+                true
+            }
+            Err(TryRecvError::Disconnected) => {
+                outcome = Err(RecvTimeoutError::Disconnected);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+        });
+        outcome
+    }
+
+    /// Call `ready` until it returns true, waiting in between until an item
+    /// arrives in this queue or any queue created alongside it by
+    /// `worker_channels`. Returns false if `deadline` passed first.
+    pub(crate) fn wait_until(&self, deadline: Option<Instant>, mut ready: impl FnMut() -> bool) -> bool {
+        let arrivals = &*self.shared.arrivals;
+        let mut waiting = false;
+        let arrived = arrivals.bell.wait_until(deadline, || {
+            if ready() {
+                return true;
+            }
+            if !waiting {
+                // A zero-capacity queue accepts an item only while a receiver waits
+                waiting = true;
+                arrivals.waiting.fetch_add(1, Ordering::SeqCst);
+                arrivals.rooms.iter().for_each(|room| room.ring());
+            }
+            false
+        });
+        if waiting {
+            arrivals.waiting.fetch_sub(1, Ordering::SeqCst);
+        }
+        arrived
+    }
+
+}
+
+/// Owning iterator over the items of a `WorkerReceiver`
+pub struct IntoIter<T> {
+    receiver: WorkerReceiver<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl<T> IntoIterator for WorkerReceiver<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter { receiver: self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Test item; odd numbers stand in for control items
+    #[derive(Debug, PartialEq)]
+    struct Item(u32);
+
+    impl Evictable for Item {
+        fn evictable(&self) -> bool {
+            self.0.is_multiple_of(2)
+        }
+    }
+
+    #[test]
+    fn blocked_sender_sees_the_worker_go_away() {
+        let (sender, receiver) = worker_channel(QueueConfig::bounded(1, OverflowPolicy::Block));
+        sender.send(Item(0)).unwrap();
+        let blocked = thread::spawn(move || sender.send(Item(2)));
+        thread::sleep(Duration::from_millis(20));
+        drop(receiver);
+        assert!(matches!(blocked.join().unwrap(), Err(SendError::Disconnected(Item(2)))));
+    }
+
+    #[test]
+    fn receiver_sees_the_last_sender_go_away() {
+        let (sender, receiver) = worker_channel::<Item>(QueueConfig::unbounded());
+        let extra = sender.clone();
+        drop(sender);
+        extra.send(Item(0)).unwrap();
+        drop(extra);
+        assert_eq!(receiver.into_iter().collect::<Vec<_>>(), vec![Item(0)]);
+    }
+
+    #[test]
+    fn drop_oldest_skips_items_that_must_be_delivered() {
+        let (sender, receiver) = worker_channel(QueueConfig::bounded(3, OverflowPolicy::DropOldest));
+        for n in [1, 2, 4, 6, 3] {
+            sender.send(Item(n)).unwrap();
+        }
+        assert_eq!(sender.stats().dropped, 2);
+        let queued: Vec<_> = (0..3).map(|_| receiver.try_recv().unwrap()).collect();
+        assert_eq!(queued, vec![Item(1), Item(6), Item(3)]);
+
+        // With nothing evictable left the new item is handed back
+        for n in [5, 7, 9] {
+            sender.send(Item(n)).unwrap();
+        }
+        assert!(matches!(sender.send(Item(8)), Err(SendError::Full(Item(8)))));
+    }
+
+    #[test]
+    #[should_panic(expected = "DropOldest needs a capacity")]
+    fn drop_oldest_needs_room() {
+        let _ = worker_channel::<Item>(QueueConfig::bounded(0, OverflowPolicy::DropOldest));
+    }
+
+    #[test]
+    fn zero_capacity_hands_items_to_a_waiting_receiver() {
+        let (sender, receiver) = worker_channel(QueueConfig::bounded(0, OverflowPolicy::Block));
+        let worker = thread::spawn(move || receiver.recv_timeout(Duration::from_secs(5)));
+        sender.send(Item(0)).unwrap();
+        assert_eq!(worker.join().unwrap(), Ok(Item(0)));
+    }
+}
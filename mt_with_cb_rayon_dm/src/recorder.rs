@@ -0,0 +1,559 @@
+use crossbeam::channel::{self, RecvTimeoutError};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::codec::{read_bytes, read_varint, write_varint, Codec, StableHasher};
+use crate::data_structures::MyData;
+use crate::queue::WorkerSender;
+use crate::worker_utils::{Operation, OperationSource};
+
+// Identifies a recording file and its format version.
+// Version 2 switched checkpoints to the stable state hash.
+const MAGIC: &[u8; 4] = b"MDRC";
+const FORMAT_VERSION: u8 = 2;
+
+// Record tags
+const TAG_INSERT: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+const TAG_GET: u8 = 3;
+const TAG_FIND: u8 = 4;
+const TAG_QUERY: u8 = 5;
+const TAG_CLEAR: u8 = 6;
+const TAG_SHUTDOWN: u8 = 7;
+const TAG_CHECKPOINT: u8 = 8;
+const TAG_REPLACE_ALL: u8 = 9;
+
+/// Tees operations and state checkpoints to a compact binary file
+pub struct OperationRecorder {
+    writer: Mutex<BufWriter<File>>,
+    started: Instant,
+    records: AtomicUsize,
+    // Records that could not be written. Nothing is written after the first
+    // failure, so the file keeps the records that went before it.
+    failed: AtomicUsize,
+}
+
+impl OperationRecorder {
+    /// Create (or truncate) a recording file
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[FORMAT_VERSION])?;
+
+        Ok(OperationRecorder {
+            writer: Mutex::new(writer),
+            started: Instant::now(),
+            records: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+        })
+    }
+
+    /// Record an operation as received by the calling thread.
+    /// Callers going through a worker should `settle` the operation first.
+    pub fn record<K: Codec, V: Codec>(&self, operation: &Operation<K, V>) -> io::Result<()> {
+        let mut payload = Vec::new();
+        let tag = match operation {
+            Operation::Insert(key, value) => {
+                key.encode(&mut payload);
+                value.encode(&mut payload);
+                TAG_INSERT
+            }
+            Operation::Remove(key) => {
+                key.encode(&mut payload);
+                TAG_REMOVE
+            }
+            Operation::Get(key, _) => {
+                key.encode(&mut payload);
+                TAG_GET
+            }
+            // Closures cannot be serialised, so only the fact that a scan happened is kept
+            Operation::Find(..) => TAG_FIND,
+            Operation::Query(query, _) => {
+                query.query().to_string().encode(&mut payload);
+                TAG_QUERY
+            }
+            Operation::Clear => TAG_CLEAR,
+            Operation::ReplaceAll(entries) => {
+                write_varint(&mut payload, entries.len() as u64);
+                for (key, value) in entries {
+                    key.encode(&mut payload);
+                    value.encode(&mut payload);
+                }
+                TAG_REPLACE_ALL
+            }
+            Operation::Shutdown => TAG_SHUTDOWN,
+            // Deadlines are relative to the original run, so record the bare operation
+            Operation::WithContext(_, inner) => return self.record(inner),
+        };
+        self.write_record(tag, &payload)
+    }
+
+    /// Record the current state hash of `data` so a replay can be verified against it
+    pub fn checkpoint<K, V>(&self, data: &MyData<K, V>) -> io::Result<()>
+    where
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+        V: Hash + Clone + Send + Sync + 'static,
+    {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&data.state_hash().to_le_bytes());
+        self.write_record(TAG_CHECKPOINT, &payload)
+    }
+
+    fn write_record(&self, tag: u8, payload: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(payload.len() + 16);
+        record.push(tag);
+        write_varint(&mut record, self.started.elapsed().as_micros() as u64);
+        write_varint(&mut record, current_thread_tag());
+        write_varint(&mut record, payload.len() as u64);
+        record.extend_from_slice(payload);
+
+        let mut writer = self.writer.lock().unwrap();
+        if self.failed.load(Ordering::Relaxed) > 0 {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::other("recording stopped after a write error"));
+        }
+        if let Err(e) = writer.write_all(&record) {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        self.records.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Number of records written so far
+    pub fn records(&self) -> usize {
+        self.records.load(Ordering::Relaxed)
+    }
+
+    /// Number of records lost to write errors. `RecordingSource` cannot
+    /// return these to the worker, so this is where they show up.
+    pub fn failed_records(&self) -> usize {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Flush buffered records to disk
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+/// A stable number identifying the current thread within this process
+fn current_thread_tag() -> u64 {
+    let mut hasher = StableHasher::default();
+    thread::current().id().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Operation source that records everything it hands to the worker
+pub struct RecordingSource<S> {
+    inner: S,
+    recorder: Arc<OperationRecorder>,
+}
+
+impl<S> RecordingSource<S> {
+    pub fn new(inner: S, recorder: Arc<OperationRecorder>) -> Self {
+        RecordingSource { inner, recorder }
+    }
+}
+
+impl<K, V, S> OperationSource<K, V> for RecordingSource<S>
+where
+    K: Codec + Send + 'static,
+    V: Codec + Send + 'static,
+    S: OperationSource<K, V>,
+{
+    fn recv_timeout(&self, timeout: Duration) -> Result<Operation<K, V>, RecvTimeoutError> {
+        let (operation, runs) = settle(self.inner.recv_timeout(timeout)?);
+        if runs {
+            // A failing disk must not take the worker down with it; the
+            // recorder counts the loss in `failed_records`
+            let _ = self.recorder.record(&operation);
+        }
+        Ok(operation)
+    }
+
+    fn try_recv(&self) -> Option<Operation<K, V>> {
+        let (operation, runs) = settle(self.inner.try_recv()?);
+        if runs {
+            let _ = self.recorder.record(&operation);
+        }
+        Some(operation)
+    }
+}
+
+/// Decide now whether the worker will run a context-wrapped operation, so the
+/// recording holds what happened rather than what was asked for. Expired or
+/// cancelled operations stay wrapped for the worker to reject and are not
+/// recorded. Writes that pass are unwrapped, so they can no longer expire
+/// between here and the worker; reads keep their context, since only their
+/// shape is recorded and they never change the state. A wrapped `Shutdown`
+/// always runs and is recorded bare.
+fn settle<K, V>(operation: Operation<K, V>) -> (Operation<K, V>, bool) {
+    // The worker stops on a shutdown whatever context wraps it
+    if operation.is_shutdown() {
+        return (Operation::Shutdown, true);
+    }
+    let Operation::WithContext(ctx, inner) = operation else {
+        return (operation, true);
+    };
+    if ctx.check().is_err() {
+        return (Operation::WithContext(ctx, inner), false);
+    }
+    match settle(*inner) {
+        (inner @ (Operation::Get(..) | Operation::Find(..) | Operation::Query(..) | Operation::WithContext(..)), runs) => {
+            (Operation::WithContext(ctx, Box::new(inner)), runs)
+        }
+        (inner, runs) => (inner, runs),
+    }
+}
+
+/// One operation read back from a recording
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordedOp<K, V> {
+    Insert(K, V),
+    Remove(K),
+    Get(K),
+    /// A predicate scan; the predicate itself was not recordable
+    Find,
+    /// A query in its textual form
+    Query(String),
+    Clear,
+    ReplaceAll(Vec<(K, V)>),
+    Shutdown,
+    /// State hash of the store when the checkpoint was taken
+    Checkpoint(u64),
+}
+
+/// A recorded operation with its timing and the thread that received it
+#[derive(Debug, Clone)]
+pub struct Record<K, V> {
+    /// Time since the recording started
+    pub at: Duration,
+    pub thread: u64,
+    pub op: RecordedOp<K, V>,
+}
+
+/// How fast to replay a recording
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Ignore the recorded timing
+    Unthrottled,
+    /// Reproduce the original gaps between operations
+    Original,
+    /// Reproduce the original gaps, divided by the given factor, which must
+    /// be finite and greater than zero
+    Accelerated(f64),
+}
+
+/// Outcome of a replay
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    pub applied: usize,
+    /// Scans that could not be re-executed because only their shape was recorded
+    pub skipped: usize,
+    pub checkpoints_verified: usize,
+    /// `(record index, expected hash, actual hash)` for every failed checkpoint
+    pub mismatches: Vec<(usize, u64, u64)>,
+    pub final_hash: u64,
+    pub elapsed: Duration,
+}
+
+impl ReplayReport {
+    /// Whether every checkpoint matched
+    pub fn verified(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Reads a recording and feeds it back into a store or a worker
+#[derive(Clone)]
+pub struct Replayer<K, V> {
+    records: Vec<Record<K, V>>,
+    speed: ReplaySpeed,
+}
+
+impl<K, V> Replayer<K, V>
+where
+    K: Codec + Hash + Eq + Clone + Send + Sync + 'static,
+    V: Codec + Hash + Clone + Send + Sync + 'static,
+{
+    /// Load a recording file
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        if bytes.len() < 5 || &bytes[..4] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an operation recording"));
+        }
+        if bytes[4] != FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported recording version"));
+        }
+
+        let mut input = &bytes[5..];
+        let mut records = Vec::new();
+        while let Some((&tag, rest)) = input.split_first() {
+            input = rest;
+            let at = Duration::from_micros(read_varint(&mut input)?);
+            let thread = read_varint(&mut input)?;
+            let len = read_varint(&mut input)? as usize;
+            let mut payload = read_bytes(&mut input, len)?;
+
+            let op = match tag {
+                TAG_INSERT => RecordedOp::Insert(K::decode(&mut payload)?, V::decode(&mut payload)?),
+                TAG_REMOVE => RecordedOp::Remove(K::decode(&mut payload)?),
+                TAG_GET => RecordedOp::Get(K::decode(&mut payload)?),
+                TAG_FIND => RecordedOp::Find,
+                TAG_QUERY => RecordedOp::Query(String::decode(&mut payload)?),
+                TAG_CLEAR => RecordedOp::Clear,
+                TAG_REPLACE_ALL => {
+                    let len = read_varint(&mut payload)? as usize;
+                    let mut entries = Vec::with_capacity(len.min(1024));
+                    for _ in 0..len {
+                        entries.push((K::decode(&mut payload)?, V::decode(&mut payload)?));
+                    }
+                    RecordedOp::ReplaceAll(entries)
+                }
+                TAG_SHUTDOWN => RecordedOp::Shutdown,
+                TAG_CHECKPOINT => {
+                    let hash = read_bytes(&mut payload, 8)?;
+                    RecordedOp::Checkpoint(u64::from_le_bytes(hash.try_into().expect("read exactly 8 bytes")))
+                }
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown record tag {}", other),
+                    ))
+                }
+            };
+            records.push(Record { at, thread, op });
+        }
+
+        Ok(Replayer {
+            records,
+            speed: ReplaySpeed::Unthrottled,
+        })
+    }
+
+    /// Replay at `speed` instead of as fast as possible
+    pub fn with_speed(mut self, speed: ReplaySpeed) -> io::Result<Self> {
+        if let ReplaySpeed::Accelerated(factor) = speed {
+            if !(factor.is_finite() && factor > 0.0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("replay speed factor must be finite and positive, got {}", factor),
+                ));
+            }
+        }
+        self.speed = speed;
+        Ok(self)
+    }
+
+    /// The records in the order they were received
+    pub fn records(&self) -> &[Record<K, V>] {
+        &self.records
+    }
+
+    /// Sleep until `record` is due according to the replay speed
+    fn pace(&self, record: &Record<K, V>, started: Instant) {
+        let due = match self.speed {
+            ReplaySpeed::Unthrottled => return,
+            ReplaySpeed::Original => record.at,
+            // `with_speed` only accepts finite, positive factors
+            ReplaySpeed::Accelerated(factor) => record.at.div_f64(factor),
+        };
+        let elapsed = started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+
+    /// Apply the recording directly to `data`, verifying every checkpoint on the way
+    pub fn replay_into(&self, data: &MyData<K, V>) -> ReplayReport {
+        let started = Instant::now();
+        let mut report = ReplayReport::default();
+
+        for (idx, record) in self.records.iter().enumerate() {
+            self.pace(record, started);
+            match &record.op {
+                RecordedOp::Insert(key, value) => {
+                    data.insert(key.clone(), value.clone());
+                }
+                RecordedOp::Remove(key) => {
+                    data.remove(key);
+                }
+                RecordedOp::Get(key) => {
+                    let _ = data.get(key);
+                }
+                RecordedOp::Find | RecordedOp::Query(_) => {
+                    report.skipped += 1;
+                    continue;
+                }
+                RecordedOp::Clear => data.clear(),
+                RecordedOp::ReplaceAll(entries) => data.replace_all(entries.clone()),
+                // Stop where the original worker stopped
+                RecordedOp::Shutdown => break,
+                RecordedOp::Checkpoint(expected) => {
+                    let actual = data.state_hash();
+                    report.checkpoints_verified += 1;
+                    if actual != *expected {
+                        report.mismatches.push((idx, *expected, actual));
+                    }
+                    continue;
+                }
+            }
+            report.applied += 1;
+        }
+
+        report.final_hash = data.state_hash();
+        report.elapsed = started.elapsed();
+        report
+    }
+
+    /// Feed the recording into a running worker through its queue.
+    /// Only the final checkpoint can be verified, once the worker has drained.
+    pub fn replay_to_worker(&self, sender: &WorkerSender<Operation<K, V>>) -> ReplayReport {
+        let started = Instant::now();
+        let mut report = ReplayReport::default();
+
+        for record in &self.records {
+            self.pace(record, started);
+            let operation = match &record.op {
+                RecordedOp::Insert(key, value) => Operation::Insert(key.clone(), value.clone()),
+                RecordedOp::Remove(key) => Operation::Remove(key.clone()),
+                RecordedOp::Get(key) => {
+                    // Nobody is waiting for the answer, the request is only there for load
+                    let (reply, _) = channel::bounded(1);
+                    Operation::Get(key.clone(), reply)
+                }
+                RecordedOp::Clear => Operation::Clear,
+                RecordedOp::ReplaceAll(entries) => Operation::ReplaceAll(entries.clone()),
+                RecordedOp::Shutdown => {
+                    // The worker stops here, and so does the replay
+                    let _ = sender.send(Operation::Shutdown);
+                    break;
+                }
+                RecordedOp::Find | RecordedOp::Query(_) => {
+                    report.skipped += 1;
+                    continue;
+                }
+                RecordedOp::Checkpoint(_) => continue,
+            };
+            if sender.send(operation).is_err() {
+                break;
+            }
+            report.applied += 1;
+        }
+
+        report.elapsed = started.elapsed();
+        report
+    }
+
+    /// Compare the state of `data` with the last checkpoint in the recording
+    pub fn verify_final(&self, data: &MyData<K, V>, report: &mut ReplayReport) {
+        report.final_hash = data.state_hash();
+        let last = self.records.iter().enumerate().rev().find_map(|(idx, r)| match r.op {
+            RecordedOp::Checkpoint(hash) => Some((idx, hash)),
+            _ => None,
+        });
+        if let Some((idx, expected)) = last {
+            report.checkpoints_verified += 1;
+            if expected != report.final_hash {
+                report.mismatches.push((idx, expected, report.final_hash));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::{CancellationToken, OpContext};
+
+    fn temp_recording(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("recorder-test-{}-{}.rec", name, std::process::id()))
+    }
+
+    #[test]
+    fn replay_stops_at_shutdown() {
+        let path = temp_recording("shutdown");
+        let recorder = OperationRecorder::create(&path).unwrap();
+        recorder.record(&Operation::<u64, u64>::Insert(1, 1)).unwrap();
+        recorder.record(&Operation::<u64, u64>::Shutdown).unwrap();
+        recorder.record(&Operation::<u64, u64>::Insert(2, 2)).unwrap();
+        recorder.flush().unwrap();
+
+        let replayer = Replayer::<u64, u64>::open(&path).unwrap();
+        let data = MyData::new(0, 2);
+        let report = replayer.replay_into(&data);
+        assert_eq!(report.applied, 1);
+        assert_eq!(data.keys(), vec![1]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn invalid_speed_factors_are_rejected() {
+        let path = temp_recording("speed");
+        OperationRecorder::create(&path).unwrap().flush().unwrap();
+        let replayer = Replayer::<u64, u64>::open(&path).unwrap();
+        for factor in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let error = replayer.clone().with_speed(ReplaySpeed::Accelerated(factor)).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", factor);
+        }
+        assert!(replayer.with_speed(ReplaySpeed::Accelerated(f64::MIN_POSITIVE)).is_ok());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn failed_writes_are_counted() {
+        let recorder = Arc::new(OperationRecorder::create("/dev/full").unwrap());
+        let (sender, receiver) = crate::queue::worker_channel(crate::queue::QueueConfig::unbounded());
+        let source = RecordingSource::new(receiver, Arc::clone(&recorder));
+        // Larger than the write buffer, so it reaches the full device at once
+        let entries: Vec<(u64, u64)> = (0..4096).map(|k| (k, k)).collect();
+        sender.send(Operation::ReplaceAll(entries)).unwrap();
+        sender.send(Operation::Insert(1, 1)).unwrap();
+
+        assert!(OperationSource::<u64, u64>::try_recv(&source).is_some());
+        assert!(OperationSource::<u64, u64>::try_recv(&source).is_some());
+        assert_eq!(recorder.failed_records(), 2);
+        assert_eq!(recorder.records(), 0);
+    }
+
+    #[test]
+    fn settle_records_outcomes_not_requests() {
+        let cancelled = CancellationToken::new();
+        cancelled.cancel();
+        let skipped = Operation::<u64, u64>::Insert(1, 1).with_context(OpContext::new().with_token(cancelled));
+        let (skipped, runs) = settle(skipped);
+        assert!(!runs);
+        assert!(matches!(skipped, Operation::WithContext(..)));
+
+        let live = Operation::<u64, u64>::Insert(2, 2).with_context(OpContext::new().with_timeout(Duration::from_secs(60)));
+        let (live, runs) = settle(live);
+        assert!(runs);
+        assert!(matches!(live, Operation::Insert(2, 2)));
+
+        let (reply, _) = channel::bounded(1);
+        let read = Operation::<u64, u64>::Get(3, reply).with_context(OpContext::new());
+        assert!(matches!(settle(read), (Operation::WithContext(..), true)));
+
+        let expired = OpContext::new().with_deadline(Instant::now());
+        let stop = Operation::<u64, u64>::Shutdown.with_context(OpContext::new()).with_context(expired);
+        assert!(matches!(settle(stop), (Operation::Shutdown, true)));
+    }
+
+    #[test]
+    fn state_hash_is_fixed() {
+        let data = MyData::<String, u64>::new(0, 4);
+        data.insert("a".to_string(), 1);
+        data.insert("b".to_string(), 2);
+        // Pinned: a change here breaks every stored checkpoint
+        assert_eq!(data.state_hash(), 0x2319_c3d1_6bf0_95d4);
+    }
+}
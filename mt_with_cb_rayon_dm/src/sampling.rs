@@ -0,0 +1,356 @@
+use rayon::prelude::*;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use crate::data_structures::MyData;
+
+// Two-sided 95% confidence, used for every bound in this module
+const Z_95: f64 = 1.96;
+
+/// An approximate answer with a 95% confidence interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub value: f64,
+    pub low: f64,
+    pub high: f64,
+    /// Entries the estimate was computed from
+    pub sample_size: usize,
+    /// Entries in the store when the estimate was taken
+    pub population: usize,
+}
+
+impl Estimate {
+    fn exact(value: f64, population: usize) -> Self {
+        Estimate {
+            value,
+            low: value,
+            high: value,
+            sample_size: population,
+            population,
+        }
+    }
+
+    /// Half the width of the confidence interval
+    pub fn margin(&self) -> f64 {
+        (self.high - self.low) / 2.0
+    }
+
+    /// Whether every entry was looked at
+    pub fn is_exact(&self) -> bool {
+        self.sample_size >= self.population
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} (95% CI {:.1}..{:.1}, {} of {} entries)",
+            self.value, self.low, self.high, self.sample_size, self.population
+        )
+    }
+}
+
+/// Small, fast generator for sampling decisions; not for anything security related
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn seeded(stream: usize) -> Self {
+        // Each RandomState gets fresh keys, so this differs per call and per segment
+        SplitMix64(RandomState::new().hash_one(stream))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Uniform random sample of up to `n` entries.
+/// Every entry gets a random tag and each segment keeps its `n` smallest tags
+/// in parallel; merging keeps the `n` smallest overall, which is a uniform
+/// sample without replacement of the whole store.
+pub fn sample<K, V>(data: &MyData<K, V>, n: usize) -> Vec<(K, V)>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    tagged_sample(data, n, |k, v| (k.clone(), v.clone())).0
+}
+
+/// `sample`, passing each kept entry through `project` instead of cloning it.
+/// Tags are drawn for every entry, but only entries that make it into a
+/// segment's running top `n` are projected. Returns the sample and the
+/// number of entries it was drawn from.
+fn tagged_sample<K, V, T, P>(data: &MyData<K, V>, n: usize, project: P) -> (Vec<T>, usize)
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    T: Send,
+    P: Fn(&K, &V) -> T + Send + Sync,
+{
+    let (tagged, population) = (0..data.num_segments())
+        .into_par_iter()
+        .map(|idx| {
+            let mut rng = SplitMix64::seeded(idx);
+            // Max-heap on the tag, so the largest kept tag is evicted first
+            let mut kept: BinaryHeap<Tagged<T>> = BinaryHeap::new();
+            let mut seen = 0;
+            if let Some(segment) = data.get_segment(idx) {
+                for entry in segment.iter() {
+                    seen += 1;
+                    if n == 0 {
+                        continue;
+                    }
+                    let tag = rng.next_u64();
+                    if kept.len() < n {
+                        kept.push(Tagged(tag, project(entry.key(), entry.value())));
+                    } else if kept.peek().is_some_and(|top| tag < top.0) {
+                        kept.pop();
+                        kept.push(Tagged(tag, project(entry.key(), entry.value())));
+                    }
+                }
+            }
+            (kept.into_vec(), seen)
+        })
+        .reduce(
+            || (Vec::new(), 0),
+            |(mut a, seen_a), (b, seen_b)| {
+                a.extend(b);
+                if a.len() > n {
+                    a.select_nth_unstable_by_key(n.saturating_sub(1), |t| t.0);
+                    a.truncate(n);
+                }
+                (a, seen_a + seen_b)
+            },
+        );
+
+    (tagged.into_iter().map(|Tagged(_, t)| t).collect(), population)
+}
+
+/// A sampled item with its random tag, ordered by the tag only
+struct Tagged<T>(u64, T);
+
+impl<T> PartialEq for Tagged<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for Tagged<T> {}
+
+impl<T> PartialOrd for Tagged<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Tagged<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+/// Estimate how many entries satisfy `predicate` by evaluating it on a
+/// uniform random sample of `sample_size` entries. The bound is a Wilson
+/// score interval with a finite-population correction, so it shrinks to zero
+/// for a full scan.
+pub fn count_where<K, V, F>(data: &MyData<K, V>, predicate: F, sample_size: usize) -> Estimate
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    F: Fn(&K, &V) -> bool + Send + Sync,
+{
+    let (hits, population) = tagged_sample(data, sample_size.max(1), |k, v| predicate(k, v));
+    let seen = hits.len();
+    let matched = hits.iter().filter(|&&hit| hit).count();
+    if seen >= population {
+        return Estimate::exact(matched as f64, population);
+    }
+
+    let n = seen as f64;
+    let p = matched as f64 / n;
+    let fpc = ((population - seen) as f64 / (population - 1) as f64).sqrt();
+    let z = Z_95 * fpc;
+    let denominator = 1.0 + z * z / n;
+    let centre = (p + z * z / (2.0 * n)) / denominator;
+    let spread = z * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt() / denominator;
+
+    let scale = population as f64;
+    Estimate {
+        value: p * scale,
+        low: ((centre - spread).max(0.0) * scale).floor(),
+        high: ((centre + spread).min(1.0) * scale).ceil(),
+        sample_size: seen,
+        population,
+    }
+}
+
+/// Estimate the number of distinct values of `projection` with a
+/// HyperLogLog sketch of `2^precision` registers (precision 4..=16).
+/// Every entry is hashed once, in parallel per segment, but only the
+/// registers are kept; the relative standard error is `1.04 / sqrt(2^precision)`.
+pub fn distinct_count<K, V, T, P>(data: &MyData<K, V>, projection: P, precision: u8) -> Estimate
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    T: Hash,
+    P: Fn(&K, &V) -> T + Send + Sync,
+{
+    let precision = precision.clamp(4, 16) as u32;
+    let m = 1usize << precision;
+
+    let (registers, population) = (0..data.num_segments())
+        .into_par_iter()
+        .map(|idx| {
+            let mut registers = vec![0u8; m];
+            let mut count = 0;
+            if let Some(segment) = data.get_segment(idx) {
+                for entry in segment.iter() {
+                    // DefaultHasher::new() has fixed keys, so segments agree on hashes
+                    let mut hasher = DefaultHasher::new();
+                    projection(entry.key(), entry.value()).hash(&mut hasher);
+                    let hash = hasher.finish();
+
+                    let register = (hash >> (64 - precision)) as usize;
+                    let rest = (hash << precision) | (1 << (precision - 1));
+                    let rank = rest.leading_zeros() as u8 + 1;
+                    registers[register] = registers[register].max(rank);
+                    count += 1;
+                }
+            }
+            (registers, count)
+        })
+        .reduce(
+            || (vec![0u8; m], 0),
+            |(mut a, count_a), (b, count_b)| {
+                for (x, y) in a.iter_mut().zip(b) {
+                    *x = (*x).max(y);
+                }
+                (a, count_a + count_b)
+            },
+        );
+
+    let mf = m as f64;
+    let alpha = match m {
+        16 => 0.673,
+        32 => 0.697,
+        64 => 0.709,
+        _ => 0.7213 / (1.0 + 1.079 / mf),
+    };
+    let harmonic: f64 = registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+    let mut value = alpha * mf * mf / harmonic;
+    let empty = registers.iter().filter(|&&r| r == 0).count();
+    if value <= 2.5 * mf && empty > 0 {
+        // Linear counting is more accurate for small cardinalities
+        value = mf * (mf / empty as f64).ln();
+    }
+    // A store cannot hold more distinct values than entries
+    value = value.min(population as f64);
+
+    let relative = Z_95 * 1.04 / mf.sqrt();
+    Estimate {
+        value: value.round(),
+        low: (value * (1.0 - relative)).floor().max(0.0),
+        high: (value * (1.0 + relative)).ceil().min(population as f64),
+        sample_size: population,
+        population,
+    }
+}
+
+/// Estimate quantiles of `projection` (each `q` in 0.0..=1.0) from a uniform
+/// random sample of `sample_size` entries. Bounds come from the Dvoretzky–Kiefer–Wolfowitz
+/// inequality: with 95% confidence the true quantile lies between the sample
+/// values at ranks `q - eps` and `q + eps`.
+pub fn quantiles<K, V, P>(data: &MyData<K, V>, projection: P, qs: &[f64], sample_size: usize) -> Vec<Estimate>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    P: Fn(&K, &V) -> f64 + Send + Sync,
+{
+    let (mut values, population) = tagged_sample(data, sample_size.max(1), projection);
+    values.retain(|v| !v.is_nan());
+    if values.is_empty() {
+        return qs.iter().map(|_| Estimate::exact(f64::NAN, population)).collect();
+    }
+    values.par_sort_unstable_by(|a, b| a.partial_cmp(b).expect("NaNs were removed"));
+
+    let n = values.len();
+    let last = (n - 1) as f64;
+    let eps = if n >= population {
+        0.0
+    } else {
+        ((2.0 / 0.05f64).ln() / (2.0 * n as f64)).sqrt()
+    };
+    let at = |q: f64| values[(q.clamp(0.0, 1.0) * last).round() as usize];
+
+    qs.iter()
+        .map(|&q| Estimate {
+            value: at(q),
+            low: values[((q - eps).clamp(0.0, 1.0) * last).floor() as usize],
+            high: values[((q + eps).clamp(0.0, 1.0) * last).ceil() as usize],
+            sample_size: n,
+            population,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// A store plus the keys DashMap iterates first in every segment, which
+    /// a prefix of each segment would over-represent
+    fn store_with_leading_keys() -> (MyData<u64, u64>, HashSet<u64>) {
+        let data = MyData::new(0, 8);
+        for key in 0..40_000 {
+            data.insert(key, key);
+        }
+        let leading = (0..data.num_segments())
+            .flat_map(|idx| {
+                let segment = data.get_segment(idx).unwrap();
+                let share = segment.len() / 10;
+                segment.iter().take(share).map(|entry| *entry.key()).collect::<Vec<_>>()
+            })
+            .collect();
+        (data, leading)
+    }
+
+    #[test]
+    fn count_where_is_not_biased_by_iteration_order() {
+        let (data, leading) = store_with_leading_keys();
+        let truth = leading.len() as f64;
+        let covered = (0..20)
+            .filter(|_| {
+                let estimate = count_where(&data, |k, _| leading.contains(k), 2_000);
+                estimate.low <= truth && truth <= estimate.high
+            })
+            .count();
+        // 95% intervals: all 20 missing by chance is vanishingly unlikely,
+        // while a biased sample misses every time
+        assert!(covered >= 15, "only {} of 20 intervals covered the truth", covered);
+    }
+
+    #[test]
+    fn quantiles_are_not_biased_by_iteration_order() {
+        let (data, leading) = store_with_leading_keys();
+        // Leading keys project to 1, so the true 10th percentile sits at the boundary
+        let estimates = quantiles(&data, |k, _| if leading.contains(k) { 1.0 } else { 0.0 }, &[0.5], 2_000);
+        assert_eq!(estimates[0].value, 0.0);
+    }
+
+    #[test]
+    fn sample_sizes_are_exact() {
+        let (data, _) = store_with_leading_keys();
+        assert_eq!(sample(&data, 0).len(), 0);
+        assert_eq!(sample(&data, 100).len(), 100);
+        let (all, population) = tagged_sample(&data, 50_000, |k, _| *k);
+        assert_eq!((all.len(), population), (40_000, 40_000));
+    }
+}
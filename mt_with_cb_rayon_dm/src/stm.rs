@@ -0,0 +1,417 @@
+use crossbeam::utils::Backoff;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::versioned::{HistoryConfig, Version, VersionedData};
+
+/// Why a transaction attempt stopped early. Returned by `Transaction`
+/// methods so the body can propagate it with `?`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StmError {
+    /// A key read by the transaction changed after it started; run it again
+    Conflict,
+    /// The transaction asked to wait until something it read changes
+    Retry,
+}
+
+impl fmt::Display for StmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StmError::Conflict => f.write_str("transaction conflicted with a concurrent commit"),
+            StmError::Retry => f.write_str("transaction is waiting for its inputs to change"),
+        }
+    }
+}
+
+impl std::error::Error for StmError {}
+
+/// Result type for transaction bodies
+pub type StmResult<T> = Result<T, StmError>;
+
+/// What a transaction saw for a key, compared again at commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Seen {
+    /// The version of the key's latest write, which may be a removal
+    Version(u64),
+    /// No history at all, as of this many keys collected by gc. A key that is
+    /// removed, collected and created again never looks unchanged.
+    Absent(u64),
+}
+
+/// One attempt of an optimistic transaction. Reads see a consistent snapshot
+/// as of the start of the attempt, taken from the store's history, so a
+/// read-only transaction only aborts if that history was collected.
+/// Writes are buffered until commit.
+pub struct Transaction<'a, K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    store: &'a VersionedData<K, V>,
+    snapshot: u64,
+    // key -> what it looked like when first read
+    reads: HashMap<K, Seen>,
+    // key -> buffered write; `None` is a removal
+    writes: HashMap<K, Option<V>>,
+}
+
+impl<K, V> Transaction<'_, K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Read a key, seeing this transaction's own writes first
+    pub fn read(&mut self, key: &K) -> StmResult<Option<V>> {
+        if let Some(write) = self.writes.get(key) {
+            return Ok(write.clone());
+        }
+
+        let (seen, value) = match self.store.latest_at(key, self.snapshot) {
+            Some(Version { version, value, .. }) => (Seen::Version(version), value),
+            // Created after our snapshot, or the history we need is gone
+            None if self.store.latest(key).is_some() => return Err(StmError::Conflict),
+            None => (Seen::Absent(self.store.collected_keys()), None),
+        };
+        if let Some(&previous) = self.reads.get(key) {
+            if previous != seen {
+                return Err(StmError::Conflict);
+            }
+        }
+        self.reads.insert(key.clone(), seen);
+        Ok(value)
+    }
+
+    /// Buffer a write of `value` to `key`
+    pub fn write(&mut self, key: K, value: V) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Buffer the removal of `key`
+    pub fn remove(&mut self, key: &K) {
+        self.writes.insert(key.clone(), None);
+    }
+
+    /// Abandon this attempt and block until a key it read changes
+    pub fn retry<T>(&self) -> StmResult<T> {
+        Err(StmError::Retry)
+    }
+
+    /// Run `first`; if it calls `retry`, undo its writes and run `second`
+    /// instead. Reads made by `first` still count towards waking a retry.
+    pub fn or_else<T, F, G>(&mut self, first: F, second: G) -> StmResult<T>
+    where
+        F: FnOnce(&mut Self) -> StmResult<T>,
+        G: FnOnce(&mut Self) -> StmResult<T>,
+    {
+        let saved = self.writes.clone();
+        match first(self) {
+            Err(StmError::Retry) => {
+                self.writes = saved;
+                second(self)
+            }
+            other => other,
+        }
+    }
+
+    /// Whether any key this attempt read has changed since
+    fn inputs_changed(&self) -> bool {
+        self.reads.iter().any(|(key, &seen)| {
+            // History first: gc counts a key before removing it, so seeing it
+            // gone means the count read next already includes it
+            let current = match self.store.latest(key) {
+                Some(latest) => Seen::Version(latest.version),
+                None => Seen::Absent(self.store.collected_keys()),
+            };
+            current != seen
+        })
+    }
+}
+
+/// Counters describing how transactions have been running
+#[derive(Debug, Clone, Copy)]
+pub struct StmStats {
+    pub commits: usize,
+    pub conflicts: usize,
+    pub retries: usize,
+}
+
+/// A versioned store whose writes all go through optimistic transactions.
+///
+/// This wraps a `VersionedData` instead of adding `atomically` to `MyData`
+/// because commit-time validation needs a version on every entry that every
+/// write bumps. `MyData` has write paths that record none (`insert`,
+/// `for_each`, `transaction`, `replace_all`, ...), and a transaction would
+/// silently miss what they change. Keeping the versions here also means
+/// plain `MyData` users do not pay for them. Snapshot reads come from the
+/// retained history, so `history` should keep enough versions for the
+/// longest-running transaction.
+pub struct StmData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    store: Arc<VersionedData<K, V>>,
+    // Highest version whose commit has fully landed; new attempts snapshot here
+    committed: AtomicU64,
+    commit_lock: Mutex<()>,
+    // Bumped and signalled after every commit to wake retrying transactions
+    commit_signal: (Mutex<u64>, Condvar),
+    commits: AtomicUsize,
+    conflicts: AtomicUsize,
+    retries: AtomicUsize,
+}
+
+impl<K, V> StmData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new(id: usize, num_segments: usize, history: HistoryConfig) -> Self {
+        StmData {
+            store: VersionedData::new(id, num_segments, history),
+            committed: AtomicU64::new(0),
+            commit_lock: Mutex::new(()),
+            commit_signal: (Mutex::new(0), Condvar::new()),
+            commits: AtomicUsize::new(0),
+            conflicts: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+        }
+    }
+
+    /// Read the latest committed value of a key outside any transaction
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.store.get(key)
+    }
+
+    /// The underlying versioned store, for history and time-travel reads
+    pub fn versions(&self) -> &VersionedData<K, V> {
+        &self.store
+    }
+
+    /// Run `body` as a transaction until it commits, backing off after
+    /// conflicts and blocking after `retry` until one of its inputs changes
+    pub fn atomically<R, F>(&self, mut body: F) -> R
+    where
+        F: FnMut(&mut Transaction<'_, K, V>) -> StmResult<R>,
+    {
+        let backoff = Backoff::new();
+        loop {
+            let seen_commits = *self.commit_signal.0.lock().unwrap();
+            let mut tx = Transaction {
+                store: &self.store,
+                snapshot: self.committed.load(Ordering::Acquire),
+                reads: HashMap::new(),
+                writes: HashMap::new(),
+            };
+
+            match body(&mut tx) {
+                Ok(result) => {
+                    if self.commit(&tx) {
+                        self.commits.fetch_add(1, Ordering::Relaxed);
+                        return result;
+                    }
+                    self.conflicts.fetch_add(1, Ordering::Relaxed);
+                    Self::back_off(&backoff);
+                }
+                Err(StmError::Conflict) => {
+                    self.conflicts.fetch_add(1, Ordering::Relaxed);
+                    Self::back_off(&backoff);
+                }
+                Err(StmError::Retry) => {
+                    self.retries.fetch_add(1, Ordering::Relaxed);
+                    self.wait_for_change(&tx, seen_commits);
+                    backoff.reset();
+                }
+            }
+        }
+    }
+
+    fn back_off(backoff: &Backoff) {
+        if backoff.is_completed() {
+            thread::sleep(Duration::from_micros(100));
+        } else {
+            backoff.snooze();
+        }
+    }
+
+    /// Validate the read set and install the write set as one step
+    fn commit(&self, tx: &Transaction<'_, K, V>) -> bool {
+        if tx.writes.is_empty() {
+            // Every read was checked against the snapshot as it happened
+            return true;
+        }
+
+        {
+            let _guard = self.commit_lock.lock().unwrap();
+            if tx.inputs_changed() {
+                return false;
+            }
+            for (key, write) in &tx.writes {
+                match write {
+                    Some(value) => {
+                        self.store.insert(key.clone(), value.clone());
+                    }
+                    None => {
+                        self.store.remove(key);
+                    }
+                }
+            }
+            self.committed.store(self.store.current_version(), Ordering::Release);
+        }
+
+        let (count, changed) = &self.commit_signal;
+        *count.lock().unwrap() += 1;
+        changed.notify_all();
+        true
+    }
+
+    /// Block until a commit touches one of the keys `tx` read
+    fn wait_for_change(&self, tx: &Transaction<'_, K, V>, mut seen_commits: u64) {
+        let (count, changed) = &self.commit_signal;
+        loop {
+            if tx.inputs_changed() {
+                return;
+            }
+            let mut current = count.lock().unwrap();
+            while *current == seen_commits {
+                current = changed.wait(current).unwrap();
+            }
+            seen_commits = *current;
+        }
+    }
+
+    pub fn stats(&self) -> StmStats {
+        StmStats {
+            commits: self.commits.load(Ordering::Relaxed),
+            conflicts: self.conflicts.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stm(history: HistoryConfig) -> StmData<String, i64> {
+        StmData::new(0, 4, history.with_gc_interval(Duration::from_secs(3600)))
+    }
+
+    fn key(name: &str) -> String {
+        name.to_string()
+    }
+
+    #[test]
+    fn conflicting_increments_are_serialized() {
+        let data = stm(HistoryConfig::last(4));
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        data.atomically(|tx| {
+                            let count = tx.read(&key("count"))?.unwrap_or(0);
+                            tx.write(key("count"), count + 1);
+                            Ok(())
+                        });
+                    }
+                });
+            }
+        });
+        assert_eq!(data.get(&key("count")), Some(400));
+        assert_eq!(data.stats().commits, 400);
+    }
+
+    #[test]
+    fn read_only_transactions_never_abort() {
+        let data = stm(HistoryConfig::last(100_000));
+        data.atomically(|tx| {
+            tx.write(key("a"), 50);
+            tx.write(key("b"), 50);
+            Ok(())
+        });
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..2_000 {
+                    data.atomically(|tx| {
+                        let (from, to) = if i % 2 == 0 { (key("a"), key("b")) } else { (key("b"), key("a")) };
+                        let a = tx.read(&from)?.unwrap_or(0);
+                        let b = tx.read(&to)?.unwrap_or(0);
+                        tx.write(from, a - 1);
+                        tx.write(to, b + 1);
+                        Ok(())
+                    });
+                }
+            });
+            for _ in 0..2_000 {
+                let mut attempts = 0;
+                let total = data.atomically(|tx| {
+                    attempts += 1;
+                    let a = tx.read(&key("a"))?.unwrap_or(0);
+                    thread::yield_now();
+                    let b = tx.read(&key("b"))?.unwrap_or(0);
+                    Ok(a + b)
+                });
+                assert_eq!(total, 100);
+                assert_eq!(attempts, 1);
+            }
+        });
+    }
+
+    #[test]
+    fn aborted_attempts_leave_no_writes() {
+        let data = stm(HistoryConfig::last(4));
+        let mut attempts = 0;
+        let copied = data.atomically(|tx| {
+            attempts += 1;
+            // Nothing from the failed attempt may be visible
+            assert_eq!(data.get(&key("copy")), None);
+            let source = tx.read(&key("source"))?.unwrap_or(0);
+            tx.write(key("copy"), source);
+            tx.write(key("other"), 1);
+            if attempts == 1 {
+                // Another transaction changes what we read before we commit
+                data.atomically(|other| {
+                    other.write(key("source"), 5);
+                    Ok(())
+                });
+            }
+            Ok(source)
+        });
+        assert_eq!(attempts, 2);
+        assert_eq!(copied, 5);
+        assert_eq!(data.get(&key("copy")), Some(5));
+        assert_eq!(data.stats().conflicts, 1);
+    }
+
+    #[test]
+    fn a_recreated_key_does_not_validate_as_absent() {
+        let data = stm(HistoryConfig::within(Duration::ZERO));
+        let mut attempts = 0;
+        data.atomically(|tx| {
+            attempts += 1;
+            let seen = tx.read(&key("k"))?;
+            if attempts == 1 {
+                assert_eq!(seen, None);
+                // Created, removed and collected while we run
+                data.atomically(|other| {
+                    other.write(key("k"), 1);
+                    Ok(())
+                });
+                data.atomically(|other| {
+                    other.remove(&key("k"));
+                    Ok(())
+                });
+                data.versions().gc();
+                assert!(data.versions().latest(&key("k")).is_none());
+            }
+            tx.write(key("seen"), seen.unwrap_or(-1));
+            Ok(())
+        });
+        assert_eq!(attempts, 2);
+    }
+}
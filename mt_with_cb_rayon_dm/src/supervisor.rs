@@ -0,0 +1,336 @@
+use crossbeam::channel::{self, Receiver, RecvTimeoutError};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::counter::StripedCounter;
+use crate::data_structures::MyData;
+use crate::worker_utils::{apply_operation, OperationSource};
+
+// Values stored in `Shared::stop`
+const RUN: u8 = 0;
+const DRAIN: u8 = 1;
+const STOP_NOW: u8 = 2;
+
+/// Callback invoked every time a supervised worker panics
+pub type PanicHook = Arc<dyn Fn(&PanicReport) + Send + Sync>;
+
+/// Details about a panic caught by the supervisor
+#[derive(Debug, Clone)]
+pub struct PanicReport {
+    /// The panic message, if it was a string
+    pub message: String,
+    /// How many panics this worker has had so far, including this one
+    pub panic_count: usize,
+    /// Whether the supervisor is going to restart the worker
+    pub will_restart: bool,
+}
+
+/// Controls how the supervisor reacts to worker panics
+#[derive(Clone)]
+pub struct SupervisorConfig {
+    /// Maximum number of restarts allowed within `restart_window`
+    pub max_restarts: usize,
+    /// Sliding window used to count restarts
+    pub restart_window: Duration,
+    /// Pause before restarting a worker that panicked
+    pub restart_backoff: Duration,
+    /// How often an idle worker checks for shutdown requests
+    pub poll_interval: Duration,
+    /// Called with a report every time the worker panics
+    pub panic_hook: Option<PanicHook>,
+}
+
+impl SupervisorConfig {
+    /// Register a callback that is told about every panic
+    pub fn on_panic<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&PanicReport) + Send + Sync + 'static,
+    {
+        self.panic_hook = Some(Arc::new(hook));
+        self
+    }
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            max_restarts: 3,
+            restart_window: Duration::from_secs(60),
+            restart_backoff: Duration::from_millis(10),
+            poll_interval: Duration::from_millis(20),
+            panic_hook: None,
+        }
+    }
+}
+
+/// Lifecycle state of a supervised worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerState {
+    Running,
+    /// Finishing whatever is left in the queue before stopping
+    Draining,
+    Stopped,
+    /// Gave up after exceeding the restart limit
+    Failed,
+}
+
+/// A point-in-time snapshot of worker statistics
+#[derive(Debug, Clone)]
+pub struct WorkerStats {
+    pub state: WorkerState,
+    pub processed: usize,
+    pub panics: usize,
+    pub restarts: usize,
+    pub last_panic: Option<String>,
+}
+
+/// Returned by `join_timeout` when the worker is still running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinTimeout;
+
+impl fmt::Display for JoinTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("worker did not stop before the timeout")
+    }
+}
+
+impl std::error::Error for JoinTimeout {}
+
+/// State shared between a handle and its worker thread
+struct Shared {
+    stop: AtomicU8,
+    processed: StripedCounter,
+    // Only the supervisor thread writes these, so striping would not help
+    panics: AtomicUsize,
+    restarts: AtomicUsize,
+    state: Mutex<WorkerState>,
+    last_panic: Mutex<Option<String>>,
+}
+
+impl Shared {
+    fn set_state(&self, state: WorkerState) {
+        *self.state.lock().unwrap() = state;
+    }
+}
+
+/// Handle to a worker thread that is supervised for panics.
+/// Dropping it stops the worker after its current operation and waits for the
+/// thread, leaving queued work unprocessed; call `shutdown_graceful` and
+/// `join_timeout` first to drain the queue or to bound the wait.
+#[must_use = "dropping the handle stops the worker right away"]
+pub struct WorkerHandle {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    // Disconnects when the worker thread exits
+    done: Receiver<()>,
+}
+
+impl WorkerHandle {
+    /// Spawn a supervised worker that processes operations from `receiver`
+    pub fn spawn<K, V, S>(data: Arc<MyData<K, V>>, receiver: S, config: SupervisorConfig) -> Self
+    where
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+        S: OperationSource<K, V>,
+    {
+        let shared = Arc::new(Shared {
+            stop: AtomicU8::new(RUN),
+            processed: StripedCounter::new(),
+            panics: AtomicUsize::new(0),
+            restarts: AtomicUsize::new(0),
+            state: Mutex::new(WorkerState::Running),
+            last_panic: Mutex::new(None),
+        });
+        let (done_sender, done) = channel::bounded::<()>(0);
+
+        let worker_shared = Arc::clone(&shared);
+        let thread = thread::spawn(move || {
+            // Dropped when this thread exits, which wakes up `join_timeout`
+            let _done_sender = done_sender;
+            supervise(&data, &receiver, &worker_shared, &config);
+        });
+
+        WorkerHandle {
+            shared,
+            thread: Some(thread),
+            done,
+        }
+    }
+
+    /// Stop accepting new work once the queue is empty
+    pub fn shutdown_graceful(&self) {
+        let _ = self
+            .shared
+            .stop
+            .compare_exchange(RUN, DRAIN, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Stop after the current operation, leaving anything queued unprocessed
+    pub fn shutdown_now(&self) {
+        self.shared.stop.store(STOP_NOW, Ordering::SeqCst);
+    }
+
+    /// Wait up to `timeout` for the worker thread to finish
+    pub fn join_timeout(&mut self, timeout: Duration) -> Result<WorkerStats, JoinTimeout> {
+        if let Some(thread) = self.thread.take() {
+            match self.done.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    self.thread = Some(thread);
+                    return Err(JoinTimeout);
+                }
+                // The worker never sends, so anything else means it has exited
+                _ => {
+                    let _ = thread.join();
+                }
+            }
+        }
+        Ok(self.stats())
+    }
+
+    /// Take a snapshot of the worker statistics
+    pub fn stats(&self) -> WorkerStats {
+        WorkerStats {
+            state: *self.shared.state.lock().unwrap(),
+            processed: self.shared.processed.sum(),
+            panics: self.shared.panics.load(Ordering::Relaxed),
+            restarts: self.shared.restarts.load(Ordering::Relaxed),
+            last_panic: self.shared.last_panic.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Drop for WorkerHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.shutdown_now();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Run the worker loop, restarting it after panics until the restart budget runs out
+fn supervise<K, V, S>(data: &MyData<K, V>, receiver: &S, shared: &Shared, config: &SupervisorConfig)
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    S: OperationSource<K, V>,
+{
+    let mut recent_restarts: VecDeque<Instant> = VecDeque::new();
+
+    loop {
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_worker_loop(data, receiver, shared, config.poll_interval)
+        }));
+
+        let payload = match outcome {
+            Ok(()) => {
+                shared.set_state(WorkerState::Stopped);
+                return;
+            }
+            Err(payload) => payload,
+        };
+
+        let message = panic_message(payload.as_ref());
+        let panic_count = shared.panics.fetch_add(1, Ordering::Relaxed) + 1;
+        *shared.last_panic.lock().unwrap() = Some(message.clone());
+
+        // Only count restarts that happened inside the sliding window
+        let now = Instant::now();
+        while let Some(&oldest) = recent_restarts.front() {
+            if now.duration_since(oldest) > config.restart_window {
+                recent_restarts.pop_front();
+            } else {
+                break;
+            }
+        }
+        let will_restart = recent_restarts.len() < config.max_restarts
+            && shared.stop.load(Ordering::SeqCst) != STOP_NOW;
+
+        if let Some(hook) = &config.panic_hook {
+            hook(&PanicReport {
+                message,
+                panic_count,
+                will_restart,
+            });
+        }
+
+        if !will_restart {
+            shared.set_state(WorkerState::Failed);
+            return;
+        }
+
+        recent_restarts.push_back(now);
+        shared.restarts.fetch_add(1, Ordering::Relaxed);
+        thread::sleep(config.restart_backoff);
+    }
+}
+
+/// Process operations until shutdown is requested or the channel closes
+fn run_worker_loop<K, V, S>(data: &MyData<K, V>, receiver: &S, shared: &Shared, poll_interval: Duration)
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    S: OperationSource<K, V>,
+{
+    loop {
+        let operation = match shared.stop.load(Ordering::SeqCst) {
+            RUN => match receiver.recv_timeout(poll_interval) {
+                Ok(operation) => operation,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            DRAIN => {
+                shared.set_state(WorkerState::Draining);
+                match receiver.try_recv() {
+                    Some(operation) => operation,
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+
+        let keep_going = apply_operation(data, operation);
+        shared.processed.increment();
+        if !keep_going {
+            return;
+        }
+    }
+}
+
+/// Extract a readable message from a panic payload
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{worker_channel, QueueConfig};
+    use crate::worker_utils::Operation;
+
+    #[test]
+    fn dropping_the_handle_stops_the_worker() {
+        let data = Arc::new(MyData::<u64, u64>::new(0, 2));
+        let (sender, receiver) = worker_channel(QueueConfig::unbounded());
+        let handle = WorkerHandle::spawn(Arc::clone(&data), receiver, SupervisorConfig::default());
+        let shared = Arc::clone(&handle.shared);
+        sender.send(Operation::Insert(1, 1)).unwrap();
+        drop(handle);
+        assert_eq!(*shared.state.lock().unwrap(), WorkerState::Stopped);
+        // The worker thread and its clone of the store are gone
+        assert_eq!(Arc::strong_count(&data), 1);
+    }
+}
@@ -0,0 +1,737 @@
+use crossbeam::channel;
+use std::fmt;
+use std::hash::Hash;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::panic;
+use std::str::FromStr;
+
+use crate::data_structures::MyData;
+
+// Keep at most this many line errors in an import report; the rest are only counted
+const MAX_REPORTED_ERRORS: usize = 100;
+// Lines between progress callbacks on import
+const IMPORT_PROGRESS_INTERVAL: usize = 10_000;
+// Export hands encoded text to the writer in chunks of about this size...
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+// ...with at most this many waiting per segment being encoded
+const EXPORT_CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Turns keys or values into a text field and back, for CSV and JSON Lines
+pub trait TextCodec<T>: Sync {
+    fn encode(&self, value: &T) -> String;
+
+    fn decode(&self, text: &str) -> Result<T, String>;
+
+    /// Whether `encode` produces a JSON number to embed as is rather than
+    /// text to write as a JSON string. Text that is not a valid JSON number
+    /// is quoted anyway.
+    fn raw_json(&self) -> bool {
+        false
+    }
+}
+
+/// `Display`/`FromStr` text, written to JSON as a string
+pub struct PlainText;
+
+impl<T> TextCodec<T> for PlainText
+where
+    T: fmt::Display + FromStr,
+    T::Err: fmt::Display,
+{
+    fn encode(&self, value: &T) -> String {
+        value.to_string()
+    }
+
+    fn decode(&self, text: &str) -> Result<T, String> {
+        text.parse().map_err(|e: T::Err| e.to_string())
+    }
+}
+
+/// `Display`/`FromStr` numbers, written to JSON as bare numbers. Values
+/// whose text is not a JSON number, such as `NaN` or `inf`, are written as
+/// JSON strings instead and still decode.
+pub struct Number;
+
+impl<T> TextCodec<T> for Number
+where
+    T: fmt::Display + FromStr,
+    T::Err: fmt::Display,
+{
+    fn encode(&self, value: &T) -> String {
+        value.to_string()
+    }
+
+    fn decode(&self, text: &str) -> Result<T, String> {
+        text.trim().parse().map_err(|e: T::Err| format!("{:?} is not a number: {}", text, e))
+    }
+
+    fn raw_json(&self) -> bool {
+        true
+    }
+}
+
+/// How far an export or import has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Entries written, or lines read on import
+    pub records: usize,
+    /// Entries in the store when an export started; unknown on import
+    pub total: Option<usize>,
+    pub bytes: u64,
+}
+
+/// A line of input that could not be imported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineError {
+    /// 1-based line number where the bad record starts
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Outcome of an import. Bad lines are skipped and reported, not fatal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Number of bad lines, including any not kept in `errors`
+    pub failed: usize,
+    /// The first bad lines, in input order
+    pub errors: Vec<LineError>,
+    pub progress: Progress,
+}
+
+impl ImportReport {
+    pub fn is_clean(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+/// Write `key,value` CSV with a header line
+pub fn export_csv<K, V, W, KC, VC, P>(data: &MyData<K, V>, out: W, keys: &KC, values: &VC, progress: P) -> io::Result<Progress>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    W: Write,
+    KC: TextCodec<K>,
+    VC: TextCodec<V>,
+    P: FnMut(&Progress),
+{
+    export_with(data, out, Some("key,value\n"), progress, |key, value, buf| {
+        write_csv_field(buf, &keys.encode(key));
+        buf.push(b',');
+        write_csv_field(buf, &values.encode(value));
+        buf.push(b'\n');
+    })
+}
+
+/// Write one `{"key": ..., "value": ...}` object per line
+pub fn export_jsonl<K, V, W, KC, VC, P>(data: &MyData<K, V>, out: W, keys: &KC, values: &VC, progress: P) -> io::Result<Progress>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    W: Write,
+    KC: TextCodec<K>,
+    VC: TextCodec<V>,
+    P: FnMut(&Progress),
+{
+    export_with(data, out, None, progress, |key, value, buf| {
+        buf.extend_from_slice(b"{\"key\":");
+        write_json_field(buf, &keys.encode(key), keys.raw_json());
+        buf.extend_from_slice(b",\"value\":");
+        write_json_field(buf, &values.encode(value), values.raw_json());
+        buf.extend_from_slice(b"}\n");
+    })
+}
+
+/// Encode a window of segments in parallel and write their text in segment
+/// order, so the output is deterministic. Each shard is copied out and its
+/// lock released before encoding, and the text goes out in bounded chunks
+/// that wait for the writer. Memory use stays at one shard copy and a few
+/// chunks per segment in the window, and a slow writer (or a `progress`
+/// callback that writes to the store) never waits on a shard lock.
+fn export_with<K, V, W, P, E>(
+    data: &MyData<K, V>,
+    mut out: W,
+    header: Option<&str>,
+    mut progress: P,
+    encode: E,
+) -> io::Result<Progress>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    W: Write,
+    P: FnMut(&Progress),
+    E: Fn(&K, &V, &mut Vec<u8>) + Sync,
+{
+    let mut done = Progress {
+        records: 0,
+        total: Some(data.len()),
+        bytes: 0,
+    };
+    if let Some(header) = header {
+        out.write_all(header.as_bytes())?;
+        done.bytes += header.len() as u64;
+    }
+
+    let encode = &encode;
+    let segments: Vec<usize> = (0..data.num_segments()).collect();
+    for window in segments.chunks(rayon::current_num_threads().max(1)) {
+        crossbeam::scope(|scope| -> io::Result<()> {
+            let chunks: Vec<channel::Receiver<(usize, Vec<u8>)>> = window
+                .iter()
+                .map(|&idx| {
+                    let (sender, receiver) = channel::bounded(EXPORT_CHUNKS_IN_FLIGHT);
+                    scope.spawn(move |_| {
+                        let mut buf = Vec::with_capacity(EXPORT_CHUNK_BYTES);
+                        let mut count = 0;
+                        let shards = data.get_segment(idx).map(|segment| segment.shards()).unwrap_or_default();
+                        for shard in shards {
+                            let entries: Vec<(K, V)> =
+                                shard.read().iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+                            for (key, value) in &entries {
+                                encode(key, value, &mut buf);
+                                count += 1;
+                                if buf.len() >= EXPORT_CHUNK_BYTES {
+                                    let chunk = mem::replace(&mut buf, Vec::with_capacity(EXPORT_CHUNK_BYTES));
+                                    if sender.send((mem::take(&mut count), chunk)).is_err() {
+                                        // The writer failed and gave up
+                                        return;
+                                    }
+                                }
+                            }
+                        }
+                        if count > 0 {
+                            let _ = sender.send((count, buf));
+                        }
+                    });
+                    receiver
+                })
+                .collect();
+
+            // Dropping the receivers on a write error stops the encoders
+            for segment in chunks {
+                for (count, chunk) in segment {
+                    out.write_all(&chunk)?;
+                    done.records += count;
+                    done.bytes += chunk.len() as u64;
+                    progress(&done);
+                }
+            }
+            Ok(())
+        })
+        .unwrap_or_else(|payload| panic::resume_unwind(payload))?;
+    }
+    out.flush()?;
+    Ok(done)
+}
+
+/// Read CSV written by `export_csv`: a `key,value` header, then one record
+/// per line; quoted fields may contain commas, quotes and newlines
+pub fn import_csv<K, V, R, KC, VC, P>(data: &MyData<K, V>, input: R, keys: &KC, values: &VC, progress: P) -> io::Result<ImportReport>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    R: BufRead,
+    KC: TextCodec<K>,
+    VC: TextCodec<V>,
+    P: FnMut(&Progress),
+{
+    let mut seen_header = false;
+    import_with(data, input, progress, true, |record| {
+        if record.trim().is_empty() {
+            return Ok(None);
+        }
+        let fields = parse_csv_record(record)?;
+        if !seen_header {
+            seen_header = true;
+            if fields == ["key", "value"] {
+                return Ok(None);
+            }
+            return Err(format!("expected a \"key,value\" header, found {:?}", record.trim_end()));
+        }
+        match fields.as_slice() {
+            [key, value] => Ok(Some((
+                keys.decode(key).map_err(|e| format!("bad key: {}", e))?,
+                values.decode(value).map_err(|e| format!("bad value: {}", e))?,
+            ))),
+            _ => Err(format!("expected 2 fields, found {}", fields.len())),
+        }
+    })
+}
+
+/// Read JSON Lines written by `export_jsonl`: one object per line with
+/// `key` and `value` members
+pub fn import_jsonl<K, V, R, KC, VC, P>(data: &MyData<K, V>, input: R, keys: &KC, values: &VC, progress: P) -> io::Result<ImportReport>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    R: BufRead,
+    KC: TextCodec<K>,
+    VC: TextCodec<V>,
+    P: FnMut(&Progress),
+{
+    import_with(data, input, progress, false, |record| {
+        if record.trim().is_empty() {
+            return Ok(None);
+        }
+        let (key, value) = parse_json_pair(record)?;
+        Ok(Some((
+            keys.decode(&key).map_err(|e| format!("bad key: {}", e))?,
+            values.decode(&value).map_err(|e| format!("bad value: {}", e))?,
+        )))
+    })
+}
+
+/// Split `input` into records, parse each and insert the good ones.
+/// With `multiline`, a record continues onto the next line while it has an
+/// open quote. `parse` returns `Ok(None)` for lines that hold no entry.
+fn import_with<K, V, R, P, F>(
+    data: &MyData<K, V>,
+    mut input: R,
+    mut progress: P,
+    multiline: bool,
+    mut parse: F,
+) -> io::Result<ImportReport>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    R: BufRead,
+    P: FnMut(&Progress),
+    F: FnMut(&str) -> Result<Option<(K, V)>, String>,
+{
+    let mut report = ImportReport {
+        imported: 0,
+        failed: 0,
+        errors: Vec::new(),
+        progress: Progress {
+            records: 0,
+            total: None,
+            bytes: 0,
+        },
+    };
+    let mut line_no = 0;
+    // Raw bytes, so a line of bad UTF-8 is reported rather than ending the import
+    let mut record = Vec::new();
+
+    loop {
+        record.clear();
+        let start = line_no + 1;
+        let read = input.read_until(b'\n', &mut record)?;
+        if read == 0 {
+            break;
+        }
+        line_no += 1;
+        report.progress.bytes += read as u64;
+        while multiline && has_open_quote(&record) {
+            let read = input.read_until(b'\n', &mut record)?;
+            if read == 0 {
+                break;
+            }
+            line_no += 1;
+            report.progress.bytes += read as u64;
+        }
+
+        let outcome = match std::str::from_utf8(&record) {
+            Ok(_) if multiline && has_open_quote(&record) => Err("unterminated quoted field".to_string()),
+            Ok(text) => parse(text),
+            Err(_) => Err("not valid UTF-8".to_string()),
+        };
+        match outcome {
+            Ok(Some((key, value))) => {
+                data.insert(key, value);
+                report.imported += 1;
+            }
+            Ok(None) => {}
+            Err(message) => {
+                report.failed += 1;
+                if report.errors.len() < MAX_REPORTED_ERRORS {
+                    report.errors.push(LineError { line: start, message });
+                }
+            }
+        }
+
+        report.progress.records = line_no;
+        if line_no % IMPORT_PROGRESS_INTERVAL == 0 {
+            progress(&report.progress);
+        }
+    }
+    progress(&report.progress);
+    Ok(report)
+}
+
+fn write_csv_field(buf: &mut Vec<u8>, field: &str) {
+    let needs_quotes = field.is_empty()
+        || field.starts_with(' ')
+        || field.ends_with(' ')
+        || field.contains([',', '"', '\n', '\r']);
+    if needs_quotes {
+        buf.push(b'"');
+        buf.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+        buf.push(b'"');
+    } else {
+        buf.extend_from_slice(field.as_bytes());
+    }
+}
+
+/// Whether `record` ends inside a quoted field, so it continues on the next
+/// line. A quote that can neither open nor close a quoted field makes the
+/// record malformed; it then ends here, so the error stays on this record
+/// instead of swallowing the lines after it.
+fn has_open_quote(record: &[u8]) -> bool {
+    let mut in_quotes = false;
+    let mut field_start = true;
+    let mut bytes = record.iter().peekable();
+    while let Some(&b) = bytes.next() {
+        if in_quotes {
+            if b == b'"' && bytes.next_if_eq(&&b'"').is_none() {
+                in_quotes = false;
+            }
+            continue;
+        }
+        match b {
+            b'"' if field_start => in_quotes = true,
+            b'"' => return false,
+            _ => {}
+        }
+        field_start = b == b',';
+    }
+    in_quotes
+}
+
+fn parse_csv_record(record: &str) -> Result<Vec<String>, String> {
+    let record = record.strip_suffix('\n').unwrap_or(record);
+    let record = record.strip_suffix('\r').unwrap_or(record);
+    let mut fields = Vec::new();
+    let mut chars = record.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("unterminated quoted field".to_string()),
+                }
+            }
+            match chars.next() {
+                None => {
+                    fields.push(field);
+                    return Ok(fields);
+                }
+                Some(',') => fields.push(field),
+                Some(c) => return Err(format!("unexpected {:?} after a quoted field", c)),
+            }
+        } else {
+            loop {
+                match chars.next() {
+                    None => {
+                        fields.push(field);
+                        return Ok(fields);
+                    }
+                    Some(',') => break,
+                    Some('"') => return Err("quote inside an unquoted field".to_string()),
+                    Some(c) => field.push(c),
+                }
+            }
+            fields.push(field);
+        }
+    }
+}
+
+fn write_json_field(buf: &mut Vec<u8>, field: &str, raw: bool) {
+    if raw && is_json_number(field) {
+        buf.extend_from_slice(field.as_bytes());
+        return;
+    }
+    buf.push(b'"');
+    for c in field.chars() {
+        match c {
+            '"' => buf.extend_from_slice(b"\\\""),
+            '\\' => buf.extend_from_slice(b"\\\\"),
+            '\n' => buf.extend_from_slice(b"\\n"),
+            '\r' => buf.extend_from_slice(b"\\r"),
+            '\t' => buf.extend_from_slice(b"\\t"),
+            c if (c as u32) < 0x20 => buf.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes()),
+            c => {
+                let mut utf8 = [0; 4];
+                buf.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            }
+        }
+    }
+    buf.push(b'"');
+}
+
+/// Whether `text` is a number as JSON writes them: `-?(0|[1-9][0-9]*)`, then
+/// an optional fraction and exponent
+fn is_json_number(text: &str) -> bool {
+    let digits = |s: &str| s.bytes().take_while(u8::is_ascii_digit).count();
+    let rest = text.strip_prefix('-').unwrap_or(text);
+    let whole = digits(rest);
+    if whole == 0 || (whole > 1 && rest.starts_with('0')) {
+        return false;
+    }
+    let mut rest = &rest[whole..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let n = digits(fraction);
+        if n == 0 {
+            return false;
+        }
+        rest = &fraction[n..];
+    }
+    if let Some(exponent) = rest.strip_prefix(['e', 'E']) {
+        let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        let n = digits(exponent);
+        if n == 0 {
+            return false;
+        }
+        rest = &exponent[n..];
+    }
+    rest.is_empty()
+}
+
+/// Pull the `key` and `value` members out of a one-line JSON object.
+/// Strings are unescaped; any other JSON value is returned as written.
+fn parse_json_pair(line: &str) -> Result<(String, String), String> {
+    let mut rest = line.trim();
+    rest = rest.strip_prefix('{').ok_or("expected a JSON object")?;
+    let (mut key, mut value) = (None, None);
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('}') {
+            if !after.trim().is_empty() {
+                return Err("unexpected text after the object".to_string());
+            }
+            break;
+        }
+        let (name, after) = parse_json_string(rest)?;
+        rest = after.trim_start().strip_prefix(':').ok_or("expected ':' after a member name")?;
+        let (member, after) = parse_json_value(rest.trim_start())?;
+        rest = after.trim_start();
+        let slot = match name.as_str() {
+            "key" => &mut key,
+            "value" => &mut value,
+            _ => return Err(format!("unexpected member {:?}", name)),
+        };
+        if slot.replace(member).is_some() {
+            return Err(format!("duplicate member {:?}", name));
+        }
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after;
+        } else if !rest.starts_with('}') {
+            return Err("expected ',' or '}'".to_string());
+        }
+    }
+    Ok((key.ok_or("missing \"key\"")?, value.ok_or("missing \"value\"")?))
+}
+
+fn parse_json_value(input: &str) -> Result<(String, &str), String> {
+    if input.starts_with('"') {
+        return parse_json_string(input);
+    }
+    // Anything else runs to the next ',' or '}' outside brackets and strings
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '[' | '{' if !in_string => depth += 1,
+            ']' | '}' if !in_string && depth > 0 => depth -= 1,
+            ',' | '}' if !in_string && depth == 0 => {
+                let raw = input[..i].trim();
+                if raw.is_empty() {
+                    return Err("missing value".to_string());
+                }
+                return Ok((raw.to_string(), &input[i..]));
+            }
+            _ => {}
+        }
+    }
+    Err("unterminated object".to_string())
+}
+
+fn parse_json_string(input: &str) -> Result<(String, &str), String> {
+    let body = input.strip_prefix('"').ok_or("expected a string")?;
+    let mut out = String::new();
+    let mut chars = body.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &body[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => out.push('"'),
+                Some('\\') => out.push('\\'),
+                Some('/') => out.push('/'),
+                Some('b') => out.push('\u{8}'),
+                Some('f') => out.push('\u{c}'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some('u') => {
+                    let first = read_hex4(&mut chars)?;
+                    let code = if (0xd800..0xdc00).contains(&first) {
+                        // High surrogate: the low half must follow as another \u escape
+                        let escape: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                        let second = read_hex4(&mut chars)?;
+                        if escape != "\\u" || !(0xdc00..0xe000).contains(&second) {
+                            return Err("unpaired surrogate in string".to_string());
+                        }
+                        0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+                    } else {
+                        first
+                    };
+                    out.push(char::from_u32(code).ok_or("invalid \\u escape")?);
+                }
+                _ => return Err("bad escape in string".to_string()),
+            },
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+fn read_hex4(chars: &mut std::str::CharIndices<'_>) -> Result<u32, String> {
+    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+    if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("bad \\u escape {:?}", hex));
+    }
+    Ok(u32::from_str_radix(&hex, 16).expect("four hex digits"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the largest single write
+    #[derive(Default)]
+    struct Recorder {
+        bytes: Vec<u8>,
+        largest_write: usize,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.largest_write = self.largest_write.max(buf.len());
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn export_writes_large_segments_in_bounded_chunks() {
+        let data = MyData::<u64, String>::new(0, 2);
+        for key in 0..50_000u64 {
+            data.insert(key, "x".repeat(20));
+        }
+        let mut out = Recorder::default();
+        let mut callbacks = 0;
+        let done = export_csv(&data, &mut out, &Number, &PlainText, |_| callbacks += 1).unwrap();
+
+        assert_eq!(done.records, 50_000);
+        assert_eq!(done.bytes, out.bytes.len() as u64);
+        // One chunk may overshoot by the record that filled it
+        assert!(out.largest_write < EXPORT_CHUNK_BYTES + 64, "wrote {} bytes at once", out.largest_write);
+        assert!(callbacks > 2 * 2, "only {} progress callbacks", callbacks);
+
+        let copy = MyData::<u64, String>::new(1, 3);
+        let report = import_csv(&copy, out.bytes.as_slice(), &Number, &PlainText, |_| {}).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(copy.state_hash(), data.state_hash());
+    }
+
+    #[test]
+    fn progress_callback_can_write_to_the_exported_store() {
+        let data = MyData::<u64, String>::new(0, 2);
+        for key in 0..50_000u64 {
+            data.insert(key, "x".repeat(20));
+        }
+        let mut out = Vec::new();
+        let mut next = 0;
+        // Touches every shard while the encoders still have chunks to send
+        let done = export_csv(&data, &mut out, &Number, &PlainText, |_| {
+            for _ in 0..100 {
+                data.insert(next % 50_000, "y".to_string());
+                next += 1;
+            }
+        })
+        .unwrap();
+        assert_eq!(done.records, 50_000);
+    }
+
+    #[test]
+    fn export_stops_at_the_first_write_error() {
+        struct Failing;
+        impl Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let data = MyData::<u64, u64>::new(0, 2);
+        for key in 0..50_000u64 {
+            data.insert(key, key);
+        }
+        let error = export_jsonl(&data, Failing, &Number, &Number, |_| {}).unwrap_err();
+        assert_eq!(error.to_string(), "disk full");
+    }
+
+    #[test]
+    fn non_finite_numbers_are_quoted_in_json() {
+        let data = MyData::<u64, f64>::new(0, 2);
+        for (key, value) in [(1, f64::NAN), (2, f64::INFINITY), (3, f64::NEG_INFINITY), (4, -1.5)] {
+            data.insert(key, value);
+        }
+        let mut out = Vec::new();
+        export_jsonl(&data, &mut out, &Number, &Number, |_| {}).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("\"value\":\"NaN\""), "{}", text);
+        assert!(text.contains("\"value\":\"inf\""), "{}", text);
+        assert!(text.contains("\"value\":-1.5"), "{}", text);
+
+        let copy = MyData::<u64, f64>::new(1, 2);
+        let report = import_jsonl(&copy, text.as_bytes(), &Number, &Number, |_| {}).unwrap();
+        assert!(report.is_clean(), "{:?}", report.errors);
+        assert!(copy.get(&1).unwrap().is_nan());
+        assert_eq!(copy.get(&3).map(|v| *v), Some(f64::NEG_INFINITY));
+    }
+
+    #[test]
+    fn stray_quote_only_fails_its_own_record() {
+        let input = "key,value\nab\"c,1\nd,2\n\"e,f\",3\n\"multi\nline\",4\n";
+        let data = MyData::<String, u64>::new(0, 2);
+        let report = import_csv(&data, input.as_bytes(), &PlainText, &Number, |_| {}).unwrap();
+        assert_eq!(report.imported, 3);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 2);
+        assert_eq!(data.get(&"d".to_string()).map(|v| *v), Some(2));
+        assert_eq!(data.get(&"multi\nline".to_string()).map(|v| *v), Some(4));
+    }
+
+    #[test]
+    fn json_number_grammar() {
+        for ok in ["0", "-0", "12", "1.5", "-2.25e10", "3E-7", "1e+2"] {
+            assert!(is_json_number(ok), "{}", ok);
+        }
+        for bad in ["", "-", "01", "1.", ".5", "1e", "NaN", "inf", "-inf", "1_000", " 1"] {
+            assert!(!is_json_number(bad), "{}", bad);
+        }
+    }
+}
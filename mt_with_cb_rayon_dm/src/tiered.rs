@@ -0,0 +1,527 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::codec::Codec;
+use crate::counter::StripedCounter;
+use crate::data_structures::MyData;
+
+/// Configuration for the disk tier
+#[derive(Debug, Clone)]
+pub struct TierConfig {
+    /// Directory holding one cold file per segment. Several stores, even
+    /// with the same id, can share it.
+    pub directory: PathBuf,
+    /// Maximum number of entries kept in memory
+    pub memory_budget: usize,
+    /// Entries not read or written for this long are spilled even under budget
+    pub cold_after: Duration,
+    /// How often the background thread looks for entries to spill
+    pub spill_interval: Duration,
+}
+
+/// A value in the hot tier together with its last access time
+#[derive(Clone)]
+struct HotEntry<V> {
+    value: V,
+    // Shared so reads can refresh it under a read lock; also identifies this
+    // particular insert when the spiller removes the entry
+    last_access: Arc<AtomicU64>,
+}
+
+// A cold file is rewritten once more than this fraction of it is dead space...
+const COMPACT_DEAD_FRACTION: f64 = 0.5;
+// ...and it is at least this big, so small files are not rewritten constantly
+const COMPACT_MIN_BYTES: u64 = 64 * 1024;
+
+// Numbers each store in this process, so stores with the same id never share files
+static NEXT_INSTANCE: AtomicUsize = AtomicUsize::new(0);
+
+/// On-disk part of one segment: an append-only file and an index into it.
+/// Overwritten and removed values leave dead space behind, which is
+/// reclaimed by rewriting the file once it makes up most of it.
+struct ColdSegment<K> {
+    file: File,
+    path: PathBuf,
+    end: u64,
+    // Bytes of the file still referenced by `index`
+    live: u64,
+    // key -> (offset, length) of the encoded value
+    index: HashMap<K, (u64, u64)>,
+}
+
+impl<K: Hash + Eq> ColdSegment<K> {
+    fn append(&mut self, bytes: &[u8]) -> io::Result<(u64, u64)> {
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(bytes)?;
+        let location = (self.end, bytes.len() as u64);
+        self.end += bytes.len() as u64;
+        Ok(location)
+    }
+
+    fn read(&mut self, (offset, len): (u64, u64)) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Point `key` at a freshly appended value
+    fn record(&mut self, key: K, location: (u64, u64)) {
+        self.live += location.1;
+        if let Some((_, old_len)) = self.index.insert(key, location) {
+            self.live -= old_len;
+        }
+    }
+
+    /// Drop `key` from the index; its bytes become dead space
+    fn forget(&mut self, key: &K) -> Option<(u64, u64)> {
+        let location = self.index.remove(key)?;
+        self.live -= location.1;
+        Some(location)
+    }
+
+    /// Rewrite the file with only the live values if dead space has grown
+    /// past `COMPACT_DEAD_FRACTION`. Returns whether it was rewritten.
+    fn maybe_compact(&mut self) -> io::Result<bool> {
+        let dead = self.end - self.live;
+        if self.end < COMPACT_MIN_BYTES || (dead as f64) < self.end as f64 * COMPACT_DEAD_FRACTION {
+            return Ok(false);
+        }
+
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let locations: Vec<(u64, u64)> = self.index.values().copied().collect();
+        let mut moved = HashMap::with_capacity(locations.len());
+        let mut end = 0;
+        for location in locations {
+            let bytes = self.read(location)?;
+            tmp.write_all(&bytes)?;
+            moved.insert(location, (end, location.1));
+            end += location.1;
+        }
+        tmp.flush()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        for location in self.index.values_mut() {
+            *location = moved[location];
+        }
+        self.file = tmp;
+        self.end = end;
+        self.live = end;
+        Ok(true)
+    }
+}
+
+#[derive(Default)]
+struct TierCounters {
+    hot_hits: StripedCounter,
+    cold_hits: StripedCounter,
+    misses: StripedCounter,
+    spilled: AtomicUsize,
+    spill_errors: AtomicUsize,
+    compactions: AtomicUsize,
+}
+
+/// A point-in-time snapshot of tier statistics
+#[derive(Debug, Clone, Copy)]
+pub struct TierStats {
+    pub hot_entries: usize,
+    pub cold_entries: usize,
+    pub hot_hits: usize,
+    pub cold_hits: usize,
+    pub misses: usize,
+    pub spilled: usize,
+    pub spill_errors: usize,
+    /// Cold files rewritten to reclaim dead space
+    pub compactions: usize,
+    /// Bytes on disk, including dead space not yet reclaimed
+    pub cold_bytes: u64,
+}
+
+impl TierStats {
+    fn rate(hits: usize, total: usize) -> f64 {
+        if total == 0 {
+            0.0
+        } else {
+            hits as f64 / total as f64
+        }
+    }
+
+    /// Fraction of lookups served from memory
+    pub fn hot_hit_rate(&self) -> f64 {
+        Self::rate(self.hot_hits, self.hot_hits + self.cold_hits + self.misses)
+    }
+
+    /// Fraction of lookups that had to be faulted back from disk
+    pub fn cold_hit_rate(&self) -> f64 {
+        Self::rate(self.cold_hits, self.hot_hits + self.cold_hits + self.misses)
+    }
+}
+
+/// A `MyData` whose rarely used entries are moved to per-segment files on disk
+/// and transparently faulted back in on `get`
+pub struct TieredData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    hot: MyData<K, HotEntry<V>>,
+    cold: Vec<Mutex<ColdSegment<K>>>,
+    config: TierConfig,
+    clock: Instant,
+    counters: TierCounters,
+    stop: AtomicBool,
+}
+
+impl<K, V> TieredData<K, V>
+where
+    K: Codec + Hash + Eq + Clone + Send + Sync + 'static,
+    V: Codec + Clone + Send + Sync + 'static,
+{
+    /// Create the store and start its background spill thread.
+    /// The thread stops on its own once the store is dropped.
+    pub fn new(id: usize, num_segments: usize, config: TierConfig) -> io::Result<Arc<Self>> {
+        fs::create_dir_all(&config.directory)?;
+        let instance = NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed);
+        let mut cold = Vec::with_capacity(num_segments);
+        for idx in 0..num_segments {
+            let name = format!("segment-{}-{}-{}-{}.cold", id, std::process::id(), instance, idx);
+            let path = config.directory.join(name);
+            // Never take over a file some other store is using
+            let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+            cold.push(Mutex::new(ColdSegment {
+                file,
+                path,
+                end: 0,
+                live: 0,
+                index: HashMap::new(),
+            }));
+        }
+
+        let tiered = Arc::new(TieredData {
+            hot: MyData::new(id, num_segments),
+            cold,
+            config,
+            clock: Instant::now(),
+            counters: TierCounters::default(),
+            stop: AtomicBool::new(false),
+        });
+
+        let weak = Arc::downgrade(&tiered);
+        let interval = tiered.config.spill_interval;
+        thread::spawn(move || spill_loop(weak, interval));
+
+        Ok(tiered)
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.elapsed().as_millis() as u64
+    }
+
+    /// Insert a key-value pair into the hot tier
+    pub fn insert(&self, key: K, value: V) {
+        let segment_idx = self.hot.get_segment_index(&key);
+        // Hold the cold segment throughout, so a concurrent `get` cannot fault
+        // the old value back in over this one
+        let mut cold = self.cold[segment_idx].lock().unwrap();
+        self.hot.insert(
+            key.clone(),
+            HotEntry {
+                value,
+                last_access: Arc::new(AtomicU64::new(self.now())),
+            },
+        );
+        // Any older copy on disk is now stale; the spiller reclaims the space
+        cold.forget(&key);
+    }
+
+    /// Get a value, reading it back from disk if it was spilled.
+    /// A cold value that cannot be read or decoded is an error, not a miss,
+    /// and stays on disk.
+    pub fn get(&self, key: &K) -> io::Result<Option<V>> {
+        if let Some(entry) = self.hot.get(key) {
+            entry.last_access.store(self.now(), Ordering::Relaxed);
+            self.counters.hot_hits.increment();
+            return Ok(Some(entry.value.clone()));
+        }
+
+        let segment_idx = self.hot.get_segment_index(key);
+        let mut cold = self.cold[segment_idx].lock().unwrap();
+
+        // Another thread may have faulted it in while we waited for the lock
+        if let Some(entry) = self.hot.get(key) {
+            self.counters.hot_hits.increment();
+            return Ok(Some(entry.value.clone()));
+        }
+
+        let location = match cold.index.get(key) {
+            Some(&location) => location,
+            None => {
+                self.counters.misses.increment();
+                return Ok(None);
+            }
+        };
+        let value = V::decode(&mut cold.read(location)?.as_slice())?;
+
+        cold.forget(key);
+        self.hot.insert(
+            key.clone(),
+            HotEntry {
+                value: value.clone(),
+                last_access: Arc::new(AtomicU64::new(self.now())),
+            },
+        );
+        self.counters.cold_hits.increment();
+        Ok(Some(value))
+    }
+
+    /// Remove a key from both tiers, returning the value it held. The key is
+    /// removed even if its cold value cannot be read back, which is an error.
+    pub fn remove(&self, key: &K) -> io::Result<Option<V>> {
+        let segment_idx = self.hot.get_segment_index(key);
+        let mut cold = self.cold[segment_idx].lock().unwrap();
+        let location = cold.forget(key);
+        if let Some((_, entry)) = self.hot.remove(key) {
+            return Ok(Some(entry.value));
+        }
+        match location {
+            Some(location) => Ok(Some(V::decode(&mut cold.read(location)?.as_slice())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reclaim dead space in a cold file if it has grown too large
+    fn compact(&self, cold: &mut ColdSegment<K>) {
+        match cold.maybe_compact() {
+            Ok(true) => {
+                self.counters.compactions.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(false) => {}
+            // The old file and index are still intact; try again next time
+            Err(_) => {
+                self.counters.spill_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Total number of entries across both tiers
+    pub fn len(&self) -> usize {
+        self.hot.len() + self.cold.iter().map(|c| c.lock().unwrap().index.len()).sum::<usize>()
+    }
+
+    /// Whether both tiers are empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Move cold entries to disk now: everything idle for longer than
+    /// `cold_after`, then the least recently used entries until the hot tier
+    /// fits the memory budget. Then compact cold files that are mostly dead
+    /// space. Returns the number of entries spilled.
+    pub fn spill(&self) -> usize {
+        let now = self.now();
+        let cold_after = self.config.cold_after.as_millis() as u64;
+
+        // (segment, key, last access, identity of the hot entry)
+        let mut candidates = Vec::new();
+        for idx in 0..self.hot.num_segments() {
+            if let Some(segment) = self.hot.get_segment(idx) {
+                for entry in segment.iter() {
+                    let accessed = entry.last_access.load(Ordering::Relaxed);
+                    candidates.push((idx, entry.key().clone(), accessed, Arc::clone(&entry.last_access)));
+                }
+            }
+        }
+        candidates.sort_by_key(|c| c.2);
+
+        let over_budget = candidates.len().saturating_sub(self.config.memory_budget);
+        let mut spilled = 0;
+        for (position, (idx, key, accessed, identity)) in candidates.into_iter().enumerate() {
+            let idle = now.saturating_sub(accessed) >= cold_after;
+            if position >= over_budget && !idle {
+                // Sorted by access time, so nothing later is idle either
+                break;
+            }
+            match self.spill_one(idx, &key, &identity) {
+                Ok(true) => spilled += 1,
+                Ok(false) => {}
+                Err(_) => {
+                    self.counters.spill_errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        self.counters.spilled.fetch_add(spilled, Ordering::Relaxed);
+        for cold in &self.cold {
+            self.compact(&mut cold.lock().unwrap());
+        }
+        spilled
+    }
+
+    /// Write one entry to disk and drop it from memory, unless it was replaced meanwhile
+    fn spill_one(&self, idx: usize, key: &K, identity: &Arc<AtomicU64>) -> io::Result<bool> {
+        let mut cold = self.cold[idx].lock().unwrap();
+        let Some(segment) = self.hot.get_segment(idx) else {
+            return Ok(false);
+        };
+
+        let mut bytes = Vec::new();
+        match segment.get(key) {
+            Some(entry) if Arc::ptr_eq(&entry.last_access, identity) => entry.value.encode(&mut bytes),
+            _ => return Ok(false),
+        }
+
+        let location = cold.append(&bytes)?;
+        // Only remove the exact entry we wrote; a concurrent insert wins
+        if segment
+            .remove_if(key, |_, entry| Arc::ptr_eq(&entry.last_access, identity))
+            .is_some()
+        {
+            cold.record(key.clone(), location);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Take a snapshot of the tier statistics
+    pub fn stats(&self) -> TierStats {
+        TierStats {
+            hot_entries: self.hot.len(),
+            cold_entries: self.cold.iter().map(|c| c.lock().unwrap().index.len()).sum(),
+            hot_hits: self.counters.hot_hits.sum(),
+            cold_hits: self.counters.cold_hits.sum(),
+            misses: self.counters.misses.sum(),
+            spilled: self.counters.spilled.load(Ordering::Relaxed),
+            spill_errors: self.counters.spill_errors.load(Ordering::Relaxed),
+            compactions: self.counters.compactions.load(Ordering::Relaxed),
+            cold_bytes: self.cold.iter().map(|c| c.lock().unwrap().end).sum(),
+        }
+    }
+
+    /// Stop the background spill thread
+    pub fn stop_spilling(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Background loop that spills cold entries until the store goes away
+fn spill_loop<K, V>(store: Weak<TieredData<K, V>>, interval: Duration)
+where
+    K: Codec + Hash + Eq + Clone + Send + Sync + 'static,
+    V: Codec + Clone + Send + Sync + 'static,
+{
+    loop {
+        thread::sleep(interval);
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        if store.stop.load(Ordering::Relaxed) {
+            return;
+        }
+        store.spill();
+    }
+}
+
+impl<K, V> Drop for TieredData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        // The cold files only make sense together with the in-memory index
+        for segment in &self.cold {
+            if let Ok(segment) = segment.lock() {
+                let _ = fs::remove_file(&segment.path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(name: &str) -> TierConfig {
+        TierConfig {
+            directory: std::env::temp_dir().join(format!("tiered-test-{}-{}", name, std::process::id())),
+            memory_budget: 0,
+            cold_after: Duration::from_secs(3600),
+            spill_interval: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn overwrites_compact_the_cold_file() {
+        let tiered = TieredData::<u64, String>::new(0, 1, config("compact")).unwrap();
+        tiered.stop_spilling();
+        let big = "x".repeat(8 * 1024);
+        for round in 0..64 {
+            tiered.insert(1, format!("{}{}", big, round));
+            assert_eq!(tiered.spill(), 1);
+        }
+
+        let stats = tiered.stats();
+        assert!(stats.compactions > 0);
+        assert!(stats.cold_bytes < COMPACT_MIN_BYTES * 2);
+        assert_eq!(tiered.get(&1).unwrap(), Some(format!("{}{}", big, 63)));
+    }
+
+    #[test]
+    fn stores_with_the_same_id_keep_separate_files() {
+        let first = TieredData::<u64, u64>::new(0, 2, config("shared")).unwrap();
+        let second = TieredData::<u64, u64>::new(0, 2, config("shared")).unwrap();
+        first.stop_spilling();
+        second.stop_spilling();
+        first.insert(1, 10);
+        second.insert(1, 20);
+        first.spill();
+        second.spill();
+        assert_eq!(first.get(&1).unwrap(), Some(10));
+        assert_eq!(second.get(&1).unwrap(), Some(20));
+    }
+
+    #[test]
+    fn unreadable_cold_values_are_errors() {
+        let tiered = TieredData::<u64, String>::new(0, 1, config("corrupt")).unwrap();
+        tiered.stop_spilling();
+        tiered.insert(1, "value".to_string());
+        assert_eq!(tiered.spill(), 1);
+        // Cut the file short under the store
+        tiered.cold[0].lock().unwrap().file.set_len(1).unwrap();
+
+        assert!(tiered.get(&1).is_err());
+        // Still indexed, so it is not mistaken for a missing key
+        assert!(tiered.get(&1).is_err());
+        assert_eq!(tiered.get(&2).unwrap(), None);
+        assert!(tiered.remove(&1).is_err());
+        assert_eq!(tiered.get(&1).unwrap(), None);
+    }
+
+    #[test]
+    fn insert_is_not_undone_by_a_concurrent_fault_in() {
+        let tiered = TieredData::<u64, u64>::new(0, 1, config("race")).unwrap();
+        tiered.stop_spilling();
+        for round in 0..200 {
+            tiered.insert(7, round);
+            tiered.spill();
+            thread::scope(|s| {
+                s.spawn(|| tiered.get(&7).unwrap());
+                s.spawn(|| tiered.insert(7, round + 1000));
+            });
+            assert_eq!(tiered.get(&7).unwrap(), Some(round + 1000));
+        }
+    }
+}
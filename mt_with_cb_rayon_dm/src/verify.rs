@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// One inconsistency found by `MyData::verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault<K> {
+    /// Stored in a segment other than the one its key hashes to
+    WrongSegment { key: K, found_in: usize, belongs_in: usize },
+    /// In the right segment but the wrong shard of it, so lookups miss it
+    Unreachable { key: K, segment: usize },
+    /// A segment's `len()` disagrees with the entries actually stored
+    CountMismatch { segment: usize, reported: usize, counted: usize },
+    /// A lease holds a fencing token the store has not handed out yet, so a
+    /// later grant would repeat it
+    FenceAhead { key: K, fence: u64, next: u64 },
+    /// A lease shares its fencing token with another key's lease
+    DuplicateFence { key: K, fence: u64 },
+}
+
+/// A checksum taken earlier with `state_hash`, against the current contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumCheck {
+    pub expected: u64,
+    /// Hash of the contents as found, before any repair
+    pub actual: u64,
+    /// Hash once the repair was done, if it changed anything
+    pub after_repair: Option<u64>,
+}
+
+impl ChecksumCheck {
+    pub fn matches(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+/// Result of a scrub
+#[derive(Debug, Clone)]
+pub struct VerifyReport<K> {
+    pub segments: usize,
+    /// Entries found by walking every shard
+    pub entries: usize,
+    /// Everything found wrong, before any repair
+    pub faults: Vec<Fault<K>>,
+    /// Misplaced entries moved to where lookups find them
+    pub repaired: usize,
+    /// Misplaced copies discarded because their key already existed where it belongs
+    pub dropped: usize,
+    pub checksum: Option<ChecksumCheck>,
+}
+
+impl<K> VerifyReport<K> {
+    /// No faults were found and the checksum, if any, matched
+    pub fn is_consistent(&self) -> bool {
+        self.faults.is_empty() && self.checksum.is_none_or(|c| c.matches())
+    }
+}
+
+impl<K: fmt::Debug> fmt::Display for VerifyReport<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} entries in {} segments: {}",
+            self.entries,
+            self.segments,
+            if self.is_consistent() { "consistent" } else { "INCONSISTENT" }
+        )?;
+        for fault in &self.faults {
+            match fault {
+                Fault::WrongSegment { key, found_in, belongs_in } => {
+                    writeln!(f, "  {:?} is in segment {} but belongs in {}", key, found_in, belongs_in)?
+                }
+                Fault::Unreachable { key, segment } => {
+                    writeln!(f, "  {:?} is in the wrong shard of segment {}", key, segment)?
+                }
+                Fault::CountMismatch { segment, reported, counted } => writeln!(
+                    f,
+                    "  segment {} reports {} entries but holds {}",
+                    segment, reported, counted
+                )?,
+                Fault::FenceAhead { key, fence, next } => writeln!(
+                    f,
+                    "  lease on {:?} has fencing token {} but the next one to hand out is {}",
+                    key, fence, next
+                )?,
+                Fault::DuplicateFence { key, fence } => {
+                    writeln!(f, "  lease on {:?} reuses fencing token {}", key, fence)?
+                }
+            }
+        }
+        if let Some(check) = &self.checksum {
+            writeln!(
+                f,
+                "  checksum {:016x}, expected {:016x}: {}",
+                check.actual,
+                check.expected,
+                if check.matches() { "ok" } else { "MISMATCH" }
+            )?;
+            if let Some(after) = check.after_repair {
+                writeln!(f, "  checksum after repair {:016x}", after)?;
+            }
+        }
+        write!(f, "  repaired {}, dropped {}", self.repaired, self.dropped)
+    }
+}
@@ -0,0 +1,324 @@
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::data_structures::MyData;
+
+/// How much history to keep per key. The latest version of a key is
+/// always kept; older ones are dropped once they fall outside either limit.
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    /// Keep at most this many versions per key
+    pub max_versions: Option<usize>,
+    /// Keep versions written within this window
+    pub max_age: Option<Duration>,
+    /// How often the background thread prunes old versions
+    pub gc_interval: Duration,
+}
+
+impl HistoryConfig {
+    /// Keep the last `n` versions of every key
+    pub fn last(n: usize) -> Self {
+        HistoryConfig {
+            max_versions: Some(n.max(1)),
+            max_age: None,
+            gc_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Keep every version written within `window`
+    pub fn within(window: Duration) -> Self {
+        HistoryConfig {
+            max_versions: None,
+            max_age: Some(window),
+            gc_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Also drop all but the last `n` versions
+    pub fn and_last(mut self, n: usize) -> Self {
+        self.max_versions = Some(n.max(1));
+        self
+    }
+
+    pub fn with_gc_interval(mut self, interval: Duration) -> Self {
+        self.gc_interval = interval;
+        self
+    }
+}
+
+/// One retained version of a key. `value` is `None` when the key was removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Version<V> {
+    pub version: u64,
+    pub written_at: SystemTime,
+    pub value: Option<V>,
+}
+
+/// Point in history to read at
+#[derive(Debug, Clone, Copy)]
+pub enum At {
+    /// The state after the write that produced this version number
+    Version(u64),
+    /// The state at this wall-clock time
+    Time(SystemTime),
+}
+
+/// A `MyData` that keeps older versions of every key for time-travel reads
+pub struct VersionedData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    // Versions of each key, oldest first
+    data: MyData<K, Vec<Version<V>>>,
+    clock: AtomicU64,
+    config: HistoryConfig,
+    pruned: AtomicUsize,
+    // Keys gc has removed outright; bumped before each removal
+    collected_keys: AtomicU64,
+    stop: AtomicBool,
+}
+
+impl<K, V> VersionedData<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Create the store and start its background garbage collector.
+    /// The collector stops on its own once the store is dropped.
+    pub fn new(id: usize, num_segments: usize, config: HistoryConfig) -> Arc<Self> {
+        let store = Arc::new(VersionedData {
+            data: MyData::new(id, num_segments),
+            clock: AtomicU64::new(0),
+            config,
+            pruned: AtomicUsize::new(0),
+            collected_keys: AtomicU64::new(0),
+            stop: AtomicBool::new(false),
+        });
+
+        let weak = Arc::downgrade(&store);
+        let interval = store.config.gc_interval;
+        thread::spawn(move || gc_loop(weak, interval));
+        store
+    }
+
+    /// The version number of the most recent write
+    pub fn current_version(&self) -> u64 {
+        self.clock.load(Ordering::Acquire)
+    }
+
+    /// Insert a value, returning the version number of the write.
+    /// The version is assigned under the key's entry lock, so the versions
+    /// of a key are always in increasing order.
+    pub fn insert(&self, key: K, value: V) -> u64 {
+        let idx = self.data.get_segment_index(&key);
+        let segment = self.data.get_segment(idx).expect("segment index in range");
+        let mut versions = segment.entry(key).or_default();
+        let version = self.clock.fetch_add(1, Ordering::AcqRel) + 1;
+        versions.push(Version {
+            version,
+            written_at: SystemTime::now(),
+            value: Some(value),
+        });
+        version
+    }
+
+    /// Remove a key, keeping its history. Returns the value it held.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let idx = self.data.get_segment_index(key);
+        let segment = self.data.get_segment(idx)?;
+        let mut versions = segment.get_mut(key)?;
+        let previous = versions.last()?.value.clone()?;
+        let version = self.clock.fetch_add(1, Ordering::AcqRel) + 1;
+        versions.push(Version {
+            version,
+            written_at: SystemTime::now(),
+            value: None,
+        });
+        Some(previous)
+    }
+
+    /// Get the current value of a key
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.data.get(key)?.last()?.value.clone()
+    }
+
+    /// The most recent version of a key, including removals
+    pub fn latest(&self, key: &K) -> Option<Version<V>> {
+        self.data.get(key)?.last().cloned()
+    }
+
+    /// The most recent version of a key written at or before `version`.
+    /// `None` if the key was first written later or that history was collected.
+    pub fn latest_at(&self, key: &K, version: u64) -> Option<Version<V>> {
+        self.data.get(key)?.iter().rev().find(|v| v.version <= version).cloned()
+    }
+
+    /// Get the value a key held at a point in history. Returns `None` if the
+    /// key did not exist then, or if that part of its history was collected.
+    pub fn get_at(&self, key: &K, at: At) -> Option<V> {
+        let versions = self.data.get(key)?;
+        let visible = versions.iter().rev().find(|v| match at {
+            At::Version(version) => v.version <= version,
+            At::Time(time) => v.written_at <= time,
+        })?;
+        visible.value.clone()
+    }
+
+    /// All retained versions of a key, oldest first
+    pub fn history(&self, key: &K) -> Vec<Version<V>> {
+        self.data.get(key).map(|v| v.clone()).unwrap_or_default()
+    }
+
+    /// Number of keys that currently have a value
+    pub fn len(&self) -> usize {
+        self.count(|versions| versions.last().is_some_and(|v| v.value.is_some()) as usize)
+    }
+
+    /// Total number of versions retained across all keys
+    pub fn retained_versions(&self) -> usize {
+        self.count(|versions| versions.len())
+    }
+
+    fn count(&self, f: impl Fn(&Vec<Version<V>>) -> usize) -> usize {
+        let mut total = 0;
+        for idx in 0..self.data.num_segments() {
+            if let Some(segment) = self.data.get_segment(idx) {
+                total += segment.iter().map(|entry| f(entry.value())).sum::<usize>();
+            }
+        }
+        total
+    }
+
+    /// Number of versions dropped by garbage collection so far
+    pub fn pruned_versions(&self) -> usize {
+        self.pruned.load(Ordering::Relaxed)
+    }
+
+    /// Number of keys garbage collection has removed along with all their
+    /// history. A caller that finds a key missing can compare this later to
+    /// tell whether the key may have existed and been collected in between.
+    pub fn collected_keys(&self) -> u64 {
+        self.collected_keys.load(Ordering::Acquire)
+    }
+
+    /// Drop versions outside the retention limits now. Keys whose only
+    /// remaining version is an expired tombstone are removed entirely.
+    /// Returns the number of versions dropped.
+    pub fn gc(&self) -> usize {
+        let cutoff = self
+            .config
+            .max_age
+            .and_then(|age| SystemTime::now().checked_sub(age));
+        let mut dropped = 0;
+
+        for idx in 0..self.data.num_segments() {
+            let Some(segment) = self.data.get_segment(idx) else {
+                continue;
+            };
+            segment.retain(|_, versions| {
+                let before = versions.len();
+                let mut keep_from = 0;
+                if let Some(max) = self.config.max_versions {
+                    keep_from = before.saturating_sub(max);
+                }
+                if let Some(cutoff) = cutoff {
+                    // Keep the version that was live at the cutoff along with
+                    // everything written after it, and always the latest version
+                    let in_window = versions
+                        .iter()
+                        .position(|v| v.written_at >= cutoff)
+                        .unwrap_or(before);
+                    keep_from = keep_from.max(in_window.saturating_sub(1).min(before - 1));
+                }
+                versions.drain(..keep_from);
+                dropped += before - versions.len();
+
+                let expired_tombstone = versions.len() == 1
+                    && versions[0].value.is_none()
+                    && cutoff.is_some_and(|c| versions[0].written_at < c);
+                if expired_tombstone {
+                    dropped += 1;
+                    // Still under the shard lock, so anyone who sees the key
+                    // gone also sees the new count
+                    self.collected_keys.fetch_add(1, Ordering::AcqRel);
+                }
+                !expired_tombstone
+            });
+        }
+
+        self.pruned.fetch_add(dropped, Ordering::Relaxed);
+        dropped
+    }
+
+    /// Stop the background garbage collector
+    pub fn stop_gc(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Background loop that prunes history until the store goes away
+fn gc_loop<K, V>(store: Weak<VersionedData<K, V>>, interval: Duration)
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    loop {
+        thread::sleep(interval);
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        if store.stop.load(Ordering::Relaxed) {
+            return;
+        }
+        store.gc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gc_by_age_keeps_the_version_live_at_the_cutoff() {
+        let config = HistoryConfig::within(Duration::from_millis(100)).with_gc_interval(Duration::from_secs(3600));
+        let store = VersionedData::<u64, u64>::new(0, 4, config);
+        store.insert(1, 0);
+        thread::sleep(Duration::from_millis(60));
+        store.insert(1, 1);
+        thread::sleep(Duration::from_millis(150));
+        store.insert(1, 2);
+        let [first, second, _] = <[Version<u64>; 3]>::try_from(store.history(&1)).unwrap();
+
+        assert_eq!(store.gc(), 1);
+        let values: Vec<_> = store.history(&1).into_iter().map(|v| v.value).collect();
+        assert_eq!(values, vec![Some(1), Some(2)]);
+        // Just inside the window the older value is still readable
+        let inside = SystemTime::now() - Duration::from_millis(90);
+        assert_eq!(store.get_at(&1, At::Time(inside)), Some(1));
+        assert_eq!(store.get_at(&1, At::Time(second.written_at)), Some(1));
+        // Outside it the history is gone
+        assert_eq!(store.get_at(&1, At::Time(first.written_at)), None);
+        assert_eq!(store.get(&1), Some(2));
+    }
+
+    #[test]
+    fn gc_trims_to_max_versions() {
+        let store = VersionedData::<u64, u64>::new(0, 4, HistoryConfig::last(2).with_gc_interval(Duration::from_secs(3600)));
+        for value in 1..=5 {
+            store.insert(7, value);
+        }
+        store.insert(8, 1);
+
+        assert_eq!(store.gc(), 3);
+        assert_eq!(store.retained_versions(), 3);
+        assert_eq!(store.get_at(&7, At::Version(3)), None);
+        assert_eq!(store.get_at(&7, At::Version(4)), Some(4));
+        assert_eq!(store.get(&7), Some(5));
+        assert_eq!(store.get(&8), Some(1));
+        assert_eq!(store.pruned_versions(), 3);
+    }
+}
@@ -1,18 +1,108 @@
-use crossbeam::channel::{Receiver, Sender};
+use crossbeam::channel::{RecvTimeoutError, Sender};
 use rayon::prelude::*;
 use std::hash::Hash;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use dashmap::DashMap;
+use crate::cancellation::{OpContext, OpError, OpResult};
 use crate::data_structures::MyData;
+use crate::queue::{Evictable, WorkerReceiver};
+use crate::query::CompiledQuery;
+use crate::supervisor::panic_message;
 
-/// Different operation types that can be performed on the data structure
+/// A shareable predicate used by `Operation::Find`
+pub type Predicate<K, V> = Arc<dyn Fn(&K, &V) -> bool + Send + Sync>;
+
+/// Different operation types that can be performed on the data structure.
+/// Replies carry an `OpResult` so callers can tell a skipped request apart
+/// from one that ran.
 pub enum Operation<K, V> {
     Insert(K, V),
     Remove(K),
-    Get(K, Sender<Option<V>>),
-    Find(Arc<dyn Fn(&K, &V) -> bool + Send + Sync>, Sender<Vec<(K, V)>>),
+    Get(K, Sender<OpResult<Option<V>>>),
+    Find(Predicate<K, V>, Sender<OpResult<Vec<(K, V)>>>),
+    /// Like `Find`, but described by a query that can be logged or sent over the wire
+    Query(CompiledQuery<K, V>, Sender<OpResult<Vec<(K, V)>>>),
     Clear,
+    /// Atomically replace the whole contents with these entries
+    ReplaceAll(Vec<(K, V)>),
     Shutdown,
+    /// Run the inner operation only if its deadline has not passed and it has not
+    /// been cancelled. Nested contexts combine, so the earliest deadline and every
+    /// token apply. A wrapped `Shutdown` always stops the worker.
+    WithContext(OpContext, Box<Operation<K, V>>),
+}
+
+impl<K, V> Operation<K, V> {
+    /// Attach a deadline and/or cancellation token to this operation
+    pub fn with_context(self, ctx: OpContext) -> Self {
+        Operation::WithContext(ctx, Box::new(self))
+    }
+
+    /// Whether this is a `Shutdown`, possibly wrapped in contexts
+    pub(crate) fn is_shutdown(&self) -> bool {
+        match self {
+            Operation::Shutdown => true,
+            Operation::WithContext(_, inner) => inner.is_shutdown(),
+            _ => false,
+        }
+    }
+
+    /// Tell whoever is waiting for a reply that the operation was skipped
+    fn reject(self, error: OpError) {
+        match self {
+            Operation::Get(_, sender) => {
+                let _ = sender.send(Err(error));
+            }
+            Operation::Find(_, sender) | Operation::Query(_, sender) => {
+                let _ = sender.send(Err(error));
+            }
+            Operation::WithContext(_, inner) => inner.reject(error),
+            Operation::Insert(..)
+            | Operation::Remove(_)
+            | Operation::Clear
+            | Operation::ReplaceAll(_)
+            | Operation::Shutdown => {}
+        }
+    }
+}
+
+impl<K, V> Evictable for Operation<K, V> {
+    fn evictable(&self) -> bool {
+        match self {
+            Operation::Insert(..) | Operation::Remove(_) | Operation::Clear | Operation::ReplaceAll(_) => true,
+            // Somebody is waiting for the reply, or the worker has to see it
+            Operation::Get(..) | Operation::Find(..) | Operation::Query(..) | Operation::Shutdown => false,
+            Operation::WithContext(_, inner) => inner.evictable(),
+        }
+    }
+}
+
+/// Anything a worker loop can pull operations from
+pub trait OperationSource<K, V>: Send + 'static {
+    /// Wait up to `timeout` for the next operation
+    fn recv_timeout(&self, timeout: Duration) -> Result<Operation<K, V>, RecvTimeoutError>;
+
+    /// Take the next operation if one is immediately available
+    fn try_recv(&self) -> Option<Operation<K, V>>;
+}
+
+impl<K, V> OperationSource<K, V> for WorkerReceiver<Operation<K, V>>
+where
+    K: Send + 'static,
+    V: Send + 'static,
+{
+    fn recv_timeout(&self, timeout: Duration) -> Result<Operation<K, V>, RecvTimeoutError> {
+        WorkerReceiver::recv_timeout(self, timeout)
+    }
+
+    fn try_recv(&self) -> Option<Operation<K, V>> {
+        WorkerReceiver::try_recv(self).ok()
+    }
 }
 
 /// Process a batch of keys in parallel using Rayon
@@ -35,11 +125,71 @@ where
     }).collect()
 }
 
+/// Apply a single operation to the data structure.
+/// Returns `false` when the operation asks the worker to stop.
+pub fn apply_operation<K, V>(data: &MyData<K, V>, operation: Operation<K, V>) -> bool
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    apply_with_context(data, operation, &OpContext::new())
+}
+
+fn apply_with_context<K, V>(data: &MyData<K, V>, operation: Operation<K, V>, ctx: &OpContext) -> bool
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    match operation {
+        Operation::Insert(key, value) => {
+            data.insert(key, value);
+        }
+        Operation::Remove(key) => {
+            data.remove(&key);
+        }
+        Operation::Get(key, sender) => {
+            let value = data.get(&key).map(|r| r.value().clone());
+            let _ = sender.send(Ok(value));
+        }
+        Operation::Find(predicate, sender) => {
+            let results = data.find_cancellable(|k, v| predicate(k, v), ctx);
+            let _ = sender.send(results);
+        }
+        Operation::Query(query, sender) => {
+            let results = query.run_cancellable(data, ctx);
+            let _ = sender.send(results);
+        }
+        Operation::Clear => {
+            data.clear();
+        }
+        Operation::ReplaceAll(entries) => {
+            data.replace_all(entries);
+        }
+        Operation::Shutdown => {
+            return false;
+        }
+        Operation::WithContext(inner_ctx, inner) => {
+            // A stop request is never skipped, or the worker would outlive its deadline
+            if inner.is_shutdown() {
+                return false;
+            }
+            // Expired or cancelled requests are skipped without touching the data
+            let ctx = inner_ctx.within(ctx);
+            if let Err(error) = ctx.check() {
+                inner.reject(error);
+                return true;
+            }
+            return apply_with_context(data, *inner, &ctx);
+        }
+    }
+    true
+}
+
 /// Create a worker function that processes operations from a channel
 pub fn create_worker_fn<K, V>(
     data: Arc<MyData<K, V>>,
-    receiver: Receiver<Operation<K, V>>,
-) -> impl FnOnce() -> ()
+    receiver: WorkerReceiver<Operation<K, V>>,
+) -> impl FnOnce()
 where
     K: Hash + Eq + Clone + Send + Sync + 'static,
     V: Clone + Send + Sync + 'static,
@@ -47,27 +197,8 @@ where
     move || {
         // Process operations until shutdown signal is received
         for operation in receiver {
-            match operation {
-                Operation::Insert(key, value) => {
-                    data.insert(key, value);
-                }
-                Operation::Remove(key) => {
-                    data.remove(&key);
-                }
-                Operation::Get(key, sender) => {
-                    let value = data.get(&key).map(|r| r.value().clone());
-                    let _ = sender.send(value);
-                }
-                Operation::Find(predicate, sender) => {
-                    let results = data.find(|k, v| predicate(k, v));
-                    let _ = sender.send(results);
-                }
-                Operation::Clear => {
-                    data.clear();
-                }
-                Operation::Shutdown => {
-                    break;
-                }
+            if !apply_operation(&data, operation) {
+                break;
             }
         }
     }
@@ -109,40 +240,285 @@ where
         });
 }
 
-/// Use Crossbeam scopes to process data with stack references
-pub fn scoped_data_processing<K, V, F, R>(
+/// How per-segment results are combined in the map-reduce helpers.
+/// Both strategies combine results in segment order, so `combine` only needs
+/// to be associative, not commutative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Combiner {
+    /// Combine pairs of results in parallel as a balanced tree
+    #[default]
+    Tree,
+    /// Fold the results left to right on the calling thread
+    Sequential,
+}
+
+/// Map every segment in parallel and return the partial results in segment order
+pub fn map_segments<K, V, M, A>(
     data: &MyData<K, V>,
-    processor: F,
-) -> Vec<R>
+    map: M,
+) -> Vec<A>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    M: Fn(usize, &DashMap<K, V>) -> A + Send + Sync,
+    A: Send,
+{
+    (0..data.num_segments())
+        .into_par_iter()
+        .filter_map(|idx| data.get_segment(idx).map(|segment| map(idx, segment)))
+        .collect()
+}
+
+/// Map every segment in parallel and combine the results in segment order.
+/// Returns `None` if the data structure has no segments.
+pub fn map_reduce<K, V, M, C, A>(
+    data: &MyData<K, V>,
+    map: M,
+    combine: C,
+) -> Option<A>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    M: Fn(usize, &DashMap<K, V>) -> A + Send + Sync,
+    C: Fn(A, A) -> A + Send + Sync,
+    A: Send,
+{
+    map_reduce_with(data, map, combine, Combiner::Tree)
+}
+
+/// `map_reduce` with an explicit choice of combiner
+pub fn map_reduce_with<K, V, M, C, A>(
+    data: &MyData<K, V>,
+    map: M,
+    combine: C,
+    combiner: Combiner,
+) -> Option<A>
 where
     K: Hash + Eq + Clone + Send + Sync + 'static,
     V: Clone + Send + Sync + 'static,
-    F: Fn(usize, &DashMap<K, V>) -> R + Send + Sync + Clone,
-    R: Send + 'static,
+    M: Fn(usize, &DashMap<K, V>) -> A + Send + Sync,
+    C: Fn(A, A) -> A + Send + Sync,
+    A: Send,
 {
-    let mut results = Vec::with_capacity(data.num_segments());
+    match combiner {
+        // Rayon keeps the order of an indexed iterator when reducing
+        Combiner::Tree => (0..data.num_segments())
+            .into_par_iter()
+            .filter_map(|idx| data.get_segment(idx).map(|segment| map(idx, segment)))
+            .reduce_with(&combine),
+        Combiner::Sequential => map_segments(data, &map)
+            .into_iter()
+            .reduce(&combine),
+    }
+}
 
-    crossbeam::scope(|scope| {
-        let mut handles = Vec::with_capacity(data.num_segments());
+/// Like `map_reduce`, but stops scheduling segments once any segment's partial
+/// result satisfies `done`. Segments already running finish normally, so the
+/// result covers only the segments that were mapped, still combined in order.
+pub fn map_reduce_until<K, V, M, C, D, A>(
+    data: &MyData<K, V>,
+    map: M,
+    combine: C,
+    done: D,
+) -> Option<A>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    M: Fn(usize, &DashMap<K, V>) -> A + Send + Sync,
+    C: Fn(A, A) -> A + Send + Sync,
+    D: Fn(&A) -> bool + Send + Sync,
+    A: Send,
+{
+    let stop = AtomicBool::new(false);
 
-        // Process each segment in its own thread
-        for idx in 0..data.num_segments() {
-            if let Some(segment) = data.get_segment(idx) {
-                let processor_clone = processor.clone();
-                let handle = scope.spawn(move |_| {
-                    processor_clone(idx, segment)
-                });
-                handles.push(handle);
+    (0..data.num_segments())
+        .into_par_iter()
+        .filter_map(|idx| {
+            if stop.load(Ordering::Relaxed) {
+                return None;
             }
+            let partial = map(idx, data.get_segment(idx)?);
+            if done(&partial) {
+                stop.store(true, Ordering::Relaxed);
+            }
+            Some(partial)
+        })
+        .reduce_with(&combine)
+}
+
+/// Map every segment of several data structures in parallel and combine the
+/// results ordered by data structure, then by segment.
+/// The mapper receives `(data structure index, segment index, segment)`.
+pub fn map_reduce_many<K, V, M, C, A>(
+    data_structures: &[Arc<MyData<K, V>>],
+    map: M,
+    combine: C,
+) -> Option<A>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    M: Fn(usize, usize, &DashMap<K, V>) -> A + Send + Sync,
+    C: Fn(A, A) -> A + Send + Sync,
+    A: Send,
+{
+    let work: Vec<(usize, usize)> = data_structures
+        .iter()
+        .enumerate()
+        .flat_map(|(ds_idx, data)| (0..data.num_segments()).map(move |seg_idx| (ds_idx, seg_idx)))
+        .collect();
+
+    work.into_par_iter()
+        .filter_map(|(ds_idx, seg_idx)| {
+            data_structures[ds_idx]
+                .get_segment(seg_idx)
+                .map(|segment| map(ds_idx, seg_idx, segment))
+        })
+        .reduce_with(&combine)
+}
+
+/// A segment processor panicked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentPanic {
+    /// Index of the segment being processed
+    pub segment: usize,
+    /// The panic message, if it was a string
+    pub message: String,
+}
+
+impl fmt::Display for SegmentPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "processing segment {} panicked: {}", self.segment, self.message)
+    }
+}
+
+impl std::error::Error for SegmentPanic {}
+
+/// Processes segments on a bounded number of scoped threads that pull
+/// segment indices from a shared counter
+#[derive(Debug, Clone, Copy)]
+pub struct ScopedExecutor {
+    max_threads: usize,
+}
+
+impl ScopedExecutor {
+    /// Use at most `max_threads` threads (at least one)
+    pub fn new(max_threads: usize) -> Self {
+        ScopedExecutor {
+            max_threads: max_threads.max(1),
         }
+    }
 
-        // Collect results
-        for handle in handles {
-            if let Ok(result) = handle.join() {
-                results.push(result);
-            }
+    /// Use one thread per available CPU
+    pub fn with_available_parallelism() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+
+    /// Run `processor` on every segment. The result at position `i` belongs
+    /// to segment `i`; a panic becomes an `Err` for that segment only.
+    pub fn run<K, V, F, R>(
+        &self,
+        data: &MyData<K, V>,
+        processor: F,
+    ) -> Vec<Result<R, SegmentPanic>>
+    where
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+        F: Fn(usize, &DashMap<K, V>) -> R + Send + Sync,
+        R: Send,
+    {
+        let num_segments = data.num_segments();
+        let next_segment = AtomicUsize::new(0);
+        let num_threads = self.max_threads.min(num_segments);
+
+        let per_thread: Vec<Vec<(usize, Result<R, SegmentPanic>)>> = crossbeam::scope(|scope| {
+            let handles: Vec<_> = (0..num_threads)
+                .map(|_| {
+                    scope.spawn(|_| {
+                        let mut done = Vec::new();
+                        loop {
+                            let idx = next_segment.fetch_add(1, Ordering::Relaxed);
+                            let Some(segment) = data.get_segment(idx) else {
+                                break;
+                            };
+                            let outcome = panic::catch_unwind(AssertUnwindSafe(|| processor(idx, segment)))
+                                .map_err(|payload| SegmentPanic {
+                                    segment: idx,
+                                    message: panic_message(payload.as_ref()),
+                                });
+                            done.push((idx, outcome));
+                        }
+                        done
+                    })
+                })
+                .collect();
+
+            // Panics are caught inside the threads, so joining cannot fail
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("segment panics are caught"))
+                .collect()
+        })
+        .expect("segment panics are caught");
+
+        // Put every result back in its segment's slot
+        let mut slots: Vec<Option<Result<R, SegmentPanic>>> = (0..num_segments).map(|_| None).collect();
+        for (idx, outcome) in per_thread.into_iter().flatten() {
+            slots[idx] = Some(outcome);
         }
-    }).unwrap();
+        slots
+            .into_iter()
+            .map(|slot| slot.expect("every segment is processed exactly once"))
+            .collect()
+    }
+}
 
-    results
-}
\ No newline at end of file
+/// Use Crossbeam scopes to process data with stack references.
+/// Runs on one thread per CPU; the result at position `i` belongs to segment `i`.
+pub fn scoped_data_processing<K, V, F, R>(
+    data: &MyData<K, V>,
+    processor: F,
+) -> Vec<Result<R, SegmentPanic>>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    F: Fn(usize, &DashMap<K, V>) -> R + Send + Sync,
+    R: Send,
+{
+    ScopedExecutor::with_available_parallelism().run(data, processor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use std::time::Instant;
+
+    #[test]
+    fn wrapped_shutdown_always_stops() {
+        let data = MyData::<u64, u64>::new(0, 2);
+        let token = CancellationToken::new();
+        token.cancel();
+        let expired = OpContext::new().with_deadline(Instant::now());
+        assert!(!apply_operation(&data, Operation::Shutdown.with_context(expired)));
+        assert!(!apply_operation(&data, Operation::Shutdown.with_context(OpContext::new().with_token(token))));
+    }
+
+    #[test]
+    fn nested_contexts_apply_the_outer_limits() {
+        let data = MyData::<u64, u64>::new(0, 2);
+        let live = OpContext::new().with_timeout(Duration::from_secs(60));
+        let expired = OpContext::new().with_deadline(Instant::now());
+        assert!(apply_operation(&data, Operation::Insert(1, 1).with_context(live.clone()).with_context(expired)));
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let cancelled = OpContext::new().with_token(token);
+        let (reply, result) = crossbeam::channel::bounded(1);
+        assert!(apply_operation(&data, Operation::Get(1, reply).with_context(live.clone()).with_context(cancelled)));
+        assert_eq!(result.recv().unwrap(), Err(OpError::Cancelled));
+        assert!(data.get(&1).is_none());
+
+        assert!(apply_operation(&data, Operation::Insert(2, 2).with_context(live.clone()).with_context(live)));
+        assert_eq!(data.get(&2).map(|r| *r.value()), Some(2));
+    }
+}